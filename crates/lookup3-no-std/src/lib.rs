@@ -0,0 +1,41 @@
+#![no_std]
+
+//! Checks that `lookup3` and `packed-int` build in a `#![no_std]` crate with their `std` features
+//! off. Nothing here pulls in std, so if either starts depending on it this crate stops compiling.
+
+pub use lookup3::{hashlittle, hashlittle2, hashpath};
+pub use packed_int::{u24, u40};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashes() {
+        // Test vectors from lookup3.c's driver5
+        assert_eq!(hashlittle(b"", 0), 0xdeadbeef);
+        assert_eq!(hashlittle2(b"", 0, 0), (0xdeadbeef, 0xdeadbeef));
+        assert_eq!(
+            hashlittle2(b"Four score and seven years ago", 0, 0),
+            (0x17770551, 0xce7226e6)
+        );
+        assert_eq!(
+            hashpath(b"Four score and seven years ago"),
+            0x17770551_ce7226e6
+        );
+    }
+
+    #[test]
+    fn test_packed_ints() {
+        assert_eq!(0xabcdef, u24::new(0xabcdef).get());
+        assert!(u24::try_from(0x1000000u32).is_err());
+        assert_eq!(
+            0xff_ffff_ffff,
+            u40::checked_new(0xff_ffff_ffff).unwrap().get()
+        );
+        assert_eq!(
+            (0x3fffffff, 0x3ff),
+            u40::from_30_10(0x3fffffff, 0x3ff).unwrap().get_30_10()
+        );
+    }
+}
@@ -1,3 +1,5 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
 /// mix -- mix 3 32-bit values reversibly.
 ///
 /// This is reversible, so any information in (a,b,c) before mix() is
@@ -323,6 +325,12 @@ pub fn hashbig2(mut k: &[u8], pc: u32, pb: u32) -> (u32, u32) {
     (c, b)
 }
 
+/// The 64 bit name hash CASC root files use, over an already normalized path
+pub fn hashpath(path: &[u8]) -> u64 {
+    let (pc, pb) = hashlittle2(path, 0, 0);
+    pb as u64 | ((pc as u64) << 32)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
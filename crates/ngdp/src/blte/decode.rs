@@ -2,13 +2,86 @@ use std::io::Cursor;
 use std::io::Read;
 
 use binrw::BinRead;
-use flate2::bufread::ZlibDecoder;
-use libdeflate_sys::{libdeflate_free_decompressor, libdeflate_zlib_decompress};
+use flate2::bufread::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use libdeflate_sys::{
+    libdeflate_deflate_decompress, libdeflate_free_decompressor, libdeflate_gzip_decompress,
+    libdeflate_zlib_decompress,
+};
 
-use crate::tact::keys::TactKeys;
+use super::espec::{Block, BlockSize, Blocks, ESpec, Encrypted, Zip, ZipBits};
+use crate::{error::NgdpError, tact::keys::TactKeys};
 
 // TODO: Rewrite as a std::io::Read impl?
-pub fn decode_blte(tact_keys: &TactKeys, content: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+pub fn decode_blte(tact_keys: &TactKeys, content: &[u8]) -> Result<Vec<u8>, NgdpError> {
+    let mut res = vec![];
+    decode_blte_into(tact_keys, content, &mut res)?;
+    Ok(res)
+}
+
+/// Like `decode_blte`, but decodes into `out`, replacing its contents and reusing its allocation
+pub fn decode_blte_into(
+    tact_keys: &TactKeys,
+    content: &[u8],
+    out: &mut Vec<u8>,
+) -> Result<(), NgdpError> {
+    decode_blte_into_inner(tact_keys, content, out, &mut vec![]).map_err(to_ngdp_error)
+}
+
+/// Decoded data, along with the names of any encryption keys that were missing.
+/// Chunks encrypted with a missing key are zero filled.
+#[derive(Debug)]
+pub struct DecodeResult {
+    pub data: Vec<u8>,
+    pub missing_keys: Vec<[u8; 8]>,
+}
+
+impl DecodeResult {
+    /// The data if every chunk could be decrypted, otherwise an error naming the missing keys
+    pub fn require_all_keys(self, what: &str) -> Result<Vec<u8>, anyhow::Error> {
+        if self.missing_keys.is_empty() {
+            return Ok(self.data);
+        }
+        let names = self
+            .missing_keys
+            .iter()
+            .map(hex::encode)
+            .collect::<Vec<_>>()
+            .join(", ");
+        let missing_keys = self.missing_keys.into_iter().collect();
+        Err(
+            anyhow::Error::from(NgdpError::MissingEncryptionKey(missing_keys))
+                .context(format!("{} needs missing encryption keys: {}", what, names)),
+        )
+    }
+}
+
+/// Like `decode_blte`, but reports which encryption keys were missing instead of silently zero filling
+pub fn decode_blte_reporting(
+    tact_keys: &TactKeys,
+    content: &[u8],
+) -> Result<DecodeResult, NgdpError> {
+    let mut data = vec![];
+    let mut missing_keys = vec![];
+    decode_blte_into_inner(tact_keys, content, &mut data, &mut missing_keys)
+        .map_err(to_ngdp_error)?;
+    Ok(DecodeResult { data, missing_keys })
+}
+
+fn to_ngdp_error(e: anyhow::Error) -> NgdpError {
+    match e.downcast::<NgdpError>() {
+        Ok(e) => e,
+        Err(e) => NgdpError::CorruptBlte(format!("{:#}", e)),
+    }
+}
+
+fn decode_blte_into_inner(
+    tact_keys: &TactKeys,
+    content: &[u8],
+    out: &mut Vec<u8>,
+    missing_keys: &mut Vec<[u8; 8]>,
+) -> Result<(), anyhow::Error> {
+    out.clear();
+
     let mut r = Cursor::new(content);
     let res = repr::BLTEHeader::read(&mut r)?;
 
@@ -19,11 +92,15 @@ pub fn decode_blte(tact_keys: &TactKeys, content: &[u8]) -> Result<Vec<u8>, anyh
         checksum: [0; 16],
     }];
 
-    let chunk_infos = if !res.chunks.is_empty() {
+    let chunk_infos = if res.header_size > 0 {
+        // A chunk table with no chunks describes an empty file
+        if res.chunks.is_empty() && r.position() as usize != content.len() {
+            anyhow::bail!("blte has data following an empty chunk table");
+        }
         res.chunks.as_slice()
     } else {
+        // No header, the rest of the file is a single chunk
         let rest = &content[r.position() as usize..];
-        assert_eq!(content.len() - 8, rest.len());
         dummy_chunk[0].compressed_size = rest.len() as u32;
         dummy_chunk[0].checksum = compute_md5(rest);
         dummy_chunk.as_slice()
@@ -33,22 +110,271 @@ pub fn decode_blte(tact_keys: &TactKeys, content: &[u8]) -> Result<Vec<u8>, anyh
         .iter()
         .map(|c| c.decompressed_size as usize)
         .sum();
-    let mut res = Vec::with_capacity(expected_size);
+    out.reserve(expected_size);
 
     for (index, chunk_info) in chunk_infos.iter().enumerate() {
         let mut data = vec![0; chunk_info.compressed_size as usize];
         r.read_exact(&mut data)?;
-        let hash = compute_md5(&data);
-        assert_eq!(
-            hash, chunk_info.checksum,
-            "blte chunk did not match checksum"
-        );
-        handle_data_block(&data, tact_keys, index as u32, chunk_info, &mut res)?;
+        if compute_md5(&data) != chunk_info.checksum {
+            anyhow::bail!("blte chunk {} did not match checksum", index);
+        }
+        handle_data_block(
+            &data,
+            tact_keys,
+            index as u32,
+            chunk_info,
+            out,
+            missing_keys,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Number of leading bytes of a BLTE file needed to decode at least `max_bytes` of it,
+/// given a prefix containing at least its header. `None` for chunkless files, which can only be decoded whole.
+pub fn prefix_len(content: &[u8], max_bytes: usize) -> Result<Option<usize>, anyhow::Error> {
+    let mut r = Cursor::new(content);
+    let header = repr::BLTEHeader::read(&mut r)?;
+    if header.header_size == 0 {
+        return Ok(None);
+    }
+
+    let mut len = r.position() as usize;
+    let mut decoded = 0;
+    for chunk_info in &header.chunks {
+        if decoded >= max_bytes {
+            break;
+        }
+        len += chunk_info.compressed_size as usize;
+        decoded += chunk_info.decompressed_size as usize;
+    }
+    Ok(Some(len))
+}
+
+/// Decodes whole chunks of a BLTE file until at least `max_bytes` have been decoded,
+/// skipping the rest. Chunkless files are decoded whole.
+pub fn decode_blte_prefix(
+    tact_keys: &TactKeys,
+    content: &[u8],
+    max_bytes: usize,
+) -> Result<Vec<u8>, NgdpError> {
+    let corrupt = |e: anyhow::Error| NgdpError::CorruptBlte(format!("{:#}", e));
+
+    let needed = prefix_len(content, max_bytes).map_err(corrupt)?;
+    if let Some(needed) = needed.filter(|needed| *needed > content.len()) {
+        return Err(NgdpError::CorruptBlte(format!(
+            "blte is truncated, {} bytes needed but only {} present",
+            needed,
+            content.len()
+        )));
+    }
+
+    decode_available_chunks(tact_keys, content, max_bytes).map_err(corrupt)
+}
+
+/// Decodes whole chunks from the start of a possibly truncated BLTE file,
+/// until at least `max_bytes` have been decoded or the file runs out.
+pub(crate) fn decode_available_chunks(
+    tact_keys: &TactKeys,
+    content: &[u8],
+    max_bytes: usize,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let mut r = Cursor::new(content);
+    let header = repr::BLTEHeader::read(&mut r)?;
+    if header.header_size == 0 {
+        return Ok(decode_blte(tact_keys, content)?);
     }
 
+    let mut offset = r.position() as usize;
+    let mut res = vec![];
+    for (index, chunk_info) in header.chunks.iter().enumerate() {
+        if res.len() >= max_bytes {
+            break;
+        }
+        let Some(data) = content.get(offset..offset + chunk_info.compressed_size as usize) else {
+            break;
+        };
+        offset += data.len();
+
+        if compute_md5(data) != chunk_info.checksum {
+            anyhow::bail!("blte chunk {} did not match checksum", index);
+        }
+        handle_data_block(
+            data,
+            tact_keys,
+            index as u32,
+            chunk_info,
+            &mut res,
+            &mut vec![],
+        )?;
+    }
     Ok(res)
 }
 
+/// Decodes a BLTE file, also returning the espec it was most likely encoded with
+pub fn decode_blte_with_espec(
+    tact_keys: &TactKeys,
+    content: &[u8],
+) -> Result<(Vec<u8>, ESpec), anyhow::Error> {
+    let espec = infer_espec(tact_keys, content)?;
+    let decoded = decode_blte(tact_keys, content)?;
+    Ok((decoded, espec))
+}
+
+/// Reconstructs an espec from the chunk layout of a BLTE file.
+/// Zip levels can only be recovered approximately, as zlib only records a rough compression level.
+pub fn infer_espec(tact_keys: &TactKeys, content: &[u8]) -> Result<ESpec, anyhow::Error> {
+    let mut r = Cursor::new(content);
+    let header = repr::BLTEHeader::read(&mut r)?;
+    let mut offset = r.position() as usize;
+
+    if header.header_size == 0 {
+        return infer_chunk_espec(&content[offset..], tact_keys, 0);
+    }
+    if header.chunks.is_empty() {
+        anyhow::bail!("can't infer espec of blte without chunks");
+    }
+
+    // Runs of equally sized chunks with the same espec, as (size, count, espec)
+    let mut runs: Vec<(u64, u64, ESpec)> = vec![];
+    for (index, chunk_info) in header.chunks.iter().enumerate() {
+        let data = content
+            .get(offset..offset + chunk_info.compressed_size as usize)
+            .ok_or_else(|| anyhow::anyhow!("blte chunk {} out of bounds", index))?;
+        offset += data.len();
+
+        let size = chunk_info.decompressed_size as u64;
+        let espec = infer_chunk_espec(data, tact_keys, index as u32)?;
+        match runs.last_mut() {
+            Some((run_size, count, run_espec))
+                if *run_size == size && run_espec.to_string() == espec.to_string() =>
+            {
+                *count += 1
+            }
+            _ => runs.push((size, 1, espec)),
+        }
+    }
+
+    let mut blocks: Vec<Block> = runs
+        .into_iter()
+        .map(|(size, count, inner)| Block {
+            size: BlockSize::Chunked { size, count },
+            inner,
+        })
+        .collect();
+    let final_ = Box::new(blocks.pop().expect("blte had chunks"));
+
+    Ok(ESpec::Blocks(Blocks { blocks, final_ }))
+}
+
+fn infer_chunk_espec(
+    data: &[u8],
+    tact_keys: &TactKeys,
+    index: u32,
+) -> Result<ESpec, anyhow::Error> {
+    let (encoding_mode, data) = data
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("blte: Expected at least one byte for block"))?;
+    match encoding_mode {
+        b'N' => Ok(ESpec::Raw),
+        b'Z' => {
+            let (cmf, flg) = match data {
+                [cmf, flg, ..] => (cmf, flg),
+                _ => anyhow::bail!("blte Z chunk too short for a zlib header"),
+            };
+            let level = match flg >> 6 {
+                0 => 1,
+                1 => 5,
+                2 => 6,
+                _ => 9,
+            };
+            Ok(ESpec::Zip(Zip {
+                level,
+                bits: ZipBits::Bits((cmf >> 4) + 8),
+            }))
+        }
+        b'E' => match decrypt_block(data, tact_keys, index)? {
+            (header, Some(decrypted)) => Ok(ESpec::Encrypted(Encrypted {
+                key: header.key_name,
+                iv: header.iv,
+                inner: Box::new(infer_chunk_espec(&decrypted, tact_keys, index)?),
+            })),
+            (header, None) => anyhow::bail!(
+                "can't infer espec of chunk encrypted with missing key {}",
+                hex::encode_upper(header.key_name)
+            ),
+        },
+        encoding_mode => anyhow::bail!("Unknown encoding mode: {}", encoding_mode.escape_ascii()),
+    }
+}
+
+/// Structure of a BLTE file, as returned by `describe`
+#[derive(Debug)]
+pub struct BlteInfo {
+    pub header_size: u32,
+    pub flags: Option<u8>,
+    pub chunks: Vec<ChunkDescription>,
+}
+
+#[derive(Debug)]
+pub struct ChunkDescription {
+    /// First byte of the chunk, e.g. `b'N'`, `b'Z'` or `b'E'`
+    pub mode: u8,
+    pub compressed_size: u32,
+    /// Always 0 for files without a chunk table
+    pub decompressed_size: u32,
+    pub checksum: [u8; 16],
+    /// Name of the key needed to decrypt the chunk, for encrypted chunks
+    pub key_name: Option<[u8; 8]>,
+}
+
+/// Parses the header and chunk table of a BLTE file without decompressing or decrypting anything
+pub fn describe(content: &[u8]) -> Result<BlteInfo, anyhow::Error> {
+    let mut r = Cursor::new(content);
+    let header = repr::BLTEHeader::read(&mut r)?;
+    let mut offset = r.position() as usize;
+
+    let chunk_infos = if header.header_size > 0 {
+        header.chunks.clone()
+    } else {
+        let rest = &content[offset..];
+        vec![repr::ChunkInfo {
+            compressed_size: rest.len() as u32,
+            decompressed_size: 0,
+            checksum: compute_md5(rest),
+        }]
+    };
+
+    let mut chunks = Vec::with_capacity(chunk_infos.len());
+    for chunk_info in chunk_infos {
+        let mode = *content
+            .get(offset)
+            .ok_or_else(|| anyhow::anyhow!("blte chunk starts past end of file"))?;
+        let key_name = if mode == b'E' {
+            let header = repr::EncryptHeader::read(&mut Cursor::new(&content[offset + 1..]))?;
+            Some(header.key_name)
+        } else {
+            None
+        };
+        offset += chunk_info.compressed_size as usize;
+
+        chunks.push(ChunkDescription {
+            mode,
+            compressed_size: chunk_info.compressed_size,
+            decompressed_size: chunk_info.decompressed_size,
+            checksum: chunk_info.checksum,
+            key_name,
+        });
+    }
+
+    Ok(BlteInfo {
+        header_size: header.header_size,
+        flags: header.flags,
+        chunks,
+    })
+}
+
 #[inline(always)]
 pub fn compute_md5(data: &[u8]) -> [u8; 16] {
     use md5::{Digest, Md5};
@@ -64,17 +390,33 @@ fn handle_data_block(
     index: u32,
     chunk_info: &repr::ChunkInfo,
     out: &mut Vec<u8>,
+    missing_keys: &mut Vec<[u8; 8]>,
 ) -> Result<(), anyhow::Error> {
     let (encoding_mode, data) = data
         .split_first()
         .ok_or_else(|| anyhow::anyhow!("blte: Expected at least one byte for block"))?;
     match encoding_mode {
-        b'N' => out.extend_from_slice(data),
-        b'Z' => handle_deflate_block(data, chunk_info, out),
-        b'F' => todo!("recursive blte block"),
-        b'E' => handle_encrypted_block(data, tact_keys, index, chunk_info, out)?,
+        b'N' => {
+            // Chunkless files don't declare a size, so there's nothing to check against
+            if chunk_info.decompressed_size != 0
+                && data.len() != chunk_info.decompressed_size as usize
+            {
+                anyhow::bail!(
+                    "blte N chunk size mismatch: declared {}, got {}",
+                    chunk_info.decompressed_size,
+                    data.len()
+                );
+            }
+            out.extend_from_slice(data)
+        }
+        b'Z' => handle_deflate_block(data, chunk_info, out)?,
+        b'F' => anyhow::bail!("recursive blte blocks are not supported"),
+        b'E' => handle_encrypted_block(data, tact_keys, index, chunk_info, out, missing_keys)?,
         encoding_mode => {
-            panic!("Unknown encoding mode: {}", encoding_mode.escape_ascii())
+            anyhow::bail!(
+                "unknown blte encoding mode: {}",
+                encoding_mode.escape_ascii()
+            )
         }
     }
     Ok(())
@@ -107,24 +449,63 @@ pub fn dbg_zlib_wrapper(data: &[u8]) {
     );
 }
 
-fn handle_deflate_block(data: &[u8], chunk_info: &repr::ChunkInfo, out: &mut Vec<u8>) {
+/// Container format around the deflate stream of a Z chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeflateWrapper {
+    Zlib,
+    Gzip,
+    Raw,
+}
+
+impl DeflateWrapper {
+    fn detect(data: &[u8]) -> DeflateWrapper {
+        match data {
+            [0x1f, 0x8b, ..] => DeflateWrapper::Gzip,
+            // CM must be deflate, CINFO at most a 32K window, and FCHECK must check out
+            [cmf, flg, ..]
+                if cmf & 0x0f == 8
+                    && cmf >> 4 <= 7
+                    && (*cmf as u16 * 256 + *flg as u16).is_multiple_of(31) =>
+            {
+                DeflateWrapper::Zlib
+            }
+            _ => DeflateWrapper::Raw,
+        }
+    }
+}
+
+fn handle_deflate_block(
+    data: &[u8],
+    chunk_info: &repr::ChunkInfo,
+    out: &mut Vec<u8>,
+) -> Result<(), anyhow::Error> {
     // dbg_zlib_wrapper(&data[..2]);
 
+    let wrapper = DeflateWrapper::detect(data);
     let decompressed_size = chunk_info.decompressed_size as usize;
     if decompressed_size > 0 {
         // If we know the output size, use libdeflate
-        zlib_decompress(data, out, decompressed_size).expect("error deflating blte Z chunk");
+        deflate_decompress(wrapper, data, out, decompressed_size)?;
     } else {
         // Otherwise use flate2 which uses an internal buffer
-        let mut decoder = ZlibDecoder::new(data);
-
-        let _num_decompressed = decoder
-            .read_to_end(out)
-            .expect("error deflating blte Z chunk");
+        let res = match wrapper {
+            DeflateWrapper::Zlib => ZlibDecoder::new(data).read_to_end(out),
+            DeflateWrapper::Gzip => GzDecoder::new(data).read_to_end(out),
+            DeflateWrapper::Raw => DeflateDecoder::new(data).read_to_end(out),
+        };
+        res.map_err(|e| {
+            anyhow::anyhow!(
+                "error inflating blte Z chunk ({:?} wrapper): {}",
+                wrapper,
+                e
+            )
+        })?;
     }
+    Ok(())
 }
 
-fn zlib_decompress(
+fn deflate_decompress(
+    wrapper: DeflateWrapper,
     in_buf: &[u8],
     out_buf: &mut Vec<u8>,
     decompressed_size: usize,
@@ -136,16 +517,15 @@ fn zlib_decompress(
     let in_ptr = in_buf.as_ptr() as *const std::ffi::c_void;
     let out_ptr = out.as_mut_ptr() as *mut std::ffi::c_void;
 
+    let decompress = match wrapper {
+        DeflateWrapper::Zlib => libdeflate_zlib_decompress,
+        DeflateWrapper::Gzip => libdeflate_gzip_decompress,
+        DeflateWrapper::Raw => libdeflate_deflate_decompress,
+    };
+
     let ret = unsafe {
         let d = libdeflate_sys::libdeflate_alloc_decompressor();
-        let ret = libdeflate_zlib_decompress(
-            d,
-            in_ptr,
-            in_buf.len(),
-            out_ptr,
-            out.len(),
-            &mut out_nbytes,
-        );
+        let ret = decompress(d, in_ptr, in_buf.len(), out_ptr, out.len(), &mut out_nbytes);
         libdeflate_free_decompressor(d);
         ret
     };
@@ -165,9 +545,11 @@ fn zlib_decompress(
                 Ok(out_nbytes)
             }
         }
-        libdeflate_sys::libdeflate_result_LIBDEFLATE_BAD_DATA => {
-            Err(anyhow::anyhow!("bad data in blte Z chunk"))
-        }
+        libdeflate_sys::libdeflate_result_LIBDEFLATE_BAD_DATA => Err(anyhow::anyhow!(
+            "bad data in blte Z chunk ({:?} wrapper, starting with {:02x?})",
+            wrapper,
+            &in_buf[..in_buf.len().min(2)]
+        )),
         libdeflate_sys::libdeflate_result_LIBDEFLATE_INSUFFICIENT_SPACE => Err(anyhow::anyhow!(
             "insufficient space in output buffer for blte Z chunk"
         )),
@@ -183,38 +565,23 @@ fn handle_encrypted_block(
     index: u32,
     chunk_info: &repr::ChunkInfo,
     out: &mut Vec<u8>,
+    missing_keys: &mut Vec<[u8; 8]>,
 ) -> Result<(), anyhow::Error> {
-    let mut r = Cursor::new(data);
-    let header = repr::EncryptHeader::read(&mut r)?;
-    let data = &data[r.position() as usize..];
-
-    assert_eq!(8, header.key_name_length);
-    assert_eq!(4, header.iv_length);
-
-    if let Some(&key) = tact_keys.get_key(&header.key_name) {
-        let mut buf = data.to_vec();
-        match header.type_ {
-            b'S' => {
-                let mut full_iv = [0; 8];
-                full_iv[0..4].copy_from_slice(&header.iv);
-
-                let index = index.to_le_bytes();
-                for i in 0..4 {
-                    full_iv[i] ^= index[i];
-                }
-
-                salsa_crypt(key, full_iv, &mut buf);
-            }
-            _ => anyhow::bail!("Unhandled encryption mode: {}", header.type_.escape_ascii()),
-        }
+    let (header, buf) = decrypt_block(data, tact_keys, index)?;
 
-        match buf[0] {
-            b'N' | b'Z' | b'F' | b'E' => {
+    // Chunkless files have no declared size to fill in for data we can't decrypt
+    let unknown_size = chunk_info.decompressed_size == 0;
+    if let Some(buf) = buf {
+        match buf.first() {
+            Some(b'N' | b'Z' | b'F' | b'E') => {
                 let chunk_info = repr::ChunkInfo {
                     compressed_size: buf.len() as u32,
                     ..chunk_info.clone()
                 };
-                handle_data_block(&buf, tact_keys, index, &chunk_info, out)?;
+                handle_data_block(&buf, tact_keys, index, &chunk_info, out, missing_keys)?;
+            }
+            _ if unknown_size => {
+                anyhow::bail!("decrypted chunkless blte is corrupt, or the key is wrong");
             }
             _ => {
                 // println!(
@@ -228,6 +595,8 @@ fn handle_encrypted_block(
                 out.extend((0..chunk_info.decompressed_size).map(|_| 0u8));
             }
         }
+    } else if unknown_size {
+        return Err(NgdpError::missing_key(header.key_name).into());
     } else {
         // println!(
         //     "index: {}, key_name: {:02X?}, iv: {:02X?}, type: {}",
@@ -240,19 +609,86 @@ fn handle_encrypted_block(
         //     "Encryption key name {:02X?} not found, filling with dummy data",
         //     header.key_name
         // );
+        if !missing_keys.contains(&header.key_name) {
+            missing_keys.push(header.key_name);
+        }
         out.extend((0..chunk_info.decompressed_size).map(|_| 0u8));
     }
     Ok(())
 }
 
-pub(super) fn salsa_crypt(key: [u8; 16], iv: [u8; 8], buf: &mut [u8]) {
-    // println!("key: {:02x?}", key);
-    // println!("iv: {:02x?}", iv);
-    let key = rust_salsa20::Key::Key16(key);
-    let mut cipher = rust_salsa20::Salsa20::new(key, iv, 0);
-    // println!("enc: {:02x?}", buf);
-    cipher.encrypt(buf);
-    // println!("dec: {:02x?}", buf);
+/// Decrypts the contents of an `E` chunk, returning `None` if we don't have the key
+fn decrypt_block(
+    data: &[u8],
+    tact_keys: &TactKeys,
+    index: u32,
+) -> Result<(repr::EncryptHeader, Option<Vec<u8>>), anyhow::Error> {
+    let mut r = Cursor::new(data);
+    let header = repr::EncryptHeader::read(&mut r)?;
+    let data = &data[r.position() as usize..];
+
+    if header.key_name_length != 8 || header.iv_length != 4 {
+        anyhow::bail!(
+            "unsupported blte encryption header: {} byte key name, {} byte iv",
+            header.key_name_length,
+            header.iv_length
+        );
+    }
+
+    let Some(&key) = tact_keys.get_key(&header.key_name) else {
+        return Ok((header, None));
+    };
+
+    let mut buf = data.to_vec();
+    match header.type_ {
+        b'S' => SalsaCipher::new(key, header.iv, index).apply(&mut buf),
+        _ => anyhow::bail!("Unhandled encryption mode: {}", header.type_.escape_ascii()),
+    }
+    Ok((header, Some(buf)))
+}
+
+/// Salsa20 keystream for a single encrypted chunk.
+/// Tracks the position so a chunk's payload can be processed across several buffers.
+pub struct SalsaCipher {
+    cipher: rust_salsa20::Salsa20,
+    position: u64,
+}
+
+impl SalsaCipher {
+    /// The chunk index is mixed into the IV, so every chunk gets its own keystream
+    pub fn new(key: [u8; 16], iv: [u8; 4], chunk_index: u32) -> SalsaCipher {
+        let mut full_iv = [0; 8];
+        full_iv[0..4].copy_from_slice(&iv);
+
+        let index = chunk_index.to_le_bytes();
+        for i in 0..4 {
+            full_iv[i] ^= index[i];
+        }
+
+        SalsaCipher {
+            cipher: rust_salsa20::Salsa20::new(rust_salsa20::Key::Key16(key), full_iv, 0),
+            position: 0,
+        }
+    }
+
+    /// Byte offset into the chunk payload of the next byte to be processed
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Moves to a byte offset in the chunk payload
+    pub fn seek(&mut self, position: u64) {
+        self.cipher.set_counter(position / 64);
+        let mut skip = [0u8; 64];
+        self.cipher.generate(&mut skip[..(position % 64) as usize]);
+        self.position = position;
+    }
+
+    /// Encrypts or decrypts the next `buf.len()` bytes of the chunk payload in place
+    pub fn apply(&mut self, buf: &mut [u8]) {
+        self.cipher.encrypt(buf);
+        self.position += buf.len() as u64;
+    }
 }
 
 pub(super) mod repr {
@@ -305,3 +741,276 @@ pub(super) mod repr {
         pub type_: u8,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    fn single_n_chunk_blte(payload: &[u8], decompressed_size: u32) -> Vec<u8> {
+        single_chunk_blte(b'N', payload, decompressed_size)
+    }
+
+    fn single_chunk_blte(mode: u8, payload: &[u8], decompressed_size: u32) -> Vec<u8> {
+        let mut chunk = vec![mode];
+        chunk.extend_from_slice(payload);
+
+        let mut res = vec![];
+        res.extend_from_slice(b"BLTE");
+        res.extend_from_slice(&36u32.to_be_bytes()); // header size, with a single chunk
+        res.extend_from_slice(&[0x0f, 0, 0, 1]); // flags, chunk count
+        res.extend_from_slice(&(chunk.len() as u32).to_be_bytes());
+        res.extend_from_slice(&decompressed_size.to_be_bytes());
+        res.extend_from_slice(&compute_md5(&chunk));
+        res.extend_from_slice(&chunk);
+        res
+    }
+
+    #[test]
+    fn test_salsa_cipher_split_buffers() {
+        let key = [0x42; 16];
+        let iv = [1, 2, 3, 4];
+        let plain: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+
+        let mut whole = plain.clone();
+        SalsaCipher::new(key, iv, 3).apply(&mut whole);
+        assert_ne!(plain, whole);
+
+        // Uneven splits that straddle 64 byte keystream blocks
+        let mut split = plain.clone();
+        let mut cipher = SalsaCipher::new(key, iv, 3);
+        let (a, rest) = split.split_at_mut(7);
+        let (b, c) = rest.split_at_mut(130);
+        cipher.apply(a);
+        cipher.apply(b);
+        cipher.apply(c);
+        assert_eq!(1000, cipher.position());
+        assert_eq!(whole, split);
+
+        let mut tail = plain[200..].to_vec();
+        let mut cipher = SalsaCipher::new(key, iv, 3);
+        cipher.seek(200);
+        cipher.apply(&mut tail);
+        assert_eq!(whole[200..], tail);
+
+        let mut other_chunk = plain.clone();
+        SalsaCipher::new(key, iv, 4).apply(&mut other_chunk);
+        assert_ne!(whole, other_chunk);
+    }
+
+    #[test]
+    fn test_decode_blte_prefix() {
+        let keys = TactKeys::default();
+        let espec: ESpec = "b:{1K*9=z,*=n}".parse().unwrap();
+        let input: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let encoded = crate::blte::encode_blte(&keys, &espec, &input).unwrap();
+
+        // Whole chunks, so overshooting to the next chunk boundary
+        let prefix = decode_blte_prefix(&keys, &encoded, 1500).unwrap();
+        assert_eq!(&input[..2048], prefix);
+        assert!(decode_blte_prefix(&keys, &encoded, 0).unwrap().is_empty());
+        assert_eq!(
+            input,
+            decode_blte_prefix(&keys, &encoded, usize::MAX).unwrap()
+        );
+
+        let truncated = &encoded[..encoded.len() - 100];
+        assert_eq!(
+            &input[..2048],
+            decode_blte_prefix(&keys, truncated, 1500).unwrap()
+        );
+        assert!(matches!(
+            decode_blte_prefix(&keys, truncated, usize::MAX),
+            Err(NgdpError::CorruptBlte(_))
+        ));
+    }
+
+    #[test]
+    fn test_chunkless_encrypted() {
+        let mut keys = TactKeys::default();
+        keys.add_key([0x11; 8], [0x22; 16]);
+
+        for espec in [
+            "e:{1111111111111111,33333333,n}",
+            "e:{1111111111111111,33333333,z}",
+        ] {
+            let espec: ESpec = espec.parse().unwrap();
+            let encoded = crate::blte::encode_blte(&keys, &espec, b"hello world").unwrap();
+            assert_eq!([0; 4], encoded[4..8], "chunkless");
+            assert_eq!(
+                b"hello world".as_slice(),
+                decode_blte(&keys, &encoded).unwrap()
+            );
+
+            assert!(matches!(
+                decode_blte(&TactKeys::default(), &encoded),
+                Err(NgdpError::MissingEncryptionKey(keys)) if keys == BTreeSet::from([[0x11; 8]])
+            ));
+        }
+    }
+
+    #[test]
+    fn test_decode_blte_reporting() {
+        let mut keys = TactKeys::default();
+        keys.add_key([0x11; 8], [0x22; 16]);
+
+        let espec: ESpec = "b:{4=n,*=e:{1111111111111111,33333333,n}}".parse().unwrap();
+        let encoded = crate::blte::encode_blte(&keys, &espec, b"hello world").unwrap();
+
+        let res = decode_blte_reporting(&keys, &encoded).unwrap();
+        assert!(res.missing_keys.is_empty());
+        assert_eq!(
+            b"hello world".as_slice(),
+            res.require_all_keys("test").unwrap()
+        );
+
+        let res = decode_blte_reporting(&TactKeys::default(), &encoded).unwrap();
+        assert_eq!(vec![[0x11; 8]], res.missing_keys);
+        assert_eq!(b"hell\0\0\0\0\0\0\0".as_slice(), res.data);
+
+        let err = res.require_all_keys("test manifest").unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("test manifest needs missing encryption keys: 1111111111111111"));
+        assert!(matches!(
+            err.downcast_ref::<NgdpError>(),
+            Some(NgdpError::MissingEncryptionKey(keys)) if *keys == BTreeSet::from([[0x11; 8]])
+        ));
+    }
+
+    #[test]
+    fn test_chunkless() {
+        let keys = TactKeys::default();
+
+        let mut content = b"BLTE\0\0\0\0N".to_vec();
+        content.extend_from_slice(b"hello world");
+        assert_eq!(
+            b"hello world".as_slice(),
+            decode_blte(&keys, &content).unwrap()
+        );
+        assert_eq!(1, describe(&content).unwrap().chunks.len());
+    }
+
+    #[test]
+    fn test_zero_chunks() {
+        let keys = TactKeys::default();
+
+        let mut content = b"BLTE".to_vec();
+        content.extend_from_slice(&12u32.to_be_bytes()); // header size, without any chunks
+        content.extend_from_slice(&[0x0f, 0, 0, 0]); // flags, chunk count
+        assert!(decode_blte(&keys, &content).unwrap().is_empty());
+        assert!(describe(&content).unwrap().chunks.is_empty());
+
+        content.extend_from_slice(b"Nhello world");
+        assert!(decode_blte(&keys, &content).is_err());
+    }
+
+    #[test]
+    fn test_decode_into_reused_buffer() {
+        let keys = TactKeys::default();
+        let input: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+        let encoded =
+            crate::blte::encode_blte(&keys, &"b:{1K*4=z,*=z}".parse().unwrap(), &input).unwrap();
+
+        let mut out = b"left over from a previous file".to_vec();
+        decode_blte_into(&keys, &encoded, &mut out).unwrap();
+        assert_eq!(input, out);
+
+        decode_blte_into(&keys, &single_n_chunk_blte(b"short", 5), &mut out).unwrap();
+        assert_eq!(b"short".as_slice(), out);
+    }
+
+    #[test]
+    fn test_decode_available_chunks() {
+        let keys = TactKeys::default();
+        let input: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+        let encoded =
+            crate::blte::encode_blte(&keys, &"b:{1K*4=z,*=z}".parse().unwrap(), &input).unwrap();
+
+        let len = prefix_len(&encoded, 1500).unwrap().unwrap();
+        assert!(len < encoded.len());
+        let prefix = decode_available_chunks(&keys, &encoded[..len], 1500).unwrap();
+        assert_eq!(&input[..2048], prefix);
+    }
+
+    #[test]
+    fn test_deflate_wrappers() {
+        use flate2::{
+            write::{DeflateEncoder, GzEncoder, ZlibEncoder},
+            Compression,
+        };
+        use std::io::Write;
+
+        let keys = TactKeys::default();
+        let input: Vec<u8> = (0..=255u8).cycle().take(5_000).collect();
+
+        let mut zlib = ZlibEncoder::new(vec![], Compression::default());
+        zlib.write_all(&input).unwrap();
+        let mut gzip = GzEncoder::new(vec![], Compression::default());
+        gzip.write_all(&input).unwrap();
+        let mut raw = DeflateEncoder::new(vec![], Compression::default());
+        raw.write_all(&input).unwrap();
+
+        for (wrapper, compressed) in [
+            (DeflateWrapper::Zlib, zlib.finish().unwrap()),
+            (DeflateWrapper::Gzip, gzip.finish().unwrap()),
+            (DeflateWrapper::Raw, raw.finish().unwrap()),
+        ] {
+            assert_eq!(wrapper, DeflateWrapper::detect(&compressed));
+
+            // With a known size, decoded through libdeflate
+            let blte = single_chunk_blte(b'Z', &compressed, input.len() as u32);
+            assert_eq!(input, decode_blte(&keys, &blte).unwrap(), "{:?}", wrapper);
+
+            // Chunkless, decoded through flate2
+            let mut blte = b"BLTE\0\0\0\0Z".to_vec();
+            blte.extend_from_slice(&compressed);
+            assert_eq!(input, decode_blte(&keys, &blte).unwrap(), "{:?}", wrapper);
+        }
+    }
+
+    #[test]
+    fn test_n_chunk_size_mismatch() {
+        let keys = TactKeys::default();
+        let payload = b"hello world";
+
+        let valid = single_n_chunk_blte(payload, payload.len() as u32);
+        assert_eq!(payload.as_slice(), decode_blte(&keys, &valid).unwrap());
+
+        let mismatched = single_n_chunk_blte(payload, payload.len() as u32 + 1);
+        assert!(decode_blte(&keys, &mismatched).is_err());
+    }
+
+    #[test]
+    fn test_corrupt_blte() {
+        let keys = TactKeys::default();
+        let corrupt = |content: &[u8]| match decode_blte(&keys, content) {
+            Err(NgdpError::CorruptBlte(msg)) => msg,
+            res => panic!("expected CorruptBlte, got {:?}", res),
+        };
+
+        let mut bad_checksum = single_n_chunk_blte(b"hello", 5);
+        *bad_checksum.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            "blte chunk 0 did not match checksum",
+            corrupt(&bad_checksum)
+        );
+
+        assert_eq!(
+            "unknown blte encoding mode: X",
+            corrupt(&single_chunk_blte(b'X', b"hello", 5))
+        );
+        assert_eq!(
+            "recursive blte blocks are not supported",
+            corrupt(&single_chunk_blte(b'F', b"hello", 5))
+        );
+
+        let mut encrypted = vec![4];
+        encrypted.extend_from_slice(&[0x11; 8]);
+        encrypted.push(4);
+        encrypted.extend_from_slice(&[0; 4]);
+        encrypted.extend_from_slice(b"S data");
+        assert!(corrupt(&single_chunk_blte(b'E', &encrypted, 5))
+            .starts_with("unsupported blte encryption header"));
+    }
+}
@@ -9,7 +9,7 @@ use crate::tact::keys::TactKeys;
 
 use super::espec::{self, Block, ESpec, Encrypted, Zip};
 use super::repr;
-use super::{compute_md5, salsa_crypt};
+use super::{compute_md5, SalsaCipher};
 
 pub fn encode_blte(keys: &TactKeys, espec: &ESpec, input: &[u8]) -> Result<Vec<u8>, EncodeError> {
     let mut buf = vec![];
@@ -38,7 +38,10 @@ pub fn encode_blte_into(
     } else {
         4 + 1 + 3 + 4 + 24 * header.chunks.len() as u32
     };
-    header.chunk_count = Some(u24::new(header.chunks.len() as u32));
+    header.chunk_count = Some(
+        u24::try_from(header.chunks.len() as u32)
+            .map_err(|_| EncodeError::TooManyChunks(header.chunks.len()))?,
+    );
 
     // dbg!(&header);
 
@@ -56,6 +59,9 @@ fn process_top(
     header: &mut repr::BLTEHeader,
 ) -> Result<(), EncodeError> {
     match espec {
+        ESpec::Encrypted(v) if matches!(*v.inner, ESpec::Blocks(_)) => {
+            process_top(keys, &encrypt_blocks(v), input, buf, header)?;
+        }
         ESpec::Raw | ESpec::Zip(_) | ESpec::Encrypted(_) => {
             process_inner(keys, espec, input, buf, 0)?;
         }
@@ -69,11 +75,38 @@ fn process_top(
             if !input.is_empty() {
                 return Err(EncodeError::LeftoverData(input.len()));
             }
+
+            // Greedy blocks produce no chunks for empty input, but a file needs at least one
+            if header.chunks.is_empty() {
+                process_chunk(keys, &v.final_.inner, &[], buf, header)?;
+            }
         }
     }
     Ok(())
 }
 
+/// Turns `e:{key,iv,b:{...}}` into `b:{...}` with every block encrypted, so each chunk is
+/// encrypted on its own with its chunk index mixed into the iv
+fn encrypt_blocks(v: &Encrypted) -> ESpec {
+    let ESpec::Blocks(blocks) = &*v.inner else {
+        unreachable!("only called for encrypted blocks")
+    };
+
+    let encrypt = |block: &Block| Block {
+        size: block.size.clone(),
+        inner: ESpec::Encrypted(Encrypted {
+            key: v.key,
+            iv: v.iv,
+            inner: Box::new(block.inner.clone()),
+        }),
+    };
+
+    ESpec::Blocks(espec::Blocks {
+        blocks: blocks.blocks.iter().map(encrypt).collect(),
+        final_: Box::new(encrypt(&blocks.final_)),
+    })
+}
+
 fn process_block<'a>(
     keys: &TactKeys,
     block: &Block,
@@ -81,20 +114,7 @@ fn process_block<'a>(
     buf: &mut Vec<u8>,
     header: &mut repr::BLTEHeader,
 ) -> Result<&'a [u8], EncodeError> {
-    let mut process_chunk = |input: &[u8]| -> Result<(), EncodeError> {
-        let start_pos = buf.len();
-        process_inner(keys, &block.inner, input, buf, header.chunks.len())?;
-        let end_pos = buf.len();
-
-        let checksum = compute_md5(&buf[start_pos..end_pos]);
-
-        header.chunks.push(repr::ChunkInfo {
-            compressed_size: (end_pos - start_pos) as u32,
-            decompressed_size: input.len() as u32,
-            checksum,
-        });
-        Ok(())
-    };
+    let mut push_chunk = |input: &[u8]| process_chunk(keys, &block.inner, input, buf, header);
 
     let mut rest = input;
     match block.size {
@@ -108,7 +128,7 @@ fn process_block<'a>(
                 let input;
                 (input, rest) = rest.split_at(size as usize);
 
-                process_chunk(input)?;
+                push_chunk(input)?;
 
                 num_chunks += 1;
                 if num_chunks >= count {
@@ -124,10 +144,12 @@ fn process_block<'a>(
             let input;
             (input, rest) = rest.split_at((size as usize).min(rest.len()));
 
-            process_chunk(input)?;
+            push_chunk(input)?;
         },
         espec::BlockSize::Greedy => {
-            process_chunk(input)?;
+            if !input.is_empty() {
+                push_chunk(input)?;
+            }
             rest = &input[input.len()..];
         }
     }
@@ -135,6 +157,27 @@ fn process_block<'a>(
     Ok(rest)
 }
 
+fn process_chunk(
+    keys: &TactKeys,
+    espec: &ESpec,
+    input: &[u8],
+    buf: &mut Vec<u8>,
+    header: &mut repr::BLTEHeader,
+) -> Result<(), EncodeError> {
+    let start_pos = buf.len();
+    process_inner(keys, espec, input, buf, header.chunks.len())?;
+    let end_pos = buf.len();
+
+    let checksum = compute_md5(&buf[start_pos..end_pos]);
+
+    header.chunks.push(repr::ChunkInfo {
+        compressed_size: (end_pos - start_pos) as u32,
+        decompressed_size: input.len() as u32,
+        checksum,
+    });
+    Ok(())
+}
+
 fn process_inner(
     keys: &TactKeys,
     espec: &ESpec,
@@ -149,9 +192,7 @@ fn process_inner(
         }
         ESpec::Zip(v) => process_zip(v, input, buf),
         ESpec::Encrypted(v) => process_encrypt(keys, v, input, buf, block_index)?,
-        ESpec::Blocks(_) => {
-            panic!("Nested BLTE blocks not supported")
-        }
+        ESpec::Blocks(_) => return Err(EncodeError::NestedBlocks),
     }
     Ok(())
 }
@@ -163,8 +204,9 @@ fn process_zip(v: &Zip, input: &[u8], buf: &mut Vec<u8>) {
     let level = Compression::new(v.level as u32);
     let window_bits = match v.bits {
         espec::ZipBits::Bits(bits) => bits,
+        // Matches the table used by StormLib/WoW for MPQ compression
         espec::ZipBits::MPQ => match input.len() {
-            // v if v <= 0x100 => 8,
+            v if v <= 0x100 => 8,
             v if v <= 0x200 => 9,
             v if v <= 0x400 => 10,
             v if v <= 0x800 => 11,
@@ -175,7 +217,9 @@ fn process_zip(v: &Zip, input: &[u8], buf: &mut Vec<u8>) {
         },
     };
 
-    let mut compress = Compress::new_with_window_bits(level, true, window_bits);
+    // zlib itself compresses a zlib wrapped stream with 8 window bits exactly as with 9, header
+    // included, so an 8 bit window can't be produced. flate2 rejects 8, so pass the 9 zlib would use.
+    let mut compress = Compress::new_with_window_bits(level, true, window_bits.max(9));
 
     // FIXME: Upstream a constructor to create ZlibEncoder with provided Compress
     // FIXME: Better allocation strategy
@@ -232,18 +276,10 @@ fn process_encrypt(
     encrypt_header.write(&mut Cursor::new(header_buf.as_mut_slice()))?;
     buf.extend_from_slice(&header_buf);
 
-    let mut full_iv = [0; 8];
-    full_iv[0..4].copy_from_slice(&v.iv);
-
-    let index = block_index.to_le_bytes();
-    for i in 0..4 {
-        full_iv[i] ^= index[i];
-    }
-
     let mut inner_buf = vec![];
     process_inner(keys, &v.inner, input, &mut inner_buf, block_index)?;
 
-    salsa_crypt(key, full_iv, &mut inner_buf);
+    SalsaCipher::new(key, v.iv, block_index as u32).apply(&mut inner_buf);
 
     buf.extend_from_slice(&inner_buf);
 
@@ -258,8 +294,134 @@ pub enum EncodeError {
     ChunkUnderflow(u64, usize),
     #[error("leftover data after main block: {0} bytes")]
     LeftoverData(usize),
+    #[error("blocks can only appear at the top level of an espec")]
+    NestedBlocks,
+    #[error("too many chunks for a blte header: {0}")]
+    TooManyChunks(usize),
     #[error("error writing to supplied writer: {0}")]
     IoError(#[from] std::io::Error),
     #[error("error writing structure to underlying writer: {0}")]
     BinError(#[from] binrw::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blte::decode_blte;
+
+    fn zlib_header(encoded: &[u8]) -> (u8, u8) {
+        // Chunkless BLTE: magic, zero header size, then the 'Z' mode byte
+        assert_eq!(b'Z', encoded[8]);
+        (encoded[9], encoded[10])
+    }
+
+    #[test]
+    fn test_encode_empty_file() {
+        let keys = TactKeys::default();
+        for espec in ["b:{*=z}", "b:{*=n}", "b:{4K*=z}", "b:{4K*=n}"] {
+            let espec: ESpec = espec.parse().unwrap();
+            let encoded = encode_blte(&keys, &espec, &[]).unwrap();
+
+            // Exactly one empty chunk
+            let header_size = u32::from_be_bytes(encoded[4..8].try_into().unwrap());
+            assert_eq!(4 + 1 + 3 + 4 + 24, header_size, "{}", espec);
+            assert_eq!(&[0, 0, 1], &encoded[9..12], "{}", espec);
+
+            assert!(
+                decode_blte(&keys, &encoded).unwrap().is_empty(),
+                "{}",
+                espec
+            );
+        }
+
+        // Input that fills earlier blocks exactly leaves the greedy block with nothing
+        let espec: ESpec = "b:{4=n,*=z}".parse().unwrap();
+        let encoded = encode_blte(&keys, &espec, b"abcd").unwrap();
+        assert_eq!(&[0, 0, 1], &encoded[9..12]);
+        assert_eq!(b"abcd".as_slice(), decode_blte(&keys, &encoded).unwrap());
+    }
+
+    #[test]
+    fn test_mpq_window_bits() {
+        let keys = TactKeys::default();
+        let espec: ESpec = "z:{9,mpq}".parse().unwrap();
+
+        // Inputs up to 256 bytes would get 8 bits, which zlib raises to 9
+        for (len, expected_cinfo) in [(0x80, 1), (0x100, 1), (0x101, 1), (0x1000, 4), (0x10000, 7)]
+        {
+            let input: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+            let encoded = encode_blte(&keys, &espec, &input).unwrap();
+
+            let (cmf, flg) = zlib_header(&encoded);
+            assert_eq!(
+                expected_cinfo,
+                cmf >> 4,
+                "window size for input of {} bytes",
+                len
+            );
+            assert_eq!(0, (cmf as u16 * 256 + flg as u16) % 31, "zlib header check");
+
+            assert_eq!(input, decode_blte(&keys, &encoded).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_mpq_small_input_reference_bytes() {
+        let keys = TactKeys::default();
+        let espec: ESpec = "z:{9,mpq}".parse().unwrap();
+        let input: Vec<u8> = (0..0x80).map(|i| i as u8).collect();
+
+        // Output of zlib 1.2.13's deflateInit2(9, Z_DEFLATED, 8, ...) for the same input,
+        // produced independently of this encoder. zlib writes a 512 byte window (0x18) for 8 bits.
+        let reference = hex::decode(
+            "18d36360646266616563e7e0e4e2e6e1e5e3171014121611151397909492969195935750545256515553\
+             d7d0d4d2d6d1d5d33730343236313533b7b0b4b2b6b1b5b37770747276717573f7f0f4f2f6f1f5f30f08\
+             0c0a0e090d0b8f888c8a8e898d8b4f484c4a4e494d4bcfc8cccacec9cdcb2f282c2a2e292d2bafa8acaa\
+             aea9adab0700560b1fc1",
+        )
+        .unwrap();
+
+        let encoded = encode_blte(&keys, &espec, &input).unwrap();
+        assert_eq!(b"BLTE\0\0\0\0Z", &encoded[..9]);
+        assert_eq!(reference, encoded[9..]);
+    }
+
+    #[test]
+    fn test_infer_espec_round_trip() {
+        let keys = TactKeys::default();
+        let espec: ESpec = "b:{16K*2=z,4K=n,*=z:{6,12}}".parse().unwrap();
+
+        let input: Vec<u8> = (0..50_000).map(|i| (i % 251) as u8).collect();
+        let encoded = encode_blte(&keys, &espec, &input).unwrap();
+
+        let (decoded, inferred) = crate::blte::decode_blte_with_espec(&keys, &encoded).unwrap();
+        assert_eq!(input, decoded);
+        assert_eq!(encoded, encode_blte(&keys, &inferred, &input).unwrap());
+    }
+
+    #[test]
+    fn test_encrypted_blocks_round_trip() {
+        let mut keys = TactKeys::default();
+        keys.add_key([0x11; 8], [0x22; 16]);
+        let espec: ESpec = "e:{1111111111111111,33333333,b:{4K*3=z,*=n}}"
+            .parse()
+            .unwrap();
+
+        let input: Vec<u8> = (0..20_000).map(|i| (i % 251) as u8).collect();
+        let encoded = encode_blte(&keys, &espec, &input).unwrap();
+        assert_eq!(input, decode_blte(&keys, &encoded).unwrap());
+
+        // Same layout as encrypting each block individually
+        let per_block: ESpec =
+            "b:{4K*3=e:{1111111111111111,33333333,z},*=e:{1111111111111111,33333333,n}}"
+                .parse()
+                .unwrap();
+        assert_eq!(encoded, encode_blte(&keys, &per_block, &input).unwrap());
+
+        let nested: ESpec = "b:{*=b:{*=n}}".parse().unwrap();
+        assert!(matches!(
+            encode_blte(&keys, &nested, &input),
+            Err(EncodeError::NestedBlocks)
+        ));
+    }
+}
@@ -63,6 +63,30 @@ impl Display for ESpec {
     }
 }
 
+impl ESpec {
+    /// Names of every encryption key referenced by this espec, including nested ones
+    pub fn key_names(&self) -> Vec<[u8; 8]> {
+        let mut res = vec![];
+        self.collect_key_names(&mut res);
+        res
+    }
+
+    fn collect_key_names(&self, out: &mut Vec<[u8; 8]>) {
+        match self {
+            ESpec::Raw | ESpec::Zip(_) => {}
+            ESpec::Encrypted(v) => {
+                out.push(v.key);
+                v.inner.collect_key_names(out);
+            }
+            ESpec::Blocks(v) => {
+                for block in v.blocks.iter().chain(std::iter::once(v.final_.as_ref())) {
+                    block.inner.collect_key_names(out);
+                }
+            }
+        }
+    }
+}
+
 impl Debug for ESpec {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -0,0 +1,174 @@
+use std::{collections::HashMap, path::Path};
+
+use crate::tact::ContentKey;
+
+/// Contents of the `.build.info` file at the root of an install, one entry per branch
+#[derive(Debug)]
+pub struct BuildInfo {
+    pub entries: Vec<BuildInfoEntry>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BuildInfoEntry {
+    /// Values keyed by column name, with the `!TYPE:size` suffix stripped
+    pub fields: HashMap<String, String>,
+}
+
+impl BuildInfoEntry {
+    pub fn get(&self, column: &str) -> Option<&str> {
+        self.fields.get(column).map(String::as_str)
+    }
+
+    pub fn branch(&self) -> Option<&str> {
+        self.get("Branch")
+    }
+
+    pub fn active(&self) -> bool {
+        self.get("Active") == Some("1")
+    }
+
+    pub fn build_key(&self) -> Option<ContentKey> {
+        self.get("Build Key")
+            .and_then(|v| ContentKey::parse(v).ok())
+    }
+
+    pub fn cdn_key(&self) -> Option<ContentKey> {
+        self.get("CDN Key").and_then(|v| ContentKey::parse(v).ok())
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.get("Version")
+    }
+
+    pub fn product(&self) -> Option<&str> {
+        self.get("Product")
+    }
+}
+
+impl BuildInfo {
+    pub fn read(install_path: &Path) -> Result<BuildInfo, anyhow::Error> {
+        let content = std::fs::read_to_string(install_path.join(".build.info"))?;
+        parse_build_info(&content)
+    }
+
+    /// The entry the client considers live. Errors unless exactly one entry is active.
+    pub fn active_entry(&self) -> Result<&BuildInfoEntry, anyhow::Error> {
+        let mut active = self.entries.iter().filter(|e| e.active());
+        match (active.next(), active.next()) {
+            (Some(entry), None) => Ok(entry),
+            (None, _) => anyhow::bail!(
+                ".build.info has no active entry (branches: {})",
+                self.branches()
+            ),
+            (Some(_), Some(_)) => anyhow::bail!(
+                ".build.info has several active entries (branches: {})",
+                self.branches()
+            ),
+        }
+    }
+
+    /// The entry for `branch`, whether it's active or not
+    pub fn entry_for_branch(&self, branch: &str) -> Result<&BuildInfoEntry, anyhow::Error> {
+        self.entries
+            .iter()
+            .find(|e| {
+                e.branch()
+                    .is_some_and(|b| b.eq_ignore_ascii_case(branch.trim()))
+            })
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    ".build.info has no entry for branch {:?} (branches: {})",
+                    branch,
+                    self.branches()
+                )
+            })
+    }
+
+    /// The entry for `branch` if given, otherwise the active entry
+    pub fn select(&self, branch: Option<&str>) -> Result<&BuildInfoEntry, anyhow::Error> {
+        match branch {
+            Some(branch) => self.entry_for_branch(branch),
+            None => self.active_entry(),
+        }
+    }
+
+    fn branches(&self) -> String {
+        self.entries
+            .iter()
+            .map(|e| e.branch().unwrap_or("<none>"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+pub fn parse_build_info(content: &str) -> Result<BuildInfo, anyhow::Error> {
+    let mut lines = content.lines().filter(|l| !l.is_empty());
+
+    let header = lines
+        .next()
+        .ok_or_else(|| anyhow::anyhow!(".build.info is empty"))?;
+    let columns: Vec<&str> = header
+        .split('|')
+        .map(|c| c.split_once('!').map_or(c, |(name, _type)| name))
+        .collect();
+
+    let mut entries = vec![];
+    for line in lines {
+        let values: Vec<&str> = line.split('|').collect();
+        if values.len() != columns.len() {
+            anyhow::bail!(
+                ".build.info row has {} values, expected {}",
+                values.len(),
+                columns.len()
+            );
+        }
+
+        entries.push(BuildInfoEntry {
+            fields: columns
+                .iter()
+                .zip(values)
+                .map(|(c, v)| (c.to_string(), v.to_string()))
+                .collect(),
+        });
+    }
+
+    Ok(BuildInfo { entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn build_info(rows: &[(&str, &str)]) -> BuildInfo {
+        let mut content = String::from("Branch!STRING:0|Active!DEC:1|Version!STRING:0\n");
+        for (branch, active) in rows {
+            content += &format!("{}|{}|1.0.{}\n", branch, active, branch);
+        }
+        parse_build_info(&content).unwrap()
+    }
+
+    #[test]
+    fn test_active_entry() {
+        let info = build_info(&[("us", "0"), ("eu", "1"), ("kr", "0")]);
+        assert_eq!(Some("eu"), info.active_entry().unwrap().branch());
+        assert_eq!(Some("eu"), info.select(None).unwrap().branch());
+        assert_eq!(Some("1.0.kr"), info.select(Some("KR")).unwrap().version());
+
+        let err = info.select(Some("cn")).unwrap_err();
+        assert!(err.to_string().contains("branches: us, eu, kr"), "{}", err);
+
+        let err = build_info(&[("us", "0"), ("eu", "0")])
+            .active_entry()
+            .unwrap_err();
+        assert!(err.to_string().contains("no active entry"), "{}", err);
+
+        let err = build_info(&[("us", "1"), ("eu", "1")])
+            .active_entry()
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("several active entries"),
+            "{}",
+            err
+        );
+    }
+}
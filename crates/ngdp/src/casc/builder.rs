@@ -0,0 +1,721 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use byteorder::{WriteBytesExt, BE, LE};
+
+use super::{idx, shmem::Shmem, FileHeader};
+use crate::{
+    blte::{compute_md5, encode_blte, espec::ESpec},
+    casc::idx::Indexes,
+    tact::{
+        cdn::{check_complete, CDNClient, CDNReader},
+        config::{EncodedPair, HashSize},
+        encoding::Encoding,
+        keys::TactKeys,
+        root::{ContentFlags, LocaleFlags, Root},
+        ContentKey, EncodingKey, HashingReader,
+    },
+    util::format_hex_bytes_le,
+};
+
+pub(crate) const ESPEC: &str = "z";
+const ENCODING_PAGE_SIZE_KB: u16 = 4;
+
+pub(crate) struct EncodedFile {
+    pub ckey: ContentKey,
+    pub ekey: EncodingKey,
+    decoded_size: u64,
+    pub blte: Vec<u8>,
+}
+
+impl EncodedFile {
+    pub(crate) fn new(
+        tact_keys: &TactKeys,
+        espec: &ESpec,
+        data: &[u8],
+    ) -> Result<Self, anyhow::Error> {
+        let blte = encode_blte(tact_keys, espec, data)?;
+        Ok(EncodedFile {
+            ckey: ContentKey::from_data(data),
+            ekey: EncodingKey::from_blte(&blte)?,
+            decoded_size: data.len() as u64,
+            blte,
+        })
+    }
+}
+
+/// Builds a CASC from scratch in `out_dir`, containing the given `(path, data)` pairs.
+/// Files are given file data ids in the order they're passed in.
+/// Returns the text of a build config referencing the written root and encoding files.
+pub fn build_casc_from_files(
+    out_dir: &Path,
+    files: &[(String, Vec<u8>)],
+) -> Result<String, anyhow::Error> {
+    let tact_keys = TactKeys::default();
+    let espec: ESpec = ESPEC.parse()?;
+
+    let mut encoded = files
+        .iter()
+        .map(|(_name, data)| EncodedFile::new(&tact_keys, &espec, data))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let root_data = write_root(files)?;
+    let root = EncodedFile::new(&tact_keys, &espec, &root_data)?;
+    let root_ckey = root.ckey.clone();
+    encoded.push(root);
+
+    // Identical files share a single encoding entry and data block
+    let mut seen = HashSet::new();
+    encoded.retain(|file| seen.insert(file.ckey.clone()));
+
+    let encoding_data = write_encoding(&encoded)?;
+    let encoding = EncodedFile::new(&tact_keys, &espec, &encoding_data)?;
+
+    let data_dir = out_dir.join("Data").join("data");
+    std::fs::create_dir_all(&data_dir)?;
+
+    let mut shmem = Shmem::new("Global\\../Data/data");
+    let mut indexes = Indexes::default();
+    let mut data_files: HashMap<u16, File> = HashMap::new();
+
+    for file in encoded.iter().chain(std::iter::once(&encoding)) {
+        let total_size = file.blte.len() + FileHeader::SIZE;
+        let slot = shmem.reserve_bytes(total_size)?;
+
+        let data_file = match data_files.entry(slot.data_number) {
+            std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+            std::collections::hash_map::Entry::Vacant(e) => {
+                let path = data_dir.join(format!("data.{:03}", slot.data_number));
+                e.insert(File::create(path)?)
+            }
+        };
+
+        let header = FileHeader {
+            hash: file.ekey.to_rev(),
+            size: total_size as u32,
+            _unk: [0, 0],
+            checksum_a: 0,
+            checksum_b: 0,
+        };
+        data_file.seek(SeekFrom::Start(slot.offset as u64))?;
+        header.write_to(slot.data_number, slot.offset, data_file)?;
+        data_file.write_all(&file.blte)?;
+
+        indexes.insert(
+            &file.ekey,
+            idx::Entry {
+                archive_index: slot.data_number,
+                offset: slot.offset,
+                size: total_size as u32,
+            },
+        )?;
+    }
+
+    indexes.write(shmem.index_versions, &data_dir)?;
+
+    let mut buf = Vec::with_capacity(16 * 1024);
+    shmem.write(&mut buf)?;
+    std::fs::write(data_dir.join("shmem"), buf)?;
+
+    Ok(format!(
+        "# Build Configuration\n\nroot = {:?}\nencoding = {:?} {:?}\nencoding-size = {} {}\n",
+        root_ckey,
+        encoding.ckey,
+        encoding.ekey,
+        encoding.decoded_size,
+        encoding.blte.len()
+    ))
+}
+
+/// Writes a single block root containing every file, with names and no flags
+fn write_root(files: &[(String, Vec<u8>)]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut name_hashes = HashSet::new();
+    for (name, _data) in files {
+        if !name_hashes.insert(Root::hashpath(name)) {
+            anyhow::bail!("duplicate path in root: {}", name);
+        }
+    }
+
+    let mut w = vec![];
+    w.write_all(b"TSFM")?;
+    w.write_u32::<LE>(files.len() as u32)?; // total_file_count
+    w.write_u32::<LE>(files.len() as u32)?; // named_file_count
+
+    w.write_u32::<LE>(files.len() as u32)?;
+    w.write_u32::<LE>(ContentFlags::empty().bits())?;
+    w.write_u32::<LE>(LocaleFlags::all().bits())?;
+    for _ in files {
+        // File data ids are sequential, starting at 0
+        w.write_i32::<LE>(0)?;
+    }
+    for (_name, data) in files {
+        w.write_all(ContentKey::from_data(data).as_slice())?;
+    }
+    for (name, _data) in files {
+        w.write_u64::<LE>(Root::hashpath(name))?;
+    }
+
+    Ok(w)
+}
+
+/// Writes an encoding table where every file uses the same espec
+pub(crate) fn write_encoding(files: &[EncodedFile]) -> Result<Vec<u8>, anyhow::Error> {
+    let entries = EncodingEntries {
+        especs: vec![ESPEC.to_string()],
+        by_ckey: files
+            .iter()
+            .map(|f| (f.ckey.clone(), (f.decoded_size, vec![f.ekey.clone()])))
+            .collect(),
+        by_ekey: files
+            .iter()
+            .map(|f| (f.ekey.clone(), (0, f.blte.len() as u64)))
+            .collect(),
+    };
+    write_encoding_entries(&entries)
+}
+
+/// The contents of an encoding table, sorted by key as they're written
+#[derive(Default)]
+pub(crate) struct EncodingEntries {
+    pub especs: Vec<String>,
+    /// Decoded size and encoding keys of each content key
+    pub by_ckey: BTreeMap<ContentKey, (u64, Vec<EncodingKey>)>,
+    /// Espec index and encoded size of each encoding key
+    pub by_ekey: BTreeMap<EncodingKey, (u32, u64)>,
+}
+
+impl EncodingEntries {
+    fn from_encoding(encoding: &Encoding) -> EncodingEntries {
+        // Zeroed page padding parses as entries with zero keys
+        let by_ckey = encoding
+            .cekey_pages
+            .iter()
+            .flat_map(|page| &page.entries.0)
+            .filter(|e| e.ckey != ContentKey::ZERO)
+            .map(|e| (e.ckey.clone(), (e.file_size.into(), e.ekeys.clone())))
+            .collect();
+        let by_ekey = encoding
+            .ekey_spec_pages
+            .iter()
+            .flat_map(|page| &page.entries.0)
+            .filter(|e| e.ekey != EncodingKey::ZERO)
+            .map(|e| (e.ekey.clone(), (e.espec_index, e.file_size.into())))
+            .collect();
+
+        EncodingEntries {
+            especs: encoding.especs.iter().map(|e| e.to_string()).collect(),
+            by_ckey,
+            by_ekey,
+        }
+    }
+
+    fn espec_index(&mut self, espec: &ESpec) -> u32 {
+        let espec = espec.to_string();
+        match self.especs.iter().position(|e| *e == espec) {
+            Some(idx) => idx as u32,
+            None => {
+                self.especs.push(espec);
+                self.especs.len() as u32 - 1
+            }
+        }
+    }
+}
+
+pub(crate) fn write_encoding_entries(entries: &EncodingEntries) -> Result<Vec<u8>, anyhow::Error> {
+    let page_size = ENCODING_PAGE_SIZE_KB as usize * 1024;
+
+    let cekey_pages = paginate(
+        page_size,
+        entries.by_ckey.iter(),
+        |w, (ckey, (size, ekeys))| {
+            w.write_u8(ekeys.len() as u8)?;
+            w.write_uint::<BE>(*size, 5)?;
+            w.write_all(ckey.as_slice())?;
+            for ekey in ekeys {
+                w.write_all(ekey.as_slice())?;
+            }
+            Ok(ckey.as_slice().to_vec())
+        },
+    )?;
+
+    let ekey_spec_pages = paginate(
+        page_size,
+        entries.by_ekey.iter(),
+        |w, (ekey, (espec_index, size))| {
+            w.write_all(ekey.as_slice())?;
+            w.write_u32::<BE>(*espec_index)?;
+            w.write_uint::<BE>(*size, 5)?;
+            Ok(ekey.as_slice().to_vec())
+        },
+    )?;
+
+    let espec_block: String = entries.especs.iter().map(|e| format!("{}\0", e)).collect();
+
+    let mut w = vec![];
+    w.write_all(b"EN")?;
+    w.write_u8(1)?; // version
+    w.write_u8(16)?; // hash_size_ckey
+    w.write_u8(16)?; // hash_size_ekey
+    w.write_u16::<BE>(ENCODING_PAGE_SIZE_KB)?;
+    w.write_u16::<BE>(ENCODING_PAGE_SIZE_KB)?;
+    w.write_u32::<BE>(cekey_pages.len() as u32)?;
+    w.write_u32::<BE>(ekey_spec_pages.len() as u32)?;
+    w.write_u8(0)?; // unk
+    w.write_u32::<BE>(espec_block.len() as u32)?;
+    w.write_all(espec_block.as_bytes())?;
+
+    for pages in [cekey_pages, ekey_spec_pages] {
+        for (first_key, page) in &pages {
+            w.write_all(first_key)?;
+            w.write_all(&compute_md5(page))?;
+        }
+        for (_first_key, page) in &pages {
+            w.write_all(page)?;
+        }
+    }
+
+    // Every caller encodes the table with ESPEC
+    w.write_all(ESPEC.as_bytes())?;
+
+    Ok(w)
+}
+
+/// A zero padded encoding page, along with the first key in it
+type Page = (Vec<u8>, Vec<u8>);
+
+/// Splits entries into encoding pages
+fn paginate<T>(
+    page_size: usize,
+    entries: impl Iterator<Item = T>,
+    mut write_entry: impl FnMut(&mut Vec<u8>, T) -> Result<Vec<u8>, std::io::Error>,
+) -> Result<Vec<Page>, anyhow::Error> {
+    let mut pages: Vec<Page> = vec![];
+    let mut current: Option<Page> = None;
+
+    for entry in entries {
+        let mut buf = vec![];
+        let key = write_entry(&mut buf, entry)?;
+        if buf.len() > page_size {
+            anyhow::bail!("encoding entry larger than page size");
+        }
+
+        if let Some((_, page)) = &current {
+            if page.len() + buf.len() > page_size {
+                pages.extend(current.take());
+            }
+        }
+        let (_, page) = current.get_or_insert_with(|| (key, Vec::with_capacity(page_size)));
+        page.extend_from_slice(&buf);
+    }
+    pages.extend(current);
+
+    if pages.is_empty() {
+        pages.push((vec![0; 16], vec![]));
+    }
+    for (_first_key, page) in &mut pages {
+        page.resize(page_size, 0);
+    }
+
+    Ok(pages)
+}
+
+/// Incrementally writes to an existing CASC, or starts a new one
+pub struct CASCBuilder {
+    root: PathBuf,
+    pub shmem: Shmem,
+    pub indexes: Indexes,
+    index_changed: [bool; 16], // Really can't be bothere to make it a bitset
+}
+
+impl CASCBuilder {
+    pub fn new(root: &Path) -> Self {
+        CASCBuilder {
+            root: root.to_owned(),
+            // TODO: I'm just copying what WoW does, is that correct?
+            shmem: Shmem::new("Global\\../Data/data"),
+            indexes: Indexes::default(),
+            index_changed: [false; 16],
+        }
+    }
+
+    pub fn load(root: &Path) -> Result<Self, anyhow::Error> {
+        let data_path = root.join("Data").join("data");
+
+        let shmem_data = std::fs::read(data_path.join("shmem"))?;
+        let shmem = Shmem::parse(&shmem_data)?;
+
+        let indexes = Indexes::read(&data_path, &shmem)?;
+
+        Ok(CASCBuilder {
+            root: root.to_owned(),
+            shmem,
+            indexes,
+            index_changed: [false; 16],
+        })
+    }
+
+    pub fn write(&mut self) -> Result<(), anyhow::Error> {
+        let data_dir = self.root.join("Data").join("data");
+
+        for (idx, has_changed) in self.index_changed.iter().copied().enumerate() {
+            if has_changed {
+                // This could technically overflow, but for ordinary usage it'll never happen
+                self.shmem.index_versions[idx] += 1;
+            }
+        }
+
+        // TODO: Delete old versions
+        self.indexes.write(self.shmem.index_versions, &data_dir)?;
+
+        let mut buf = Vec::with_capacity(16 * 1024);
+        self.shmem.write(&mut buf)?;
+        std::fs::write(data_dir.join("shmem"), buf)?;
+
+        Ok(())
+    }
+
+    pub fn read_config(&self, cdn: &CDNClient, key: &ContentKey) -> Result<String, anyhow::Error> {
+        let formatted = format_hex_bytes_le(&key.to_inner());
+        let path = self
+            .root
+            .join("Data")
+            .join("config")
+            .join(&formatted[0..2])
+            .join(&formatted[2..4])
+            .join(formatted);
+        let res = self.try_read(&path, 0, || cdn.read_config(key), Some(key))?;
+
+        String::from_utf8(res).map_err(|e| e.into())
+    }
+
+    pub fn read_archive_index(
+        &self,
+        cdn: &CDNClient,
+        key: &EncodingKey,
+        expected_size: usize,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        let path = self
+            .root
+            .join("Data")
+            .join("indices")
+            .join(format!("{:?}.index", key));
+        self.try_read(&path, expected_size, || cdn.read_index(key), None)
+    }
+
+    fn try_read(
+        &self,
+        path: &Path,
+        expected_size: usize,
+        get_reader: impl FnOnce() -> Result<CDNReader, anyhow::Error>,
+        expected_key: Option<&ContentKey>,
+    ) -> Result<Vec<u8>, anyhow::Error> {
+        // If the cached copy is unreadable, truncated or corrupt, redownload it
+        if let Ok(res) = std::fs::read(path) {
+            let size_ok = expected_size == 0 || res.len() == expected_size;
+            let key_ok = expected_key.is_none_or(|key| ContentKey::from_data(&res) == *key);
+            if size_ok && key_ok {
+                return Ok(res);
+            }
+        };
+
+        // Hash and size check while downloading, so corrupt data never makes it into the cache
+        let reader = get_reader()?;
+        let content_length = reader.content_length();
+        let mut reader = HashingReader::new(reader);
+        let mut data = Vec::with_capacity(expected_size);
+        reader.read_to_end(&mut data)?;
+        check_complete(data.len(), expected_size, content_length)
+            .with_context(|| format!("downloading {}", path.display()))?;
+        if expected_size != 0 && data.len() != expected_size {
+            anyhow::bail!(
+                "downloaded {} bytes for {}, expected {}",
+                data.len(),
+                path.display(),
+                expected_size
+            );
+        }
+        if let Some(key) = expected_key {
+            let computed_key = reader.content_key();
+            if computed_key != *key {
+                anyhow::bail!(
+                    "downloaded hash not correct! expected: {:?}, calculated: {:?}",
+                    key,
+                    computed_key
+                );
+            }
+        }
+
+        let parent = path
+            .parent()
+            .ok_or_else(|| anyhow::anyhow!("read path had no parent directory"))?;
+        std::fs::create_dir_all(parent)
+            .and_then(|_| std::fs::write(path, &data))
+            .with_context(|| format!("saving copy of {}", path.display()))?;
+
+        Ok(data)
+    }
+
+    /// Writes an encoded file to free space in a data file, and indexes it
+    pub fn write_data(&mut self, ekey: &EncodingKey, blte: &[u8]) -> Result<(), anyhow::Error> {
+        let total_size = blte.len() + FileHeader::SIZE;
+        let slot = self.shmem.reserve_bytes(total_size)?;
+
+        let data_dir = self.root.join("Data").join("data");
+        std::fs::create_dir_all(&data_dir)?;
+        let path = data_dir.join(format!("data.{:03}", slot.data_number));
+        let mut f = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        f.seek(SeekFrom::Start(slot.offset as u64))?;
+
+        let header = FileHeader {
+            hash: ekey.to_rev(),
+            size: total_size as u32,
+            _unk: [0, 0],
+            checksum_a: 0,
+            checksum_b: 0,
+        };
+        header.write_to(slot.data_number, slot.offset, &mut f)?;
+        f.write_all(blte)?;
+
+        self.insert_in_index(
+            ekey,
+            idx::Entry {
+                archive_index: slot.data_number,
+                offset: slot.offset,
+                size: total_size as u32,
+            },
+        )
+    }
+
+    /// Points `ckey` at `new_data`, encoded with `espec`, keeping the ckey so the root still finds it.
+    /// The old data is freed, and an updated encoding table written, returned as the build config
+    /// entry to use from now on. The old encoding table is kept, as the current build config uses it.
+    pub fn replace_file(
+        &mut self,
+        tact_keys: &TactKeys,
+        encoding: &Encoding,
+        ckey: &ContentKey,
+        new_data: &[u8],
+        espec: &ESpec,
+    ) -> Result<EncodedPair, anyhow::Error> {
+        let old_ekeys = encoding
+            .lookup_by_ckey(ckey)
+            .ok_or_else(|| anyhow::anyhow!("couldn't find encoding for ckey: {:?}", ckey))?
+            .ekeys
+            .clone();
+
+        let file = EncodedFile::new(tact_keys, espec, new_data)?;
+        if self.indexes.lookup(&file.ekey).is_none() {
+            self.write_data(&file.ekey, &file.blte)?;
+        }
+
+        let mut entries = EncodingEntries::from_encoding(encoding);
+        let espec_index = entries.espec_index(espec);
+        entries
+            .by_ckey
+            .insert(ckey.clone(), (file.decoded_size, vec![file.ekey.clone()]));
+        entries
+            .by_ekey
+            .insert(file.ekey.clone(), (espec_index, file.blte.len() as u64));
+
+        // Other content may share the old data, only free it once nothing refers to it
+        for old_ekey in old_ekeys {
+            let in_use = entries
+                .by_ckey
+                .values()
+                .any(|(_size, ekeys)| ekeys.contains(&old_ekey));
+            if in_use {
+                continue;
+            }
+
+            entries.by_ekey.remove(&old_ekey);
+            let (bucket, old_entry) = self.indexes.remove(&old_ekey);
+            if let Some(old_entry) = old_entry {
+                self.index_changed[bucket] = true;
+                self.shmem
+                    .free_bytes(old_entry.archive_index, old_entry.offset, old_entry.size)?;
+            }
+        }
+
+        let encoding_data = write_encoding_entries(&entries)?;
+        let encoding_file = EncodedFile::new(tact_keys, &ESPEC.parse()?, &encoding_data)?;
+        if self.indexes.lookup(&encoding_file.ekey).is_none() {
+            self.write_data(&encoding_file.ekey, &encoding_file.blte)?;
+        }
+
+        Ok(EncodedPair {
+            decoded: HashSize {
+                hash: encoding_file.ckey,
+                size: encoding_file.decoded_size as usize,
+            },
+            encoded: Some(HashSize {
+                hash: encoding_file.ekey,
+                size: encoding_file.blte.len(),
+            }),
+        })
+    }
+
+    pub fn insert_in_index(
+        &mut self,
+        k: &EncodingKey,
+        entry: idx::Entry,
+    ) -> Result<(), anyhow::Error> {
+        // TODO: Should we error on duplicate entry?
+        let (idx, _) = self.indexes.insert(k, entry)?;
+        self.index_changed[idx] = true;
+        Ok(())
+    }
+}
+
+/// A CASC built from the given files in a temporary directory, which is removed again on drop
+#[cfg(test)]
+pub(crate) struct TempCasc {
+    pub dir: PathBuf,
+    pub build_config_text: String,
+}
+
+#[cfg(test)]
+impl TempCasc {
+    /// `name` has to be unique among tests, as they run in parallel
+    pub fn new(name: &str, files: &[(String, Vec<u8>)]) -> TempCasc {
+        let dir = std::env::temp_dir().join(format!("steed-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let build_config_text = build_casc_from_files(&dir, files).unwrap();
+        TempCasc {
+            dir,
+            build_config_text,
+        }
+    }
+
+    pub fn build_config(&self) -> crate::tact::config::BuildConfig<'_> {
+        crate::tact::config::parse_build_config(&self.build_config_text).unwrap()
+    }
+
+    pub fn data_path(&self) -> PathBuf {
+        self.dir.join("Data/data")
+    }
+
+    pub fn open(&self) -> super::CASC {
+        super::CASC::new(self.dir.to_str().unwrap(), &self.build_config()).unwrap()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempCasc {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tact::{config::parse_build_config, root::parse_root};
+
+    #[test]
+    fn test_build_casc_round_trip() {
+        let files = vec![
+            (String::from("interface/a.txt"), b"hello world".to_vec()),
+            (
+                String::from("interface/b.bin"),
+                (0..=255u8).cycle().take(10_000).collect(),
+            ),
+            (String::from("world/c.txt"), b"hello world".to_vec()),
+        ];
+        let temp = TempCasc::new("build-casc", &files);
+        let build_config = temp.build_config();
+
+        let casc = temp.open();
+        assert_eq!(
+            Some(ESPEC.to_string()),
+            casc.encoding.own_espec.as_ref().map(ESpec::to_string)
+        );
+        let root = parse_root(&casc.read_by_ckey(&build_config.root).unwrap()).unwrap();
+
+        for (name, data) in &files {
+            let record = root
+                .lookup_by_name_and_flags(name, ContentFlags::empty(), LocaleFlags::EN_US)
+                .unwrap();
+            assert_eq!(&casc.read_by_ckey(&record.content_key).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_replace_file() {
+        let files = vec![
+            (String::from("a.txt"), b"original".to_vec()),
+            (String::from("b.txt"), b"untouched".to_vec()),
+        ];
+        let temp = TempCasc::new("replace", &files);
+        let build_config = temp.build_config();
+        let casc = temp.open();
+
+        let ckey = ContentKey::from_data(b"original");
+        assert_eq!(b"original".as_slice(), casc.read_by_ckey(&ckey).unwrap());
+
+        let tact_keys = TactKeys::default();
+        let new_data: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+        let espec: ESpec = "b:{4K*=z}".parse().unwrap();
+
+        let mut builder = CASCBuilder::load(&temp.dir).unwrap();
+        let used_before = builder.shmem.total_unused_bytes();
+        let encoding_pair = builder
+            .replace_file(&tact_keys, &casc.encoding, &ckey, &new_data, &espec)
+            .unwrap();
+        builder.write().unwrap();
+        assert!(builder.shmem.total_unused_bytes() < used_before);
+        drop(casc);
+
+        let encoded = encoding_pair.encoded.unwrap();
+        let build_config_text = format!(
+            "root = {:?}\nencoding = {:?} {:?}\nencoding-size = {} {}\n",
+            build_config.root,
+            encoding_pair.decoded.hash,
+            encoded.hash,
+            encoding_pair.decoded.size,
+            encoded.size
+        );
+        let build_config = parse_build_config(&build_config_text).unwrap();
+        let casc = super::super::CASC::new(temp.dir.to_str().unwrap(), &build_config).unwrap();
+
+        assert_eq!(new_data, casc.read_by_ckey(&ckey).unwrap());
+        assert_eq!(
+            espec.to_string(),
+            casc.encoding.espec_string_for_ckey(&ckey).unwrap()
+        );
+        assert_eq!(
+            b"untouched".as_slice(),
+            casc.read_by_ckey(&ContentKey::from_data(b"untouched"))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_cached_read_size() {
+        let temp = TempCasc::new("cached-read", &[(String::from("a.txt"), b"a".to_vec())]);
+        let builder = CASCBuilder::load(&temp.dir).unwrap();
+        let path = temp.dir.join("Data/indices/cached.index");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let offline = || Err(anyhow::anyhow!("offline"));
+
+        // A truncated cached copy isn't trusted, so it has to be downloaded again
+        std::fs::write(&path, [0u8; 5]).unwrap();
+        let err = builder.try_read(&path, 10, offline, None).unwrap_err();
+        assert_eq!("offline", err.to_string());
+
+        std::fs::write(&path, [0u8; 10]).unwrap();
+        assert_eq!(
+            10,
+            builder.try_read(&path, 10, offline, None).unwrap().len()
+        );
+        assert_eq!(10, builder.try_read(&path, 0, offline, None).unwrap().len());
+    }
+}
@@ -15,9 +15,90 @@ use super::NUM_INDEXES;
 #[derive(Debug)]
 pub struct Index {
     pub index: u8,
+    /// Field widths the index was read with. Indexes are always written with the default layout
+    pub layout: IndexLayout,
     pub entries: BTreeMap<[u8; 9], Entry>,
 }
 
+/// Entry field widths declared in an index header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexLayout {
+    pub size_bytes: u8,
+    pub offset_bytes: u8,
+    pub key_bytes: u8,
+    /// Number of low bits of the packed offset holding the offset within the data file, the rest is the archive index
+    pub offset_bits: u8,
+    pub archive_total_size_maximum: u64,
+}
+
+impl Default for IndexLayout {
+    fn default() -> Self {
+        IndexLayout {
+            size_bytes: 4,
+            offset_bytes: 5,
+            key_bytes: 9,
+            offset_bits: 30,
+            archive_total_size_maximum: 0x4000000000,
+        }
+    }
+}
+
+impl IndexLayout {
+    fn from_header(header: &repr::Header) -> Result<IndexLayout, anyhow::Error> {
+        let layout = IndexLayout {
+            size_bytes: header.entry_size_bytes,
+            offset_bytes: header.entry_offset_bytes,
+            key_bytes: header.entry_key_bytes,
+            offset_bits: header.archive_file_header_bytes,
+            archive_total_size_maximum: header.archive_total_size_maximum,
+        };
+
+        if !(1..=4).contains(&layout.size_bytes) {
+            anyhow::bail!("unsupported index entry_size_bytes: {}", layout.size_bytes);
+        }
+        if !(1..=8).contains(&layout.offset_bytes) {
+            anyhow::bail!(
+                "unsupported index entry_offset_bytes: {}",
+                layout.offset_bytes
+            );
+        }
+        if layout.key_bytes != 9 {
+            anyhow::bail!("unsupported index entry_key_bytes: {}", layout.key_bytes);
+        }
+        // Offset has to fit in Entry::offset, archive index in Entry::archive_index
+        let offset_bits = layout.offset_bits as u32;
+        let archive_bits = (layout.offset_bytes as u32 * 8).saturating_sub(offset_bits);
+        if offset_bits == 0 || offset_bits > 32 || archive_bits > 16 {
+            anyhow::bail!(
+                "unsupported index archive_file_header_bytes: {} with entry_offset_bytes: {}",
+                layout.offset_bits,
+                layout.offset_bytes
+            );
+        }
+
+        Ok(layout)
+    }
+
+    pub fn entry_bytes(&self) -> usize {
+        (self.size_bytes + self.offset_bytes + self.key_bytes) as usize
+    }
+
+    fn parse_entry(&self, data: &[u8]) -> ([u8; 9], Entry) {
+        let (key, rest) = data.split_at(self.key_bytes as usize);
+        let (offset, size) = rest.split_at(self.offset_bytes as usize);
+
+        let index_offset = BE::read_uint(offset, offset.len());
+        let offset_mask = (1u64 << self.offset_bits) - 1;
+        let entry = Entry {
+            archive_index: (index_offset >> self.offset_bits) as u16,
+            offset: (index_offset & offset_mask) as u32,
+            size: LE::read_uint(size, size.len()) as u32,
+        };
+
+        (key.try_into().unwrap(), entry)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Entry {
     pub archive_index: u16,
@@ -25,45 +106,85 @@ pub struct Entry {
     pub size: u32,
 }
 
+impl Entry {
+    /// Checks the entry fits in the 30 bit offset + 10 bit archive index packing used by indexes
+    pub fn validate(&self, key: &[u8]) -> Result<(), anyhow::Error> {
+        if self.offset > 0x3fffffff {
+            anyhow::bail!(
+                "index entry offset out of range - key: {}, offset: {:#x}, archive: {}",
+                hex::encode(key),
+                self.offset,
+                self.archive_index
+            );
+        }
+        if self.archive_index > 0x3ff {
+            anyhow::bail!(
+                "index entry archive index out of range - key: {}, offset: {:#x}, archive: {}",
+                hex::encode(key),
+                self.offset,
+                self.archive_index
+            );
+        }
+        Ok(())
+    }
+}
+
 impl Index {
     pub fn new(index: u8) -> Index {
         Index {
             index,
+            layout: IndexLayout::default(),
             entries: BTreeMap::new(),
         }
     }
 
     pub fn parse(content: &[u8], index: u8) -> Result<Index, anyhow::Error> {
-        let repr::Index { header, entries } = repr::Index::read(&mut Cursor::new(content))?;
-
-        let (pc, _pb) = hashlittle2(&content[8..][..header.header_hash_size as usize], 0, 0);
-        assert_eq!(pc, header.header_hash, "index header hash did not match");
-        assert_eq!(7, header.unk0);
-        assert_eq!(index, header.bucket_index);
-        assert_eq!(0, header.unk1);
-        assert_eq!(4, header.entry_size_bytes);
-        assert_eq!(5, header.entry_offset_bytes);
-        assert_eq!(9, header.entry_key_bytes);
-        assert_eq!(30, header.archive_file_header_bytes);
-        assert_eq!(0x4000000000, header.archive_total_size_maximum);
+        const ENTRIES_START: usize = 40;
 
-        let mut entry_map = BTreeMap::new();
+        let header = repr::Header::read(&mut Cursor::new(content))?;
+        if header.unk0 != 7 {
+            anyhow::bail!("unsupported index version: {}", header.unk0);
+        }
+        if header.bucket_index != index {
+            anyhow::bail!(
+                "index bucket_index mismatch - expected {:02x}, got {:02x}",
+                index,
+                header.bucket_index
+            );
+        }
+        if header.unk1 != 0 {
+            anyhow::bail!("unsupported index extra bytes: {}", header.unk1);
+        }
+        let layout = IndexLayout::from_header(&header)?;
+
+        let entries_size = header.entries_size as usize;
+        if !entries_size.is_multiple_of(layout.entry_bytes()) {
+            anyhow::bail!(
+                "index entries_size {} is not a multiple of the entry size {}",
+                entries_size,
+                layout.entry_bytes()
+            );
+        }
+        let Some(entries) = content.get(ENTRIES_START..ENTRIES_START + entries_size) else {
+            anyhow::bail!(
+                "index is truncated - entries_size is {} but only {} bytes follow the header",
+                entries_size,
+                content.len().saturating_sub(ENTRIES_START)
+            );
+        };
 
-        // Entries hash is calculated by feeding along pc and pb for each 18 byte entry
-        let (mut pc, mut pb) = (0, 0);
-        for (idx, entry) in entries.into_iter().enumerate() {
-            (pc, pb) = hashlittle2(&content[40 + 18 * idx..][..18], pc, pb);
+        let (header_hash, entries_hash) = compute_index_hashes(content)?;
+        anyhow::ensure!(
+            header_hash == header.header_hash,
+            "index header hash did not match - expected {:08x}, got {:08x}",
+            header.header_hash,
+            header_hash
+        );
 
-            let index_offset = BE::read_uint(&entry.offset, 5);
-            let archive_index = ((index_offset >> 30) & 0x3ff) as u16;
-            let offset = (index_offset & 0x3fffffff) as u32;
+        let mut entry_map = BTreeMap::new();
 
-            let key = entry.key;
-            let entry = Entry {
-                archive_index,
-                offset,
-                size: entry.size,
-            };
+        for entry in entries.chunks_exact(layout.entry_bytes()) {
+            let (key, entry) = layout.parse_entry(entry);
 
             // BTreeMap::from_iter is seemingly faster on sorted input, but we lose the ability to check for duplicate keys
             let exists = entry_map.insert(key, entry.clone());
@@ -74,10 +195,16 @@ impl Index {
                 );
             }
         }
-        assert_eq!(pc, header.entries_hash);
+        anyhow::ensure!(
+            entries_hash == header.entries_hash,
+            "index entries hash did not match - expected {:08x}, got {:08x}",
+            header.entries_hash,
+            entries_hash
+        );
 
         Ok(Index {
             index,
+            layout,
             entries: entry_map,
         })
     }
@@ -107,20 +234,19 @@ impl Index {
             .entries
             .iter()
             .map(|(key, entry)| {
-                assert!(entry.offset <= 0x3fffffff);
-                assert!(entry.archive_index <= 0x3ff);
+                entry.validate(key)?;
                 let index_offset = entry.offset as u64 | (entry.archive_index as u64) << 30;
 
                 let mut offset = [0u8; 5];
                 BE::write_uint(&mut offset, index_offset, 5);
 
-                repr::Entry {
+                Ok(repr::Entry {
                     key: *key,
                     offset,
                     size: entry.size,
-                }
+                })
             })
-            .collect();
+            .collect::<Result<_, anyhow::Error>>()?;
 
         let index = repr::Index { header, entries };
 
@@ -130,26 +256,75 @@ impl Index {
         let end = cursor.position() as usize;
         let buf = cursor.into_inner();
 
-        // Patch up header hash
+        // Patch up hashes
         let data = &mut buf[start..end];
-        let (header_hash, _) = hashlittle2(&data[8..][..HEADER_HASH_SIZE], 0, 0);
+        let (header_hash, entries_hash) = compute_index_hashes(data)?;
         LE::write_u32(&mut data[4..8], header_hash);
-
-        // Patch up entries hash
-        let (mut pc, mut pb) = (0, 0);
-        for idx in 0..index.entries.len() {
-            (pc, pb) = hashlittle2(&data[40 + 18 * idx..][..18], pc, pb);
-        }
-        LE::write_u32(&mut data[36..40], pc);
+        LE::write_u32(&mut data[36..40], entries_hash);
 
         Ok(())
     }
 }
 
+/// Computes the header hash and entries hash of a serialized index, as stored at offsets 4 and 36.
+/// The stored hashes themselves aren't covered, so this works on unpatched output too.
+pub fn compute_index_hashes(serialized: &[u8]) -> Result<(u32, u32), anyhow::Error> {
+    const ENTRIES_START: usize = 40;
+
+    anyhow::ensure!(
+        serialized.len() >= ENTRIES_START,
+        "index is truncated - {} bytes is too short for the header",
+        serialized.len()
+    );
+
+    let header_hash_size = LE::read_u32(&serialized[0..4]) as usize;
+    let header_hashed = serialized
+        .get(8..)
+        .and_then(|rest| rest.get(..header_hash_size))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "index header_hash_size {} is out of bounds of the {} byte index",
+                header_hash_size,
+                serialized.len()
+            )
+        })?;
+    let (header_hash, _) = hashlittle2(header_hashed, 0, 0);
+
+    // Entries hash is calculated by feeding along pc and pb for each entry, sized from the header's field widths
+    let entry_size = serialized[12..15]
+        .iter()
+        .map(|&b| b as usize)
+        .sum::<usize>();
+    anyhow::ensure!(entry_size > 0, "index entry size is 0");
+    let entries_size = LE::read_u32(&serialized[32..36]) as usize;
+    let entries = serialized
+        .get(ENTRIES_START..ENTRIES_START + entries_size)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "index is truncated - entries_size is {} but only {} bytes follow the header",
+                entries_size,
+                serialized.len() - ENTRIES_START
+            )
+        })?;
+    let (mut pc, mut pb) = (0, 0);
+    for entry in entries.chunks_exact(entry_size) {
+        (pc, pb) = hashlittle2(entry, pc, pb);
+    }
+
+    Ok((header_hash, pc))
+}
+
 pub struct Indexes {
     indexes: [Index; NUM_INDEXES],
 }
 
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IndexStats {
+    pub entries: usize,
+    pub total_size: u64,
+    pub layout: IndexLayout,
+}
+
 impl Indexes {
     pub fn new(indexes: Vec<Index>) -> Indexes {
         assert_eq!(NUM_INDEXES, indexes.len());
@@ -176,10 +351,21 @@ impl Indexes {
         index.entries.get(&k.short())
     }
 
-    pub fn insert(&mut self, k: &EncodingKey, entry: Entry) -> (usize, Option<Entry>) {
+    pub fn insert(
+        &mut self,
+        k: &EncodingKey,
+        entry: Entry,
+    ) -> Result<(usize, Option<Entry>), anyhow::Error> {
+        entry.validate(k.as_slice())?;
         let bucket = Self::get_bucket(k) as usize;
         let index = &mut self.indexes[bucket];
-        (bucket, index.entries.insert(k.short(), entry))
+        Ok((bucket, index.entries.insert(k.short(), entry)))
+    }
+
+    pub fn remove(&mut self, k: &EncodingKey) -> (usize, Option<Entry>) {
+        let bucket = Self::get_bucket(k) as usize;
+        let index = &mut self.indexes[bucket];
+        (bucket, index.entries.remove(&k.short()))
     }
 
     pub fn lookup_cross_ref(&self, k: &EncodingKey) -> Option<&Entry> {
@@ -192,6 +378,21 @@ impl Indexes {
         self.indexes.iter().flat_map(|f| f.entries.iter())
     }
 
+    pub fn stats(&self) -> [IndexStats; NUM_INDEXES] {
+        std::array::from_fn(|bucket| {
+            let index = &self.indexes[bucket];
+            IndexStats {
+                entries: index.entries.len(),
+                total_size: index.entries.values().map(|e| e.size as u64).sum(),
+                layout: index.layout,
+            }
+        })
+    }
+
+    pub fn total_entries(&self) -> usize {
+        self.indexes.iter().map(|index| index.entries.len()).sum()
+    }
+
     pub fn write(&self, versions: [u32; 16], path: &Path) -> Result<(), anyhow::Error> {
         let mut buf = Vec::with_capacity(0x120000);
         for (index, version) in self.indexes.iter().zip(versions) {
@@ -227,14 +428,139 @@ impl Default for Indexes {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_index_hashes() {
+        let mut index = Index::new(3);
+        for i in 0..5u8 {
+            index.entries.insert(
+                [i; 9],
+                Entry {
+                    archive_index: i as u16,
+                    offset: 0x1000 * i as u32,
+                    size: 100 + i as u32,
+                },
+            );
+        }
+
+        let mut buf = vec![];
+        index.write(&mut buf).unwrap();
+
+        let (header_hash, entries_hash) = compute_index_hashes(&buf).unwrap();
+        assert_eq!(LE::read_u32(&buf[4..8]), header_hash);
+        assert_eq!(LE::read_u32(&buf[36..40]), entries_hash);
+
+        // Parsing verifies both hashes
+        let parsed = Index::parse(&buf, 3).unwrap();
+        assert_eq!(5, parsed.entries.len());
+
+        // Changing an entry only changes the entries hash
+        buf[40 + 18 * 2 + 9] ^= 1;
+        let (new_header_hash, new_entries_hash) = compute_index_hashes(&buf).unwrap();
+        assert_eq!(header_hash, new_header_hash);
+        assert_ne!(entries_hash, new_entries_hash);
+
+        let err = Index::parse(&buf, 3).unwrap_err();
+        assert!(
+            err.to_string().contains("entries hash did not match"),
+            "{}",
+            err
+        );
+        buf[40 + 18 * 2 + 9] ^= 1;
+        buf[20] ^= 1;
+        let err = Index::parse(&buf, 3).unwrap_err();
+        assert!(
+            err.to_string().contains("header hash did not match"),
+            "{}",
+            err
+        );
+        buf[20] ^= 1;
+
+        // Truncated files and out of bounds sizes are errors rather than panics
+        for len in [0, 4, 39, 40, 40 + 18 * 5 - 1] {
+            assert!(compute_index_hashes(&buf[..len]).is_err(), "{} bytes", len);
+            assert!(Index::parse(&buf[..len], 3).is_err(), "{} bytes", len);
+        }
+        let mut bad_hash_size = buf.clone();
+        LE::write_u32(&mut bad_hash_size[0..4], u32::MAX);
+        assert!(compute_index_hashes(&bad_hash_size).is_err());
+    }
+
+    /// Serializes an index with the given header fields, entries given as raw (key, packed offset, size)
+    fn build_index(bucket: u8, fields: [u8; 4], entries: &[([u8; 9], u64, u32)]) -> Vec<u8> {
+        let [size_bytes, offset_bytes, key_bytes, _offset_bits] = fields;
+
+        let mut buf = vec![0u8; 40];
+        LE::write_u32(&mut buf[0..4], 16);
+        LE::write_u16(&mut buf[8..10], 7);
+        buf[10] = bucket;
+        buf[12..16].copy_from_slice(&fields);
+        LE::write_u64(&mut buf[16..24], 0x4000000000);
+        for (key, offset, size) in entries {
+            buf.extend_from_slice(&key[..key_bytes as usize]);
+            buf.extend_from_slice(&offset.to_be_bytes()[8 - offset_bytes as usize..]);
+            buf.extend_from_slice(&size.to_le_bytes()[..size_bytes as usize]);
+        }
+        let entries_size = (buf.len() - 40) as u32;
+        LE::write_u32(&mut buf[32..36], entries_size);
+
+        let (header_hash, entries_hash) = compute_index_hashes(&buf).unwrap();
+        LE::write_u32(&mut buf[4..8], header_hash);
+        LE::write_u32(&mut buf[36..40], entries_hash);
+        buf
+    }
+
+    #[test]
+    fn test_parse_layouts() {
+        let entries = [([1; 9], (3 << 30) | 0x1234, 100), ([2; 9], 0x20, 0x7ff)];
+        let parsed = Index::parse(&build_index(5, [4, 5, 9, 30], &entries), 5).unwrap();
+        assert_eq!(IndexLayout::default(), parsed.layout);
+        let entry = &parsed.entries[&[1; 9]];
+        assert_eq!(
+            (3, 0x1234, 100),
+            (entry.archive_index, entry.offset, entry.size)
+        );
+
+        // Wider offset with 32 bits of data file offset, 2 byte sizes
+        let entries = [([1; 9], (0x401 << 32) | 0xfedcba98, 100)];
+        let parsed = Index::parse(&build_index(5, [2, 6, 9, 32], &entries), 5).unwrap();
+        assert_eq!(
+            (2, 6, 32),
+            (
+                parsed.layout.size_bytes,
+                parsed.layout.offset_bytes,
+                parsed.layout.offset_bits
+            )
+        );
+        let entry = &parsed.entries[&[1; 9]];
+        assert_eq!(
+            (0x401, 0xfedcba98, 100),
+            (entry.archive_index, entry.offset, entry.size)
+        );
+
+        let err = Index::parse(&build_index(5, [4, 5, 16, 30], &[]), 5).unwrap_err();
+        assert!(err.to_string().contains("entry_key_bytes"), "{}", err);
+        let err = Index::parse(&build_index(5, [4, 8, 9, 30], &[]), 5).unwrap_err();
+        assert!(
+            err.to_string().contains("archive_file_header_bytes"),
+            "{}",
+            err
+        );
+        let err = Index::parse(&build_index(5, [4, 5, 9, 30], &[]), 6).unwrap_err();
+        assert!(err.to_string().contains("bucket_index"), "{}", err);
+    }
+}
+
 mod repr {
     use binrw::{BinRead, BinWrite};
 
-    #[derive(BinRead, BinWrite, Debug)]
-    #[brw(little)]
+    #[derive(BinWrite, Debug)]
+    #[bw(little)]
     pub struct Index {
         pub header: Header,
-        #[br(count = header.entries_size / 18)]
         #[bw(pad_size_to = 0x120000 - 40)]
         pub entries: Vec<Entry>,
     }
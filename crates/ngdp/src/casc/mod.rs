@@ -1,16 +1,18 @@
 use crate::{
-    blte::{decode_blte, encode_blte, espec::ESpec},
+    blte::{decode_available_chunks, decode_blte, encode_blte, espec::ESpec, prefix_len},
     casc::shmem::Shmem,
+    error::NgdpError,
     tact::{
         config::BuildConfig,
         encoding::{parse_encoding, Encoding},
         keys::TactKeys,
         ContentKey, EncodingKey,
     },
+    util::hexdump_string,
 };
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use binrw::{BinRead, BinWrite};
-use byteorder::{ByteOrder, LE};
+use byteorder::{ByteOrder, BE, LE};
 use lookup3::hashlittle;
 use std::{
     collections::HashSet,
@@ -21,6 +23,8 @@ use std::{
 
 use self::idx::Indexes;
 
+pub mod build_info;
+pub mod builder;
 pub mod idx;
 pub mod shmem;
 
@@ -57,9 +61,10 @@ impl FileHeader {
         let mut buf = Vec::with_capacity(Self::SIZE);
         self.write(&mut Cursor::new(&mut buf))?;
 
-        // Patch up checksums
-        let (checksum_a, checksum_b) = Self::checksums(&buf, archive_index, offset);
+        // Patch up checksums. Checksum b covers checksum a, so it has to be written first
+        let (checksum_a, _) = Self::checksums(&buf, archive_index, offset);
         LE::write_u32(&mut buf[Self::CHECKSUM_A_OFF..], checksum_a);
+        let (_, checksum_b) = Self::checksums(&buf, archive_index, offset);
         LE::write_u32(&mut buf[Self::CHECKSUM_B_OFF..], checksum_b);
 
         w.write_all(&buf)?;
@@ -74,7 +79,7 @@ impl FileHeader {
         // For some ungodly reason the top two bits of the offset must be set to the bottom two bits of the archive index
         let offset = (offset & 0x3fffffff) | (archive_index as u32 & 3) << 30;
 
-        let encoded_offset = offset + Self::SIZE as u32;
+        let encoded_offset = offset.wrapping_add(Self::SIZE as u32);
         let encoded_offset = TABLE_16C57A8[(encoded_offset & 0x0f) as usize] ^ encoded_offset;
         let encoded_offset = encoded_offset.to_le_bytes();
 
@@ -93,17 +98,145 @@ impl FileHeader {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderProblem {
+    /// The header runs past the end of the data file
+    Truncated,
+    /// The header's key doesn't match the index key
+    KeyMismatch,
+    /// The header's size doesn't match the size in the index
+    SizeMismatch {
+        header: u32,
+    },
+    ChecksumA {
+        expected: u32,
+        actual: u32,
+    },
+    ChecksumB {
+        expected: u32,
+        actual: u32,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct HeaderError {
+    pub key: [u8; 9],
+    pub entry: idx::Entry,
+    pub problem: HeaderProblem,
+}
+
+/// Checks the header of every indexed block in a `data.NNN` file without decoding any BLTE
+pub fn verify_archive_headers(
+    path: &Path,
+    indexes: &Indexes,
+) -> Result<Vec<HeaderError>, anyhow::Error> {
+    let archive_index: u16 = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(|ext| ext.parse().ok())
+        .ok_or_else(|| anyhow!("{:?} isn't a data.NNN file", path))?;
+
+    let mut file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+
+    let mut errors = vec![];
+    for (key, entry) in indexes.iter_all_entries() {
+        if entry.archive_index != archive_index {
+            continue;
+        }
+
+        let mut error = |problem| {
+            errors.push(HeaderError {
+                key: *key,
+                entry: entry.clone(),
+                problem,
+            })
+        };
+
+        if entry.offset as u64 + FileHeader::SIZE as u64 > file_len {
+            error(HeaderProblem::Truncated);
+            continue;
+        }
+
+        let mut buf = [0u8; FileHeader::SIZE];
+        file.seek(SeekFrom::Start(entry.offset as u64))?;
+        file.read_exact(&mut buf)?;
+        let header = FileHeader::read(&mut Cursor::new(&buf))?;
+
+        if EncodingKey::from_rev(header.hash).short() != *key {
+            error(HeaderProblem::KeyMismatch);
+        }
+        if header.size != entry.size {
+            error(HeaderProblem::SizeMismatch {
+                header: header.size,
+            });
+        }
+
+        let (checksum_a, checksum_b) = FileHeader::checksums(&buf, archive_index, entry.offset);
+        if checksum_a != header.checksum_a {
+            error(HeaderProblem::ChecksumA {
+                expected: checksum_a,
+                actual: header.checksum_a,
+            });
+        }
+        if checksum_b != header.checksum_b {
+            error(HeaderProblem::ChecksumB {
+                expected: checksum_b,
+                actual: header.checksum_b,
+            });
+        }
+    }
+
+    Ok(errors)
+}
+
+/// Opens the data file an entry is in and seeks to it, checking at least `needed` bytes of the
+/// entry are actually in the file
+fn open_data_file(
+    data_path: &Path,
+    entry: &idx::Entry,
+    needed: u32,
+) -> Result<File, anyhow::Error> {
+    let data_file = data_path.join(format!("data.{:03}", entry.archive_index));
+    let mut file =
+        File::open(&data_file).with_context(|| format!("opening {}", data_file.display()))?;
+
+    let file_len = file.metadata()?.len();
+    if entry.offset as u64 + needed as u64 > file_len {
+        anyhow::bail!(
+            "{} is truncated - entry at offset {} with size {} runs past the end of the file ({} bytes)",
+            data_file.display(),
+            entry.offset,
+            entry.size,
+            file_len
+        );
+    }
+
+    file.seek(SeekFrom::Start(entry.offset as u64))?;
+    Ok(file)
+}
+
 fn read_file(
     data_path: &Path,
     entry: &idx::Entry,
     tact_keys: &TactKeys,
     espec: Option<&ESpec>,
 ) -> Result<Vec<u8>, anyhow::Error> {
-    let data_file = data_path.join(format!("data.{:03}", entry.archive_index));
-    let mut buf = vec![0; entry.size as usize];
+    let (key, data) = read_block(data_path, entry)?;
+    let res = decode_blte(tact_keys, &data)?;
+    if let Some(espec) = espec {
+        check_recode(tact_keys, espec, &key, &data, &res)?;
+    }
+    Ok(res)
+}
 
-    let mut file = File::open(data_file)?;
-    file.seek(SeekFrom::Start(entry.offset as u64))?;
+/// Reads the BLTE data of an entry, checking its header
+fn read_block(
+    data_path: &Path,
+    entry: &idx::Entry,
+) -> Result<(EncodingKey, Vec<u8>), anyhow::Error> {
+    let mut file = open_data_file(data_path, entry, entry.size)?;
+    let mut buf = vec![0; entry.size as usize];
     file.read_exact(&mut buf)?;
 
     assert!(
@@ -115,7 +248,7 @@ fn read_file(
     );
 
     let header = FileHeader::read(&mut Cursor::new(&buf))?;
-    let _key = EncodingKey::from_rev(header.hash);
+    let key = EncodingKey::from_rev(header.hash);
 
     let (checksum_a, checksum_b) = FileHeader::checksums(&buf, entry.archive_index, entry.offset);
     assert_eq!(checksum_a, header.checksum_a);
@@ -131,108 +264,117 @@ fn read_file(
         ));
     }
 
-    let data = &buf[FileHeader::SIZE..header.size as usize];
-    assert!(!data.is_empty());
-    let res = decode_blte(tact_keys, data)?;
-    if let Some(espec) = espec {
-        match encode_blte(tact_keys, espec, &res) {
-            Ok(recoded) => {
-                if recoded != data {
-                    dbg!(&_key);
-                    dbg!(espec);
-                    eprintln!(
-                        "recoded: {}",
-                        hex::encode(&recoded[..recoded.len().min(80)])
-                    );
-                    eprintln!("   data: {}", hex::encode(&data[..data.len().min(80)]));
-                    dbg_bin_compare(&recoded, data).unwrap();
-                    eprintln!("    src: {}", hex::encode(&res[..res.len().min(80)]));
-                    panic!();
-                }
-            }
-            Err(e) => match e {
-                crate::blte::EncodeError::MissingEncryptionKey(key) => {
-                    eprintln!("BLTE encode failed - missing encryption key: {:02x?}", key);
-                }
-                _ => {
-                    dbg!(espec);
-                    panic!("BLTE encode failed: {}", e);
-                }
-            },
+    buf.truncate(header.size as usize);
+    buf.drain(..FileHeader::SIZE);
+    assert!(!buf.is_empty());
+    Ok((key, buf))
+}
+
+/// Checks that encoding `res` with `espec` gives back `data` exactly
+/// Checks that re-encoding the decoded data with its espec reproduces the stored BLTE.
+/// Files needing an encryption key we don't have can't be re-encoded, and pass.
+fn check_recode(
+    tact_keys: &TactKeys,
+    espec: &ESpec,
+    key: &EncodingKey,
+    data: &[u8],
+    res: &[u8],
+) -> Result<(), anyhow::Error> {
+    let recoded = match encode_blte(tact_keys, espec, res) {
+        Ok(recoded) => recoded,
+        Err(crate::blte::EncodeError::MissingEncryptionKey(_)) => return Ok(()),
+        Err(e) => {
+            return Err(e).with_context(|| format!("re-encoding {:?} with espec {}", key, espec))
         }
+    };
+    if recoded == data {
+        return Ok(());
     }
-    Ok(res)
+
+    let first_diff = recoded
+        .iter()
+        .zip(data)
+        .position(|(a, b)| a != b)
+        .unwrap_or(recoded.len().min(data.len()));
+    let start = first_diff.saturating_sub(16);
+    let window =
+        |d: &[u8]| hexdump_string(&d[start.min(d.len())..(start + 64).min(d.len())], start);
+    anyhow::bail!(
+        "re-encoding {:?} with espec {} doesn't reproduce the stored data \
+         ({} bytes recoded, {} stored, first difference at {:#x})\nrecoded:\n{}stored:\n{}",
+        key,
+        espec,
+        recoded.len(),
+        data.len(),
+        first_diff,
+        window(&recoded),
+        window(data)
+    )
 }
 
-fn dbg_bin_compare(a: &[u8], b: &[u8]) -> Result<(), anyhow::Error> {
-    use std::fmt::Write;
-    eprintln!("a.len() = {}, b.len() = {}", a.len(), b.len());
-
-    let max_len = a.len().max(b.len());
-
-    let mut out_a = String::new();
-    let mut out_b = String::new();
-
-    let mut stride = 0;
-    for i in 0..max_len {
-        match (a.get(i), b.get(i)) {
-            (Some(va), Some(vb)) => {
-                if va == vb {
-                    stride += 1;
-                } else {
-                    if stride > 0 {
-                        write!(out_a, "[{} eq]", stride)?;
-                        write!(out_b, "[{} eq]", stride)?;
-                        stride = 0;
-                    }
-
-                    write!(out_a, "{:02x}", va)?;
-                    write!(out_b, "{:02x}", vb)?;
-                }
-            }
-            (Some(va), None) => {
-                if stride > 0 {
-                    write!(out_a, "[{} eq]", stride)?;
-                    write!(out_b, "[{} eq]", stride)?;
-                    stride = 0;
-                }
-                write!(out_a, "{:02x}", va)?
-            }
-            (None, Some(vb)) => {
-                if stride > 0 {
-                    write!(out_a, "[{} eq]", stride)?;
-                    write!(out_b, "[{} eq]", stride)?;
-                    stride = 0;
-                }
-                write!(out_b, "{:02x}", vb)?
-            }
-            (None, None) => panic!("unexpected state"),
-        }
+/// Reads just enough of a file to decode at least its first `max_bytes`, see `CASC::read_prefix_by_ekey`
+fn read_file_prefix(
+    data_path: &Path,
+    entry: &idx::Entry,
+    tact_keys: &TactKeys,
+    max_bytes: usize,
+) -> Result<Vec<u8>, anyhow::Error> {
+    // File header, followed by the BLTE magic and header size
+    let preamble_size = FileHeader::SIZE + 8;
+    if (entry.size as usize) < preamble_size {
+        anyhow::bail!(
+            "data block too small (expected at least {}, got {})",
+            preamble_size,
+            entry.size
+        );
     }
 
-    if stride > 0 {
-        write!(out_a, "[{} eq]", stride)?;
-        write!(out_b, "[{} eq]", stride)?;
+    let mut file = open_data_file(data_path, entry, preamble_size as u32)?;
+
+    let mut buf = vec![0; preamble_size];
+    file.read_exact(&mut buf)?;
+
+    let header = FileHeader::read(&mut Cursor::new(&buf))?;
+    let (checksum_a, checksum_b) = FileHeader::checksums(&buf, entry.archive_index, entry.offset);
+    if (checksum_a, checksum_b) != (header.checksum_a, header.checksum_b) {
+        anyhow::bail!("data block header checksum mismatch");
     }
+    let total_size = (header.size as usize).min(entry.size as usize);
+
+    let blte_header_size = BE::read_u32(&buf[FileHeader::SIZE + 4..]) as usize;
+    anyhow::ensure!(
+        blte_header_size == 0
+            || (blte_header_size >= 8 && FileHeader::SIZE + blte_header_size <= total_size),
+        "blte header size {} doesn't fit in a data block of {} bytes",
+        blte_header_size,
+        total_size
+    );
+    let wanted = if blte_header_size == 0 {
+        total_size
+    } else {
+        buf.resize(FileHeader::SIZE + blte_header_size, 0);
+        file.read_exact(&mut buf[preamble_size..])?;
+        match prefix_len(&buf[FileHeader::SIZE..], max_bytes)? {
+            Some(len) => (FileHeader::SIZE + len).min(total_size),
+            None => total_size,
+        }
+    };
 
-    eprintln!("{}", out_a);
-    eprintln!("{}", out_b);
+    let already_read = buf.len();
+    buf.resize(wanted.max(already_read), 0);
+    file.read_exact(&mut buf[already_read..])?;
 
-    Ok(())
+    decode_available_chunks(tact_keys, &buf[FileHeader::SIZE..], max_bytes)
 }
 
-pub struct CASC {
+/// A `Data/data` directory, with its data files and the indexes into them
+pub struct DataRoot {
     pub data_path: PathBuf,
     pub indexes: Indexes,
-    pub encoding: Encoding,
-    pub tact_keys: TactKeys,
 }
 
-impl CASC {
-    pub fn new(root_path: &str, build_config: &BuildConfig) -> Result<CASC, anyhow::Error> {
-        let root_path = Path::new(root_path);
-        let data_path = root_path.join("Data/data");
-
+impl DataRoot {
+    pub fn open(data_path: &Path) -> Result<DataRoot, anyhow::Error> {
         let num_indexes = data_path
             .read_dir()?
             .filter_map(Result::ok)
@@ -242,55 +384,401 @@ impl CASC {
             .collect::<HashSet<String>>()
             .len();
 
-        assert_eq!(
-            num_indexes, NUM_INDEXES,
-            "num_indexes assumed to always be {}, suddenly it's not!",
-            NUM_INDEXES
-        );
+        if num_indexes != NUM_INDEXES {
+            anyhow::bail!(
+                "expected {} indexes in {}, found {}",
+                NUM_INDEXES,
+                data_path.display(),
+                num_indexes
+            );
+        }
 
         let shmem_data = std::fs::read(data_path.join("shmem"))?;
         let shmem = Shmem::parse(&shmem_data)?;
         dbg!(&shmem);
 
-        let indexes = Indexes::read(&data_path, &shmem)?;
+        let indexes = Indexes::read(data_path, &shmem)?;
+
+        Ok(DataRoot {
+            data_path: data_path.to_path_buf(),
+            indexes,
+        })
+    }
+}
+
+fn lookup_entry<'a>(
+    data_roots: &'a [DataRoot],
+    ekey: &EncodingKey,
+) -> Option<(&'a Path, &'a idx::Entry)> {
+    data_roots.iter().find_map(|root| {
+        root.indexes
+            .lookup(ekey)
+            .map(|entry| (root.data_path.as_path(), entry))
+    })
+}
+
+/// Finds the stored encoding file with the given content key by checking every block that decodes
+/// to the encoding magic, returning its key, BLTE data and decoded contents. Slow, as it has to
+/// read the start of every stored file.
+fn find_encoding_by_ckey(
+    data_roots: &[DataRoot],
+    tact_keys: &TactKeys,
+    ckey: &ContentKey,
+) -> Option<(EncodingKey, Vec<u8>, Vec<u8>)> {
+    data_roots.iter().find_map(|root| {
+        root.indexes.iter_all_entries().find_map(|(_, entry)| {
+            let prefix = read_file_prefix(&root.data_path, entry, tact_keys, 2).ok()?;
+            if !prefix.starts_with(b"EN") {
+                return None;
+            }
+            let (key, data) = read_block(&root.data_path, entry).ok()?;
+            let file = decode_blte(tact_keys, &data).ok()?;
+            (ContentKey::from_data(&file) == *ckey).then_some((key, data, file))
+        })
+    })
+}
+
+pub struct CASC {
+    /// Searched in order, so earlier roots take priority over later ones
+    pub data_roots: Vec<DataRoot>,
+    pub encoding: Encoding,
+    pub tact_keys: TactKeys,
+    /// Problems found while opening that didn't stop it, for the caller to report
+    pub warnings: Vec<String>,
+}
+
+impl CASC {
+    pub fn new(root_path: &str, build_config: &BuildConfig) -> Result<CASC, anyhow::Error> {
+        let root_path = Path::new(root_path);
+        CASC::with_data_paths(&[root_path.join("Data/data")], build_config)
+    }
+
+    /// Opens an install split across several data directories, e.g. base data and an overlay.
+    /// Files are read from the first directory that has them.
+    pub fn with_data_paths(
+        data_paths: &[PathBuf],
+        build_config: &BuildConfig,
+    ) -> Result<CASC, anyhow::Error> {
+        let data_roots = data_paths
+            .iter()
+            .map(|path| DataRoot::open(path))
+            .collect::<Result<Vec<_>, _>>()?;
 
         let tact_keys = TactKeys::default();
+        let mut warnings = vec![];
 
         let encoding = {
-            let decoded_encoding_hashsize = build_config
+            let pair = build_config
                 .encoding
                 .as_ref()
-                .ok_or_else(|| anyhow!("build config had no encoding field"))?
-                .encoded
-                .as_ref()
-                .expect("encoded hash for encoding file not found, can't progress");
-            let entry = indexes.lookup(&decoded_encoding_hashsize.hash).unwrap();
-            let file = read_file(&data_path, entry, &tact_keys, None)?;
-            parse_encoding(&file)?
+                .ok_or_else(|| anyhow!("build config had no encoding field"))?;
+            let (key, data, file) = match &pair.encoded {
+                Some(hs) => {
+                    let (data_path, entry) =
+                        lookup_entry(&data_roots, &hs.hash).ok_or_else(|| {
+                            anyhow!(
+                                "couldn't find encoding file {:?} in any data directory",
+                                hs.hash
+                            )
+                        })?;
+                    let (key, data) = read_block(data_path, entry)?;
+                    let file = decode_blte(&tact_keys, &data)?;
+                    (key, data, file)
+                }
+                // Local storage is keyed by encoding key, which we'd need the encoding file to
+                // look up, so find it by its content instead
+                None => find_encoding_by_ckey(&data_roots, &tact_keys, &pair.decoded.hash)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "couldn't find encoding file with content key {:?} in any data directory",
+                            pair.decoded.hash
+                        )
+                    })?,
+            };
+            // The encoding file's espec is stored in the file itself, so it can only be
+            // checked after parsing
+            let encoding = parse_encoding(&file, false)?;
+            warnings.extend(encoding.warnings.iter().cloned());
+            // A mismatch here doesn't make the decoded file wrong, so it's not worth failing over
+            if let Some(espec) = &encoding.own_espec {
+                if let Err(e) = check_recode(&tact_keys, espec, &key, &data, &file) {
+                    warnings.push(format!("encoding file: {:#}", e));
+                }
+            }
+            encoding
         };
 
         Ok(CASC {
-            data_path,
-            indexes,
+            data_roots,
             encoding,
             tact_keys,
+            warnings,
         })
     }
 
-    pub fn read_by_ckey(&self, ckey: &ContentKey) -> Result<Vec<u8>, anyhow::Error> {
+    /// Finds which data root has the file, and where in it
+    pub fn lookup_entry(&self, ekey: &EncodingKey) -> Option<(&Path, &idx::Entry)> {
+        lookup_entry(&self.data_roots, ekey)
+    }
+
+    /// Whether the file is stored locally, without reading it
+    pub fn contains_ekey(&self, ekey: &EncodingKey) -> bool {
+        self.lookup_entry(ekey).is_some()
+    }
+
+    /// Whether any encoding of the file is stored locally, without reading it
+    pub fn contains_ckey(&self, ckey: &ContentKey) -> bool {
+        self.encoding
+            .lookup_by_ckey(ckey)
+            .is_some_and(|ce_entry| ce_entry.ekeys.iter().any(|ekey| self.contains_ekey(ekey)))
+    }
+
+    fn lookup_entry_or_err(&self, ekey: &EncodingKey) -> Result<(&Path, &idx::Entry), NgdpError> {
+        self.lookup_entry(ekey)
+            .ok_or_else(|| NgdpError::IndexMiss(ekey.clone()))
+    }
+
+    pub fn read_by_ckey(&self, ckey: &ContentKey) -> Result<Vec<u8>, NgdpError> {
         let ce_entry = self
             .encoding
             .lookup_by_ckey(ckey)
-            .ok_or_else(|| anyhow!("couldn't find encoding for ckey. ckey = {:?}", ckey))?;
+            .ok_or_else(|| NgdpError::KeyNotInEncoding(ckey.clone()))?;
         let ekey = &ce_entry.ekeys[0];
-        let entry = self
-            .indexes
-            .lookup(ekey)
-            .ok_or_else(|| anyhow!("couldn't find entry for ekey. ekey = {:?}", ekey))?;
+        let (data_path, entry) = self.lookup_entry_or_err(ekey)?;
         let espec = self
             .encoding
             .lookup_espec(ekey)
             .ok_or_else(|| anyhow!("couldn't find espec for ekey. ekey = {:?}", ekey))?;
-        read_file(&self.data_path, entry, &self.tact_keys, Some(espec))
+        Ok(read_file(data_path, entry, &self.tact_keys, Some(espec))?)
+    }
+
+    pub fn read_by_ekey(&self, ekey: &EncodingKey) -> Result<Vec<u8>, NgdpError> {
+        let (data_path, entry) = self.lookup_entry_or_err(ekey)?;
+        let espec = self.encoding.lookup_espec(ekey);
+        Ok(read_file(data_path, entry, &self.tact_keys, espec)?)
+    }
+
+    /// Decodes the start of a file, at least `max_bytes` of it unless the file is shorter.
+    /// Only the chunks needed are read from disk, though chunkless files are always read whole.
+    pub fn read_prefix_by_ekey(
+        &self,
+        ekey: &EncodingKey,
+        max_bytes: usize,
+    ) -> Result<Vec<u8>, NgdpError> {
+        let (data_path, entry) = self.lookup_entry_or_err(ekey)?;
+        Ok(read_file_prefix(
+            data_path,
+            entry,
+            &self.tact_keys,
+            max_bytes,
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{casc::builder::TempCasc, tact::config::parse_build_config};
+
+    #[test]
+    fn test_file_header_checksums() {
+        for (archive_index, offset) in [(0, 0), (1, 0x1e), (3, 0x1234567), (0x3ff, 0x3fffffff)] {
+            let header = FileHeader {
+                hash: [0x5a; 16],
+                size: 1234,
+                _unk: [0, 0],
+                checksum_a: 0,
+                checksum_b: 0,
+            };
+            let mut buf = vec![];
+            header.write_to(archive_index, offset, &mut buf).unwrap();
+
+            // Checksum b covers the bytes of checksum a, so both have to match as written
+            let written = FileHeader::read(&mut Cursor::new(&buf)).unwrap();
+            assert_eq!(
+                (written.checksum_a, written.checksum_b),
+                FileHeader::checksums(&buf, archive_index, offset),
+                "archive {} offset {:#x}",
+                archive_index,
+                offset
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_prefix() {
+        let data: Vec<u8> = (0..=255u8).cycle().take(100_000).collect();
+        let temp = TempCasc::new("read-prefix", &[(String::from("big.bin"), data.clone())]);
+        let casc = temp.open();
+
+        let ckey = ContentKey::from_data(&data);
+        let ekey = &casc.encoding.lookup_by_ckey(&ckey).unwrap().ekeys[0];
+        let (full_ekey, espec) = casc.encoding.lookup_espec_by_short(&ekey.short()).unwrap();
+        assert_eq!(ekey, full_ekey);
+        assert_eq!(
+            casc.encoding.lookup_espec(ekey).unwrap().to_string(),
+            espec.to_string()
+        );
+
+        let prefix = casc.read_prefix_by_ekey(ekey, 16).unwrap();
+        assert!(prefix.len() >= 16);
+        assert_eq!(&data[..prefix.len()], prefix);
+    }
+
+    #[test]
+    fn test_contains_keys() {
+        let data = b"hello world".to_vec();
+        let temp = TempCasc::new("contains", &[(String::from("a.txt"), data.clone())]);
+        let casc = temp.open();
+
+        let ckey = ContentKey::from_data(&data);
+        assert!(casc.contains_ckey(&ckey));
+        let ekey = &casc.encoding.lookup_by_ckey(&ckey).unwrap().ekeys[0];
+        assert!(casc.contains_ekey(ekey));
+
+        assert!(!casc.contains_ckey(&ContentKey::from_data(b"not in the build")));
+        assert!(!casc.contains_ekey(&EncodingKey::from_slice(&[0x42; 16])));
+    }
+
+    #[test]
+    fn test_encoding_without_ekey() {
+        let temp = TempCasc::new(
+            "no-encoding-ekey",
+            &[(String::from("a.txt"), b"a".to_vec())],
+        );
+        let build_config = temp.build_config();
+        let encoding = build_config.encoding.as_ref().unwrap();
+
+        // Only the content key, which the encoding file isn't stored under locally
+        let build_config_text = format!(
+            "root = {:?}\nencoding = {:?}\nencoding-size = {}\n",
+            build_config.root, encoding.decoded.hash, encoding.decoded.size
+        );
+        let build_config = parse_build_config(&build_config_text).unwrap();
+        assert!(build_config.encoding.as_ref().unwrap().encoded.is_none());
+
+        let casc = CASC::new(temp.dir.to_str().unwrap(), &build_config).unwrap();
+        let ckey = ContentKey::from_data(b"a");
+        assert_eq!(b"a".as_slice(), casc.read_by_ckey(&ckey).unwrap());
+
+        // A content key that matches nothing stored still fails
+        let build_config_text = format!(
+            "root = {:?}\nencoding = {:?}\nencoding-size = {}\n",
+            build_config.root, ckey, encoding.decoded.size
+        );
+        let build_config = parse_build_config(&build_config_text).unwrap();
+        let err = CASC::new(temp.dir.to_str().unwrap(), &build_config)
+            .map(|_| ())
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("couldn't find encoding file"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_multiple_data_paths() {
+        let base_data = b"base data".to_vec();
+        let overlay_data = b"overlay data".to_vec();
+        let base = TempCasc::new("split-base", &[(String::from("a.txt"), base_data.clone())]);
+        let overlay = TempCasc::new(
+            "split-overlay",
+            &[(String::from("b.txt"), overlay_data.clone())],
+        );
+
+        let overlay_ekey = overlay
+            .open()
+            .encoding
+            .lookup_by_ckey(&ContentKey::from_data(&overlay_data))
+            .unwrap()
+            .ekeys[0]
+            .clone();
+
+        let casc = CASC::with_data_paths(
+            &[base.data_path(), overlay.data_path()],
+            &base.build_config(),
+        )
+        .unwrap();
+        assert_eq!(
+            base_data,
+            casc.read_by_ckey(&ContentKey::from_data(&base_data))
+                .unwrap()
+        );
+        assert_eq!(overlay_data, casc.read_by_ekey(&overlay_ekey).unwrap());
+    }
+
+    #[test]
+    fn test_verify_archive_headers() {
+        let files = vec![
+            (String::from("a.txt"), b"first".to_vec()),
+            (String::from("b.txt"), b"second".to_vec()),
+        ];
+        let temp = TempCasc::new("verify-headers", &files);
+
+        let builder = builder::CASCBuilder::load(&temp.dir).unwrap();
+        let data_file = temp.data_path().join("data.000");
+        let errors = verify_archive_headers(&data_file, &builder.indexes).unwrap();
+        assert!(errors.is_empty(), "{:?}", errors);
+
+        // Flip a byte of the first header's size field
+        let (key, entry) = builder
+            .indexes
+            .iter_all_entries()
+            .find(|(_, e)| e.archive_index == 0)
+            .unwrap();
+        let mut data = std::fs::read(&data_file).unwrap();
+        data[entry.offset as usize + 16] ^= 0xff;
+        std::fs::write(&data_file, data).unwrap();
+
+        let errors = verify_archive_headers(&data_file, &builder.indexes).unwrap();
+        assert!(errors.iter().all(|e| &e.key == key));
+        let problems: Vec<_> = errors.iter().map(|e| &e.problem).collect();
+        assert!(matches!(problems[0], HeaderProblem::SizeMismatch { .. }));
+        assert!(matches!(problems[1], HeaderProblem::ChecksumA { .. }));
+        assert!(matches!(problems[2], HeaderProblem::ChecksumB { .. }));
+    }
+
+    #[test]
+    fn test_truncated_data_file() {
+        let data: Vec<u8> = (0..=255u8).cycle().take(10_000).collect();
+        let temp = TempCasc::new("truncated-data", &[(String::from("big.bin"), data.clone())]);
+        let casc = temp.open();
+
+        let ckey = ContentKey::from_data(&data);
+        let ekey = &casc.encoding.lookup_by_ckey(&ckey).unwrap().ekeys[0];
+        let (data_path, entry) = casc.lookup_entry(ekey).unwrap();
+        let data_file = data_path.join(format!("data.{:03}", entry.archive_index));
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&data_file)
+            .unwrap();
+        file.set_len(entry.offset as u64 + entry.size as u64 - 1)
+            .unwrap();
+
+        let err = casc.read_by_ckey(&ckey).unwrap_err();
+        assert!(err.to_string().contains("is truncated"), "{}", err);
+    }
+
+    #[test]
+    fn test_read_prefix_bad_header_size() {
+        let data: Vec<u8> = (0..=255u8).cycle().take(100_000).collect();
+        let ckey = ContentKey::from_data(&data);
+        let temp = TempCasc::new("prefix-header-size", &[(String::from("big.bin"), data)]);
+        let casc = temp.open();
+        let ekey = casc.encoding.lookup_by_ckey(&ckey).unwrap().ekeys[0].clone();
+        let (data_path, entry) = casc.lookup_entry(&ekey).unwrap();
+        let data_file = data_path.join(format!("data.{:03}", entry.archive_index));
+
+        // The blte header size isn't covered by the data block checksums
+        for header_size in [4u32, entry.size] {
+            let mut content = std::fs::read(&data_file).unwrap();
+            let pos = entry.offset as usize + FileHeader::SIZE + 4;
+            content[pos..pos + 4].copy_from_slice(&header_size.to_be_bytes());
+            std::fs::write(&data_file, content).unwrap();
+
+            let err = casc.read_prefix_by_ekey(&ekey, 16).unwrap_err();
+            assert!(err.to_string().contains("doesn't fit"), "{}", err);
+        }
     }
 }
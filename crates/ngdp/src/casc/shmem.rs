@@ -1,3 +1,4 @@
+use anyhow::Context;
 use binrw::{BinRead, BinWrite, NullString};
 use std::{fmt::Debug, io::Cursor};
 
@@ -10,7 +11,7 @@ pub struct Shmem {
     pub unused_bytes: Vec<UnusedBytes>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct UnusedBytes {
     pub data_file_missing: u16,
     pub data_number: u16,
@@ -18,6 +19,33 @@ pub struct UnusedBytes {
     pub offset: u32,
 }
 
+impl UnusedBytes {
+    /// Checks the values fit in the 30 bit count/offset + 10 bit flag/data number packing
+    pub fn new(
+        data_file_missing: u16,
+        data_number: u16,
+        count: u32,
+        offset: u32,
+    ) -> Result<UnusedBytes, anyhow::Error> {
+        if data_file_missing > 1 || data_number > 0x3ff || count > 0x3fffffff || offset > 0x3fffffff
+        {
+            anyhow::bail!(
+                "unused bytes out of range - data file: {}, offset: {:#x}, count: {:#x}, missing: {}",
+                data_number,
+                offset,
+                count,
+                data_file_missing
+            );
+        }
+        Ok(UnusedBytes {
+            data_file_missing,
+            data_number,
+            count,
+            offset,
+        })
+    }
+}
+
 impl Shmem {
     pub fn new(data_path: &str) -> Shmem {
         Shmem {
@@ -34,14 +62,32 @@ impl Shmem {
         }
     }
 
-    pub fn reserve_bytes(&mut self, count: usize) -> Option<UnusedBytes> {
-        let count: u32 = count.try_into().ok()?;
+    pub fn reserve_bytes(&mut self, count: usize) -> Result<UnusedBytes, anyhow::Error> {
+        if count > MAX_DATA_SIZE {
+            anyhow::bail!(
+                "can't reserve {} bytes, more than the maximum data file size of {}",
+                count,
+                MAX_DATA_SIZE
+            );
+        }
+        let count = count as u32;
 
         let (idx, slot) = self
             .unused_bytes
             .iter_mut()
             .enumerate()
-            .find(|(_idx, ub)| ub.count >= count || ub.data_file_missing == 1)?;
+            .find(|(_idx, ub)| ub.count >= count || ub.data_file_missing == 1)
+            .ok_or_else(|| anyhow::anyhow!("no free space in shmem for {} bytes", count))?;
+
+        let end = slot.offset as u64 + count as u64;
+        if end > MAX_DATA_SIZE as u64 || slot.data_number > 0x3ff {
+            anyhow::bail!(
+                "reservation out of range - data file: {}, offset: {:#x}, count: {:#x}",
+                slot.data_number,
+                slot.offset,
+                count
+            );
+        }
 
         let res = UnusedBytes {
             data_file_missing: slot.data_file_missing,
@@ -52,34 +98,179 @@ impl Shmem {
 
         let new_count = if slot.data_file_missing == 0 {
             // Existing file
-            slot.count.checked_sub(count)?
+            slot.count - count
         } else {
             // New file
-            (MAX_DATA_SIZE as u32).checked_sub(count)?
+            MAX_DATA_SIZE as u32 - count
         };
 
-        let new_offset = slot.offset.checked_add(count)?;
-
+        let was_missing = slot.data_file_missing == 1;
         if new_count == 0 {
             self.unused_bytes.remove(idx);
         } else {
             slot.data_file_missing = 0;
             slot.count = new_count;
-            slot.offset = new_offset;
+            slot.offset = end as u32;
+            if was_missing {
+                // Now an existing file, which sorts elsewhere
+                self.sort_unused_bytes();
+            }
         }
 
-        Some(res)
+        Ok(res)
+    }
+
+    /// Free space in data files that already exist, not counting data files yet to be created
+    pub fn total_unused_bytes(&self) -> u64 {
+        self.unused_bytes
+            .iter()
+            .filter(|ub| ub.data_file_missing == 0)
+            .map(|ub| ub.count as u64)
+            .sum()
     }
 
-    // Might not even be neccesary, client doesn't seem to provide this info
-    // TODO: fn free_bytes(&mut self, data_number, count, offset)
+    /// Checks the free space agrees with what the indexes say is in use. Errors listing every free
+    /// extent overlapping an indexed entry, and every gap in a data file that's neither free nor indexed.
+    pub fn validate_against(&self, indexes: &Indexes) -> Result<(), anyhow::Error> {
+        const MAX_REPORTED: usize = 20;
+
+        // (data file, start, end, is free)
+        let mut extents = Vec::from_iter(indexes.iter_all_entries().map(|(_k, e)| {
+            (
+                e.archive_index,
+                e.offset as u64,
+                e.offset as u64 + e.size as u64,
+                false,
+            )
+        }));
+        extents.extend(
+            self.unused_bytes
+                .iter()
+                .filter(|ub| ub.data_file_missing == 0 && ub.count > 0)
+                .map(|ub| {
+                    let start = ub.offset as u64;
+                    (ub.data_number, start, start + ub.count as u64, true)
+                }),
+        );
+        extents.sort();
+
+        let mut problems = vec![];
+        // Furthest end seen so far in the current data file, of used and of free extents
+        let mut current = None;
+        let (mut used_end, mut free_end) = (0u64, 0u64);
+        for (data_number, start, end, free) in extents {
+            if current != Some(data_number) {
+                current = Some(data_number);
+                (used_end, free_end) = (0, 0);
+            }
+
+            let overlap_end = if free { used_end } else { free_end };
+            if start < overlap_end {
+                problems.push(format!(
+                    "data.{:03}: free space overlaps an index entry at {:#x}..{:#x}",
+                    data_number,
+                    start,
+                    end.min(overlap_end)
+                ));
+            }
+
+            let covered_end = used_end.max(free_end);
+            if start > covered_end {
+                problems.push(format!(
+                    "data.{:03}: {:#x}..{:#x} is neither free nor indexed",
+                    data_number, covered_end, start
+                ));
+            }
 
-    pub fn rebuild_unused_from_index(&mut self, index: &Indexes) {
-        let mut all_entries = Vec::from_iter(index.iter_all_entries().map(|(_k, e)| e));
-        all_entries.sort_by_key(|e| (e.archive_index, e.offset));
+            if free {
+                free_end = free_end.max(end);
+            } else {
+                used_end = used_end.max(end);
+            }
+        }
+
+        if !problems.is_empty() {
+            let more = problems.len().saturating_sub(MAX_REPORTED);
+            problems.truncate(MAX_REPORTED);
+            if more > 0 {
+                problems.push(format!("and {} more", more));
+            }
+            anyhow::bail!("shmem doesn't match indexes:\n{}", problems.join("\n"));
+        }
+        Ok(())
+    }
+
+    /// Marks a range of an existing data file as free, merging it with any free space either side
+    pub fn free_bytes(
+        &mut self,
+        data_number: u16,
+        offset: u32,
+        count: u32,
+    ) -> Result<(), anyhow::Error> {
+        let freed = UnusedBytes::new(0, data_number, count, offset)?;
+
+        // Kept sorted by `parse`, `reserve_bytes` and `rebuild_unused_from_index`
+        let idx = self
+            .unused_bytes
+            .partition_point(|ub| unused_sort_key(ub) < unused_sort_key(&freed));
+        let end = |ub: &UnusedBytes| ub.offset as u64 + ub.count as u64;
+        let same_file =
+            |ub: &&UnusedBytes| ub.data_file_missing == 0 && ub.data_number == data_number;
+
+        let prev = idx
+            .checked_sub(1)
+            .and_then(|i| self.unused_bytes.get(i))
+            .filter(same_file);
+        let next = self.unused_bytes.get(idx).filter(same_file);
+        if prev.is_some_and(|prev| end(prev) > offset as u64)
+            || next.is_some_and(|next| (next.offset as u64) < end(&freed))
+        {
+            anyhow::bail!(
+                "freed range overlaps free space - data file: {}, offset: {:#x}, count: {:#x}",
+                data_number,
+                offset,
+                count
+            );
+        }
+
+        let merge_prev = prev.is_some_and(|prev| end(prev) == offset as u64);
+        let merge_next = next.is_some_and(|next| next.offset as u64 == end(&freed));
+        match (merge_prev, merge_next) {
+            (false, false) => self.unused_bytes.insert(idx, freed),
+            (true, false) => {
+                let prev = &mut self.unused_bytes[idx - 1];
+                *prev = UnusedBytes::new(0, data_number, prev.count + count, prev.offset)?;
+            }
+            (false, true) => {
+                let next = &mut self.unused_bytes[idx];
+                *next = UnusedBytes::new(0, data_number, count + next.count, offset)?;
+            }
+            (true, true) => {
+                let next = self.unused_bytes.remove(idx);
+                let prev = &mut self.unused_bytes[idx - 1];
+                *prev =
+                    UnusedBytes::new(0, data_number, prev.count + count + next.count, prev.offset)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rebuild_unused_from_index(&mut self, index: &Indexes) -> Result<(), anyhow::Error> {
+        let mut all_entries = Vec::from_iter(index.iter_all_entries());
+        all_entries.sort_by_key(|(_k, e)| (e.archive_index, e.offset));
 
         let mut unused_bytes: Vec<UnusedBytes> = vec![];
-        for entry in all_entries {
+        for (key, entry) in all_entries {
+            let bad_entry = || {
+                format!(
+                    "bad index entry - key: {}, archive: {}, offset: {:#x}, size: {:#x}",
+                    hex::encode(key),
+                    entry.archive_index,
+                    entry.offset,
+                    entry.size
+                )
+            };
+
             let mut last = unused_bytes.pop().unwrap_or(UnusedBytes {
                 data_file_missing: 1,
                 data_number: entry.archive_index,
@@ -87,17 +278,21 @@ impl Shmem {
                 offset: 0,
             });
 
-            let last_end = last.offset + last.count;
-            if entry.archive_index == last.data_number && entry.offset == last_end {
-                last.count += entry.size;
+            let last_end = last.offset as u64 + last.count as u64;
+            if entry.archive_index == last.data_number && entry.offset as u64 == last_end {
+                last = UnusedBytes::new(
+                    last.data_file_missing,
+                    last.data_number,
+                    last.count.saturating_add(entry.size),
+                    last.offset,
+                )
+                .with_context(bad_entry)?;
                 unused_bytes.push(last);
             } else {
-                unused_bytes.push(UnusedBytes {
-                    data_file_missing: 0,
-                    data_number: entry.archive_index,
-                    count: entry.size,
-                    offset: entry.offset,
-                });
+                unused_bytes.push(
+                    UnusedBytes::new(0, entry.archive_index, entry.size, entry.offset)
+                        .with_context(bad_entry)?,
+                );
             }
         }
 
@@ -111,9 +306,20 @@ impl Shmem {
         }));
 
         self.unused_bytes = unused_bytes;
+        self.sort_unused_bytes();
+        Ok(())
+    }
+
+    /// Orders free space by existing files first, then data file and offset, which `free_bytes` relies on
+    fn sort_unused_bytes(&mut self) {
+        self.unused_bytes.sort_by_key(unused_sort_key);
     }
 }
 
+fn unused_sort_key(ub: &UnusedBytes) -> (u16, u16, u32) {
+    (ub.data_file_missing, ub.data_number, ub.offset)
+}
+
 impl Debug for Shmem {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("ShmemInfo")
@@ -156,13 +362,22 @@ impl Shmem {
             "header size changed, somethings new"
         );
 
-        Ok(Shmem {
+        // The slot arrays are fixed size, with unused slots zeroed out at the end
+        let num_slots = block1
+            .unused_byte_counts
+            .iter()
+            .zip(block1.unused_byte_positions.iter())
+            .rposition(|(count, offset)| count.get() != 0 || offset.get() != 0)
+            .map_or(0, |idx| idx + 1);
+
+        let mut shmem = Shmem {
             data_path: block4.data_path.to_string(),
             index_versions: block4.index_versions,
             unused_bytes: block1
                 .unused_byte_counts
                 .iter()
                 .zip(block1.unused_byte_positions.iter())
+                .take(num_slots)
                 .map(|(count, offset)| {
                     let (count, data_file_missing) = count.get_30_10();
                     let (offset, data_number) = offset.get_30_10();
@@ -180,7 +395,9 @@ impl Shmem {
                     }
                 })
                 .collect(),
-        })
+        };
+        shmem.sort_unused_bytes();
+        Ok(shmem)
     }
 
     pub fn write(&self, buf: &mut Vec<u8>) -> Result<(), anyhow::Error> {
@@ -311,3 +528,200 @@ mod repr {
         pub offset: u32,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut shmem = Shmem::new("Global\\../Data/data");
+        shmem.index_versions = [7; NUM_INDEXES];
+        shmem.unused_bytes = vec![
+            UnusedBytes {
+                data_file_missing: 0,
+                data_number: 0,
+                count: 1234,
+                offset: 0x1000,
+            },
+            UnusedBytes {
+                data_file_missing: 0,
+                data_number: 3,
+                count: MAX_DATA_SIZE as u32,
+                offset: 0,
+            },
+            UnusedBytes {
+                data_file_missing: 0,
+                data_number: 0x3ff,
+                count: 1,
+                offset: MAX_DATA_SIZE as u32 - 1,
+            },
+            UnusedBytes {
+                data_file_missing: 1,
+                data_number: 4,
+                count: 0,
+                offset: 0,
+            },
+        ];
+
+        let mut buf = vec![];
+        shmem.write(&mut buf).unwrap();
+        let parsed = Shmem::parse(&buf).unwrap();
+
+        assert_eq!(shmem.data_path, parsed.data_path);
+        assert_eq!(shmem.index_versions, parsed.index_versions);
+        assert_eq!(shmem.unused_bytes, parsed.unused_bytes);
+
+        // Writing the parsed shmem should give back identical bytes
+        let mut rewritten = vec![];
+        parsed.write(&mut rewritten).unwrap();
+        assert_eq!(buf, rewritten);
+    }
+
+    #[test]
+    fn test_round_trip_after_reservations() {
+        let mut shmem = Shmem::new("Global\\../Data/data");
+        let first = shmem.reserve_bytes(100).unwrap();
+        let second = shmem.reserve_bytes(200).unwrap();
+        assert_eq!((first.data_number, first.offset), (0, 0));
+        assert_eq!((second.data_number, second.offset), (0, 100));
+
+        let mut buf = vec![];
+        shmem.write(&mut buf).unwrap();
+        let parsed = Shmem::parse(&buf).unwrap();
+        assert_eq!(shmem.unused_bytes, parsed.unused_bytes);
+    }
+
+    #[test]
+    fn test_rebuild_rejects_out_of_range_entries() {
+        use super::super::idx::{Entry, Index};
+        use crate::tact::EncodingKey;
+
+        let mut indexes = Indexes::new((0..NUM_INDEXES as u8).map(Index::new).collect());
+        let entry = Entry {
+            archive_index: 0,
+            offset: 0,
+            size: 0x40000000,
+        };
+        indexes
+            .insert(&EncodingKey::from_slice(&[0xab; 16]), entry)
+            .unwrap();
+
+        let mut shmem = Shmem::new("Global\\../Data/data");
+        let err = shmem.rebuild_unused_from_index(&indexes).unwrap_err();
+        assert!(format!("{:#}", err).contains("key: ababababababababab,"));
+        assert!(UnusedBytes::new(0, 0x400, 0, 0).is_err());
+    }
+
+    #[test]
+    fn test_write_rejects_overflowing_slots() {
+        let mut shmem = Shmem::new("Global\\../Data/data");
+        shmem.unused_bytes[0].data_number = 0x400;
+        assert!(shmem.write(&mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_validate_against() {
+        use super::super::idx::{Entry, Index};
+        use crate::tact::EncodingKey;
+
+        let mut indexes = Indexes::new((0..NUM_INDEXES as u8).map(Index::new).collect());
+        for (i, offset) in [(1u8, 0), (2, 100)] {
+            let entry = Entry {
+                archive_index: 0,
+                offset,
+                size: 100,
+            };
+            indexes
+                .insert(&EncodingKey::from_slice(&[i; 16]), entry)
+                .unwrap();
+        }
+
+        let mut shmem = Shmem::new("Global\\../Data/data");
+        shmem.reserve_bytes(200).unwrap();
+        shmem.validate_against(&indexes).unwrap();
+
+        // Free space handing out an indexed entry's bytes
+        shmem.unused_bytes[0].offset = 150;
+        let err = shmem.validate_against(&indexes).unwrap_err();
+        assert!(format!("{:#}", err).contains("overlaps an index entry at 0x96..0xc8"));
+
+        // Bytes nothing accounts for
+        shmem.unused_bytes[0].offset = 250;
+        let err = shmem.validate_against(&indexes).unwrap_err();
+        assert!(format!("{:#}", err).contains("0xc8..0xfa is neither free nor indexed"));
+    }
+
+    #[test]
+    fn test_free_bytes() {
+        let mut shmem = Shmem::new("Global\\../Data/data");
+        for _ in 0..4 {
+            shmem.reserve_bytes(100).unwrap();
+        }
+        let tail = shmem.unused_bytes[0].clone();
+        assert_eq!(400, tail.offset);
+
+        shmem.free_bytes(0, 100, 100).unwrap();
+        shmem.free_bytes(0, 300, 100).unwrap();
+        assert_eq!(
+            (100, 100),
+            (shmem.unused_bytes[0].offset, shmem.unused_bytes[0].count)
+        );
+        assert_eq!(
+            (300, tail.count + 100),
+            (shmem.unused_bytes[1].offset, shmem.unused_bytes[1].count)
+        );
+
+        shmem.free_bytes(0, 200, 100).unwrap();
+        assert_eq!(
+            (100, tail.count + 300),
+            (shmem.unused_bytes[0].offset, shmem.unused_bytes[0].count)
+        );
+        assert_eq!(1, shmem.unused_bytes[1].data_file_missing);
+
+        assert!(shmem.free_bytes(0, 150, 10).is_err());
+        assert_eq!(shmem.reserve_bytes(50).unwrap().offset, 100);
+    }
+
+    #[test]
+    fn test_free_bytes_unsorted() {
+        // Free space as another client might have left it, not in data file order
+        let mut shmem = Shmem::new("Global\\../Data/data");
+        shmem.unused_bytes = vec![
+            UnusedBytes::new(0, 1, 100, 500).unwrap(),
+            UnusedBytes::new(1, 2, 0, 0).unwrap(),
+            UnusedBytes::new(0, 0, 100, 200).unwrap(),
+        ];
+        let mut buf = vec![];
+        shmem.write(&mut buf).unwrap();
+        let mut shmem = Shmem::parse(&buf).unwrap();
+        let order: Vec<_> = shmem.unused_bytes.iter().map(|ub| ub.data_number).collect();
+        assert_eq!(vec![0, 1, 2], order);
+
+        // Merges with the free space either side, which are only neighbours once sorted
+        shmem.free_bytes(1, 400, 100).unwrap();
+        shmem.free_bytes(0, 100, 100).unwrap();
+        assert_eq!(3, shmem.unused_bytes.len());
+        assert_eq!(
+            (100, 200),
+            (shmem.unused_bytes[0].offset, shmem.unused_bytes[0].count)
+        );
+        assert_eq!(
+            (400, 200),
+            (shmem.unused_bytes[1].offset, shmem.unused_bytes[1].count)
+        );
+
+        // Reserving from a missing file makes it an existing one, which sorts before missing files
+        let mut shmem = Shmem::new("Global\\../Data/data");
+        shmem.unused_bytes = vec![
+            UnusedBytes::new(1, 3, 0, 0).unwrap(),
+            UnusedBytes::new(0, 5, 10, 0).unwrap(),
+        ];
+        shmem.unused_bytes.sort_by_key(unused_sort_key);
+        shmem.reserve_bytes(100).unwrap();
+        let keys: Vec<_> = shmem.unused_bytes.iter().map(unused_sort_key).collect();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(sorted, keys);
+    }
+}
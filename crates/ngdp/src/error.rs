@@ -0,0 +1,99 @@
+use std::collections::BTreeSet;
+
+use thiserror::Error;
+
+use crate::tact::{ContentKey, EncodingKey};
+
+/// Errors from reading and decoding files, for callers that need to tell failures apart
+#[derive(Error, Debug)]
+pub enum NgdpError {
+    /// Every key the file needs that we don't have, not just the first one hit
+    #[error("missing encryption keys: {}", format_key_names(.0))]
+    MissingEncryptionKey(BTreeSet<[u8; 8]>),
+    #[error("corrupt blte: {0}")]
+    CorruptBlte(String),
+    #[error("couldn't find encoding for ckey. ckey = {0:?}")]
+    KeyNotInEncoding(ContentKey),
+    #[error("couldn't find entry for ekey. ekey = {0:?}")]
+    IndexMiss(EncodingKey),
+    #[error("network error: {0:#}")]
+    Network(anyhow::Error),
+    #[error("{second} (first source failed: {first})")]
+    BothSourcesFailed {
+        first: Box<NgdpError>,
+        second: Box<NgdpError>,
+    },
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("parse error: {0}")]
+    Parse(#[from] binrw::Error),
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl NgdpError {
+    pub(crate) fn network(e: impl Into<anyhow::Error>) -> NgdpError {
+        NgdpError::Network(e.into())
+    }
+
+    pub(crate) fn missing_key(key_name: [u8; 8]) -> NgdpError {
+        NgdpError::MissingEncryptionKey(BTreeSet::from([key_name]))
+    }
+}
+
+fn format_key_names(keys: &BTreeSet<[u8; 8]>) -> String {
+    keys.iter().map(hex::encode).collect::<Vec<_>>().join(", ")
+}
+
+/// Recovers typed errors that passed through an `anyhow::Error` on the way up
+impl From<anyhow::Error> for NgdpError {
+    fn from(e: anyhow::Error) -> Self {
+        let e = match e.downcast::<NgdpError>() {
+            Ok(e) => return e,
+            Err(e) => e,
+        };
+        if let Some(io_error) = e.downcast_ref::<std::io::Error>() {
+            // Rebuilt rather than downcast, to keep the context saying what was being read
+            return NgdpError::Io(std::io::Error::new(io_error.kind(), format!("{:#}", e)));
+        }
+        match e.downcast::<binrw::Error>() {
+            Ok(e) => NgdpError::Parse(e),
+            Err(e) => NgdpError::Other(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_downcast_through_anyhow() {
+        let ckey = ContentKey::from_slice(&[0xab; 16]);
+        let err = anyhow::Error::from(NgdpError::KeyNotInEncoding(ckey.clone()));
+        assert!(matches!(
+            NgdpError::from(err),
+            NgdpError::KeyNotInEncoding(k) if k == ckey
+        ));
+
+        let io_error = std::io::Error::from(std::io::ErrorKind::UnexpectedEof);
+        let err = NgdpError::from(anyhow::Error::from(io_error).context("reading data.001"));
+        assert!(
+            matches!(&err, NgdpError::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof),
+            "{:?}",
+            err
+        );
+        assert!(err.to_string().contains("reading data.001"), "{}", err);
+
+        let parse_error = binrw::Error::AssertFail {
+            pos: 0,
+            message: String::from("bad magic"),
+        };
+        let err = NgdpError::from(anyhow::Error::from(parse_error));
+        assert!(matches!(err, NgdpError::Parse(_)), "{:?}", err);
+
+        let err = NgdpError::from(anyhow::anyhow!("something else"));
+        assert!(matches!(err, NgdpError::Other(_)));
+        assert_eq!("something else", err.to_string());
+    }
+}
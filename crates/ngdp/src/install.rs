@@ -0,0 +1,453 @@
+//! Installing a build from the CDN into a fresh or partially installed directory
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{Cursor, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Context};
+use ribbit::Version;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    blte::decode_blte_into,
+    casc::{builder::CASCBuilder, idx, FileHeader},
+    progress::{ProgressReader, ProgressSink},
+    source::{
+        fetch_encoded_pair, plan_fetch_encoded, read_cdn_pair, ArchiveEfficiency, ArchivedFiles,
+    },
+    tact::{
+        cdn::CDNClient,
+        config::{parse_build_config, parse_cdn_config},
+        download::{self, parse_download_manifest},
+        encoding::parse_encoding,
+        index::parse_index,
+        install::parse_install_manifest,
+        keys::TactKeys,
+        ContentKey,
+    },
+    util::{normalize_path, PathStyle},
+};
+
+/// What an install should contain, and which of its files are already done
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InstallState {
+    pub install_tags: HashSet<String>,
+    pub download_tags: HashSet<String>,
+    pub installed_files: HashSet<ContentKey>,
+    // TODO: Include version?
+}
+
+/// Installs the build `version` points at into `dir`: the install manifest's files for
+/// `state.install_tags` go in `_retail_`, the download manifest's files for `state.download_tags`
+/// go in the local CASC. Progress is recorded in `state` as files finish, so an interrupted
+/// install can be saved and resumed.
+pub fn install(
+    cdn: &mut CDNClient,
+    version: &Version,
+    tact_keys: &TactKeys,
+    dir: &Path,
+    builder: &mut CASCBuilder,
+    state: &mut InstallState,
+    progress: &dyn ProgressSink,
+) -> Result<(), anyhow::Error> {
+    // TODO: We're being really naive with memory, keeping this ~256M buffer life this long
+    let mut buf: Vec<u8> = vec![];
+    let mut decoded: Vec<u8> = vec![];
+
+    let retail_dir = dir.join("_retail_");
+    let data_dir = dir.join("Data").join("data");
+
+    std::fs::create_dir_all(&retail_dir)?;
+    std::fs::create_dir_all(&data_dir)?;
+
+    let build_config_text = builder.read_config(cdn, &ContentKey::parse(&version.build_config)?)?;
+    let build_config = parse_build_config(&build_config_text)?;
+    build_config.validate_for_install()?;
+    // dbg!(&build_config);
+
+    let cdn_config_text = builder.read_config(cdn, &ContentKey::parse(&version.cdn_config)?)?;
+    let cdn_config = parse_cdn_config(&cdn_config_text)?;
+    // dbg!(&cdn_config);
+
+    progress.message("Ranking CDN servers");
+    cdn.rank_servers(&cdn_config.archives[0])?;
+
+    let encoding = {
+        let encoding_pair = build_config
+            .encoding
+            .as_ref()
+            .ok_or_else(|| anyhow!("build config had no encoding field"))?;
+        let encoding_data = read_cdn_pair(cdn, tact_keys, encoding_pair, None)?;
+        parse_encoding(&encoding_data, true).context("parsing encoding")?
+    };
+
+    progress.start_task(
+        cdn_config
+            .archives_index_size
+            .iter()
+            .map(|s| *s as u64)
+            .sum(),
+    );
+    progress.message("Building file index");
+
+    let mut archived_files = ArchivedFiles::new();
+    let mut archive_sizes = HashMap::new();
+    for (archive, index_size) in cdn_config
+        .archives
+        .iter()
+        .zip(cdn_config.archives_index_size)
+    {
+        let index_data = builder.read_archive_index(cdn, archive, index_size)?;
+        let index = parse_index(&index_data)
+            .with_context(|| format!("parsing archive index {:?}", archive))?;
+
+        let size: u64 = index.entries.values().map(|e| e.size).sum();
+        archive_sizes.insert(archive.clone(), size);
+
+        for (key, entry) in index.entries {
+            assert!(archived_files
+                .insert(key, (archive.clone(), entry))
+                .is_none());
+        }
+        progress.inc(index_size as u64);
+    }
+    progress.finish();
+
+    progress.message("Fetching install manifest");
+    let install_manifest_pair = build_config
+        .install
+        .as_ref()
+        .ok_or_else(|| anyhow!("build config had no install field"))?;
+    let install_manifest_data = fetch_encoded_pair(cdn, install_manifest_pair, Some(&encoding))?;
+    let install_manifest = parse_install_manifest(tact_keys, &install_manifest_data)?;
+
+    let total_bytes: u64 = install_manifest
+        .files_with_tags(&state.install_tags)
+        .map(|f| f.size as u64)
+        .sum();
+
+    progress.start_task(total_bytes);
+    progress.message("Installing base files");
+
+    // Files with identical content are only fetched and decoded once, then linked or copied
+    let mut written_paths = HashMap::<ContentKey, PathBuf>::new();
+    let mut dedup_bytes = 0u64;
+
+    for file in install_manifest.files_with_tags(&state.install_tags) {
+        let file_name = normalize_path(&file.name, PathStyle::ForFs);
+        progress.message(&file_name);
+
+        let path = retail_dir.join(PathBuf::from(&file_name));
+        if state.installed_files.contains(&file.key) && path.exists() {
+            written_paths.entry(file.key.clone()).or_insert(path);
+            progress.inc(file.size as u64);
+            continue;
+        }
+
+        || -> Result<(), anyhow::Error> {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            let already_installed = || -> Result<bool, anyhow::Error> {
+                let mut f = File::open(&path)?;
+                // Only hash the file if it could possibly match
+                if f.metadata()?.len() != file.size as u64 {
+                    return Ok(false);
+                }
+                let ckey = ContentKey::read_from_data(&mut f)?;
+                Ok(ckey == file.key)
+            }();
+            if already_installed.unwrap_or(false) {
+                return Ok(());
+            }
+
+            if let Some(first_path) = written_paths.get(&file.key) {
+                let _ = std::fs::remove_file(&path);
+                if std::fs::hard_link(first_path, &path).is_err() {
+                    std::fs::copy(first_path, &path)?;
+                }
+                dedup_bytes += file.size as u64;
+                return Ok(());
+            }
+
+            let ce_entry = encoding.lookup_by_ckey(&file.key).ok_or_else(|| {
+                anyhow::anyhow!("Couldn't find encoding for ckey: {:?}", &file.key)
+            })?;
+            let ekey = &ce_entry.ekeys[0];
+
+            let (mut reader, encoded_size) =
+                if let Some((archive, entry)) = archived_files.get(ekey) {
+                    let size = entry.size as usize;
+                    let reader = cdn.read_data_part(archive, entry.offset as usize, size)?;
+                    (reader, Some(size))
+                } else {
+                    let size = encoding.lookup_encoded_size(ekey).map(|s| s as usize);
+                    (cdn.read_data(ekey)?, size)
+                };
+            let encoded_size = encoded_size
+                .or_else(|| reader.content_length().map(|s| s as usize))
+                .ok_or_else(|| anyhow::anyhow!("unknown encoded size for {:?}", ekey))?;
+            read_with_progress(progress, &mut reader, &mut buf, encoded_size)?;
+
+            decode_blte_into(tact_keys, &buf, &mut decoded)?;
+            std::fs::write(&path, &decoded)?;
+
+            Ok(())
+        }()?;
+
+        written_paths.entry(file.key.clone()).or_insert(path);
+        state.installed_files.insert(file.key.clone());
+        progress.inc(file.size as u64);
+    }
+    if dedup_bytes > 0 {
+        progress.message(&format!(
+            "Linked {} bytes of duplicate files instead of downloading them",
+            dedup_bytes
+        ));
+    }
+    progress.finish();
+
+    progress.message("Fetching download manifest");
+    let download_manifest_pair = build_config
+        .download
+        .as_ref()
+        .ok_or_else(|| anyhow!("build config had no download key"))?;
+    let download_manifest_data = fetch_encoded_pair(cdn, download_manifest_pair, Some(&encoding))?;
+    let download_manifest = parse_download_manifest(tact_keys, &download_manifest_data)?;
+
+    let mut total_bytes = 0u64;
+    let mut finished_bytes = 0u64;
+
+    let mut download_entries = HashMap::new();
+    let mut wanted = vec![];
+    for file in download_manifest.entries_with_tags(&state.download_tags) {
+        if download_entries.insert(file.key.clone(), file).is_some() {
+            continue;
+        }
+        total_bytes += file.file_size;
+
+        if builder.indexes.lookup(&file.key).is_some() {
+            finished_bytes += file.file_size;
+            continue;
+        }
+        wanted.push(file.key.clone());
+    }
+
+    let plan = plan_fetch_encoded(&wanted, &archived_files);
+    let mut archive_order = Vec::from_iter(plan.archives.keys().cloned());
+    archive_order.sort_by_cached_key(|a| {
+        plan.archives[a]
+            .entries
+            .iter()
+            .map(|(ekey, _e)| download_entries[ekey].download_priority as u64)
+            .sum::<u64>()
+    });
+
+    progress.start_task(total_bytes);
+    progress.inc(finished_bytes);
+
+    let mut allocate_and_write =
+        |file: &download::Entry, reader: &mut dyn Read| -> Result<(), anyhow::Error> {
+            let total_size = file.file_size as usize + FileHeader::SIZE;
+
+            let slot = builder.shmem.reserve_bytes(total_size)?;
+
+            if slot.data_file_missing == 1 {
+                // Anything?
+            }
+
+            let path = data_dir.join(format!("data.{:03}", slot.data_number));
+
+            let mut f = File::options()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(path)?;
+            assert!(
+                f.metadata()?.len() >= slot.offset as u64,
+                "recieved offset is outside file bounds"
+            );
+            f.seek(SeekFrom::Start(slot.offset as u64))?;
+
+            let header = FileHeader {
+                hash: file.key.to_rev(),
+                size: total_size as u32,
+                _unk: [0, 0],
+                checksum_a: 0xdeadbeef,
+                checksum_b: 0xdeafbeef,
+            };
+            header.write_to(slot.data_number, slot.offset, &mut f)?;
+            copy_with_progress(progress, reader, &mut f, file.file_size as usize)?;
+
+            // Adding to index last as index should only contain complete entries
+            builder.insert_in_index(
+                &file.key,
+                idx::Entry {
+                    archive_index: slot.data_number,
+                    offset: slot.offset,
+                    size: total_size as u32,
+                },
+            )?;
+
+            Ok(())
+        };
+
+    let mut bulk_bandwidth_sum = 0.0f64;
+    let mut num_bulk_dls = 0u32;
+    let mut wait_time = 0.0f64;
+    let mut num_reqs = 0u32;
+
+    for (archive, fetch) in archive_order.iter().map(|a| (a, &plan.archives[a])) {
+        let archive_size = archive_sizes[archive];
+        let entries = &fetch.entries;
+        let entries_size = fetch.total_bytes;
+        let waste = ArchiveEfficiency::new(archive.clone(), entries_size, archive_size).waste;
+
+        let do_parts = {
+            let bandwidth = bulk_bandwidth_sum / num_bulk_dls as f64;
+            let req_overhead = wait_time / num_reqs as f64;
+            let archive_est = req_overhead + 256_000_000.0 / bandwidth;
+            let parts_est = entries.len() as f64 * req_overhead + entries_size as f64 / bandwidth;
+            let server = cdn
+                .server_ranking()
+                .into_iter()
+                .next()
+                .map_or(String::new(), |(server, _)| server);
+            progress.message(&format!(
+                "archive {:?} from {} ({} entries, {:.02}% waste, bw {:.0} B/s, {:.1} req/s, archive est {:.0}s, parts est {:.0}s)",
+                archive,
+                server,
+                entries.len(),
+                waste * 100.0,
+                bandwidth,
+                1.0 / req_overhead.max(0.0),
+                archive_est.max(0.0),
+                parts_est.max(0.0),
+            ));
+            parts_est < archive_est
+        };
+
+        if do_parts {
+            for (ekey, entry) in entries {
+                let file = download_entries[ekey];
+                let start = Instant::now();
+                let mut reader =
+                    cdn.read_data_part(archive, entry.offset as usize, entry.size as usize)?;
+
+                wait_time += start.elapsed().as_secs_f64();
+                num_reqs += 1;
+
+                allocate_and_write(file, &mut reader)?;
+
+                progress.inc(file.file_size);
+            }
+        } else {
+            let start = Instant::now();
+            let mut reader = cdn.read_data_resumable(archive, 3)?;
+
+            wait_time += start.elapsed().as_secs_f64();
+            num_reqs += 1;
+
+            // Indexes don't have to cover every byte of an archive, so go by the response's size
+            let archive_len = reader.content_length().unwrap_or_else(|| {
+                entries
+                    .iter()
+                    .map(|(_, e)| e.offset + e.size)
+                    .max()
+                    .unwrap_or(0)
+            });
+            read_with_progress(progress, &mut reader, &mut buf, archive_len as usize)?;
+
+            bulk_bandwidth_sum += reader.avg_bandwidth();
+            num_bulk_dls += 1;
+
+            for (ekey, entry) in entries {
+                let file = download_entries[ekey];
+                let data = &buf[entry.offset as usize..][..entry.size as usize];
+                allocate_and_write(file, &mut Cursor::new(data))?;
+
+                progress.inc(file.file_size);
+            }
+        }
+    }
+
+    for ekey in &plan.loose {
+        let file = download_entries[ekey];
+        let mut reader = cdn.read_data(&file.key)?;
+        allocate_and_write(file, &mut reader)?;
+        progress.inc(file.file_size);
+    }
+    progress.finish();
+
+    progress.message("Saving CASC state");
+    builder.write()?;
+
+    // TODO: Generate .build.info
+
+    Ok(())
+}
+
+fn read_with_progress(
+    progress: &dyn ProgressSink,
+    r: &mut impl Read,
+    buf: &mut Vec<u8>,
+    expected_size: usize,
+) -> Result<(), anyhow::Error> {
+    buf.clear();
+    buf.reserve(expected_size);
+
+    let task = progress.sub_task(expected_size as u64);
+    ProgressReader::new(r, &*task).read_to_end(buf)?;
+    task.finish();
+    if buf.len() != expected_size {
+        anyhow::bail!("read {} bytes, expected {}", buf.len(), expected_size);
+    }
+    Ok(())
+}
+
+fn copy_with_progress(
+    progress: &dyn ProgressSink,
+    r: impl Read,
+    file: &mut File,
+    expected_size: usize,
+) -> Result<(), anyhow::Error> {
+    let task = progress.sub_task(expected_size as u64);
+    let copied = std::io::copy(&mut ProgressReader::new(r, &*task), file)?;
+    task.finish();
+    if copied != expected_size as u64 {
+        anyhow::bail!("copied {} bytes, expected {}", copied, expected_size);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::NoProgress;
+
+    #[test]
+    fn test_size_checks() {
+        let data = [1u8; 10];
+        let mut buf = vec![];
+        read_with_progress(&NoProgress, &mut &data[..], &mut buf, 10).unwrap();
+        assert_eq!(data.as_slice(), buf);
+
+        let err = read_with_progress(&NoProgress, &mut &data[..4], &mut buf, 10).unwrap_err();
+        assert_eq!("read 4 bytes, expected 10", err.to_string());
+        let err = read_with_progress(&NoProgress, &mut &data[..], &mut buf, 4).unwrap_err();
+        assert_eq!("read 10 bytes, expected 4", err.to_string());
+
+        let path = std::env::temp_dir().join(format!("steed-copy-{}", std::process::id()));
+        let mut file = File::create(&path).unwrap();
+        copy_with_progress(&NoProgress, &data[..], &mut file, 10).unwrap();
+        let err = copy_with_progress(&NoProgress, &data[..4], &mut file, 10).unwrap_err();
+        assert_eq!("copied 4 bytes, expected 10", err.to_string());
+        drop(file);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,6 +1,10 @@
 mod binrw_ext;
 pub mod blte;
 pub mod casc;
+pub mod error;
+pub mod install;
 pub mod listfile;
+pub mod progress;
+pub mod source;
 pub mod tact;
 pub mod util;
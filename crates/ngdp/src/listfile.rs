@@ -1,21 +1,64 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeSet, HashMap},
+    path::Path,
+};
+
+use reqwest::{
+    header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED},
+    StatusCode,
+};
+
+use crate::{
+    tact::root::{ContentFlags, LocaleFlags, Root},
+    util::{normalize_path, PathStyle},
+};
 
 pub struct ListFile {
+    /// Normalized path to id, for case insensitive lookups
     map: HashMap<String, i32>,
+    /// Paths as written in the listfile
+    names: HashMap<i32, String>,
 }
 
 impl ListFile {
     pub fn get_id(&self, path: &str) -> Option<i32> {
-        self.map.get(&path.to_lowercase()).cloned()
+        self.map
+            .get(&normalize_path(path, PathStyle::ForFs))
+            .cloned()
+    }
+
+    pub fn get_name(&self, id: i32) -> Option<&str> {
+        self.names.get(&id).map(String::as_str)
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = (&String, &i32)> {
-        self.map.iter()
+    /// Every path with its original case, and its file id
+    pub fn iter(&self) -> impl Iterator<Item = (&str, i32)> {
+        self.names.iter().map(|(id, name)| (name.as_str(), *id))
+    }
+
+    /// Returns the entries of this listfile that are present in `root`, sorted by file id.
+    /// Records are filtered the same way as `Root::lookup_by_fileid_and_flags`, so passing empty flags includes everything.
+    pub fn subset_for_root(
+        &self,
+        root: &Root,
+        content_flags: ContentFlags,
+        locale_flags: LocaleFlags,
+    ) -> Vec<(i32, &str)> {
+        let ids: BTreeSet<i32> = root
+            .iter_records()
+            .filter(|(_id, rec_type, _rec)| rec_type.matches(content_flags, locale_flags))
+            .map(|(id, _rec_type, _rec)| id)
+            .collect();
+
+        ids.into_iter()
+            .filter_map(|id| Some((id, self.get_name(id)?)))
+            .collect()
     }
 }
 
 pub fn parse_listfile(content: &str) -> Result<ListFile, anyhow::Error> {
     let mut map = HashMap::new();
+    let mut names = HashMap::new();
     for line in content.lines() {
         if line.is_empty() {
             continue;
@@ -37,8 +80,168 @@ pub fn parse_listfile(content: &str) -> Result<ListFile, anyhow::Error> {
             }
         };
 
-        map.insert(path.to_lowercase(), id);
+        map.insert(normalize_path(path, PathStyle::ForFs), id);
+        names.insert(id, path.to_string());
     }
 
-    Ok(ListFile { map })
+    Ok(ListFile { map, names })
+}
+
+/// Downloads a listfile from `url` and parses it, caching it at `cache_path` once it parsed.
+/// The ETag and Last-Modified headers are kept next to the cache, so an unchanged listfile isn't downloaded again.
+/// Falls back to the cached copy if the download fails or has no valid lines.
+pub fn fetch_listfile_cached(
+    url: &str,
+    cache_path: &Path,
+) -> Result<CachedListFile, anyhow::Error> {
+    let headers_path = cache_path.with_extension("headers");
+    let cached = std::fs::read_to_string(cache_path).ok();
+
+    let mut req = reqwest::blocking::Client::new().get(url);
+    if cached.is_some() {
+        let headers = std::fs::read_to_string(&headers_path).unwrap_or_default();
+        for line in headers.lines() {
+            match line.split_once(": ") {
+                Some(("etag", etag)) => req = req.header(IF_NONE_MATCH, etag),
+                Some(("last-modified", date)) => req = req.header(IF_MODIFIED_SINCE, date),
+                _ => {}
+            }
+        }
+    }
+
+    let fetched = || -> Result<Option<ListFile>, anyhow::Error> {
+        let resp = req.send()?;
+        if resp.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let resp = resp.error_for_status()?;
+
+        let mut headers = String::new();
+        for (name, header) in [("etag", ETAG), ("last-modified", LAST_MODIFIED)] {
+            if let Some(value) = resp.headers().get(header).and_then(|v| v.to_str().ok()) {
+                headers.push_str(&format!("{}: {}\n", name, value));
+            }
+        }
+
+        let content = resp.text()?;
+        let listfile = parse_listfile(&content)?;
+        if listfile.names.is_empty() && !content.trim().is_empty() {
+            anyhow::bail!("downloaded listfile has no valid lines");
+        }
+
+        if let Some(parent) = cache_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(cache_path, &content)?;
+        std::fs::write(&headers_path, headers)?;
+        Ok(Some(listfile))
+    }();
+
+    let (listfile, fetch_error) = match (fetched, cached) {
+        (Ok(Some(listfile)), _) => (listfile, None),
+        (Ok(None), Some(cached)) => (parse_listfile(&cached)?, None),
+        (Ok(None), None) => anyhow::bail!("listfile not modified, but there's no cached copy"),
+        (Err(e), Some(cached)) => (parse_listfile(&cached)?, Some(e)),
+        (Err(e), None) => return Err(e.context(format!("fetching listfile from {}", url))),
+    };
+    Ok(CachedListFile {
+        listfile,
+        fetch_error,
+    })
+}
+
+/// A listfile from `fetch_listfile_cached`
+pub struct CachedListFile {
+    pub listfile: ListFile,
+    /// Why the download failed, if the cached copy was used instead
+    pub fetch_error: Option<anyhow::Error>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tact::cdn::{response, serve};
+
+    #[test]
+    fn test_listfile_and_root_agree_on_paths() {
+        let listfile = parse_listfile("1;Interface/Icons/INV_Misc_QuestionMark.blp\n").unwrap();
+
+        for path in [
+            "Interface/Icons/INV_Misc_QuestionMark.blp",
+            "interface\\icons\\inv_misc_questionmark.blp",
+            "INTERFACE\\ICONS/inv_misc_questionmark.BLP",
+        ] {
+            assert_eq!(listfile.get_id(path), Some(1));
+
+            let listfile_name = listfile.get_name(1).unwrap();
+            assert_eq!(Root::hashpath(path), Root::hashpath(listfile_name));
+        }
+
+        assert_eq!(
+            Some("Interface/Icons/INV_Misc_QuestionMark.blp"),
+            listfile.get_name(1)
+        );
+        assert_eq!(
+            vec![("Interface/Icons/INV_Misc_QuestionMark.blp", 1)],
+            listfile.iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_fetch_listfile_cached() {
+        let dir = std::env::temp_dir().join(format!("steed-listfile-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache_path = dir.join("cache").join("listfile.csv");
+
+        let good = b"1;Interface/Icons/INV_Misc_QuestionMark.blp\n";
+        let bad = b"<html>Service Unavailable</html>";
+        let (server, handle) = serve(vec![
+            response(
+                "200 OK",
+                &[
+                    format!("content-length: {}", good.len()),
+                    String::from("etag: \"v1\""),
+                ],
+                good,
+            ),
+            response("200 OK", &[format!("content-length: {}", bad.len())], bad),
+            response("304 Not Modified", &[], b""),
+        ]);
+        let url = format!("{}/listfile.csv", server);
+
+        let fetched = fetch_listfile_cached(&url, &cache_path).unwrap();
+        assert!(fetched.fetch_error.is_none());
+        let listfile = fetched.listfile;
+        assert_eq!(
+            Some(1),
+            listfile.get_id("interface/icons/inv_misc_questionmark.blp")
+        );
+        assert_eq!(good.as_slice(), std::fs::read(&cache_path).unwrap());
+
+        // A response that isn't a listfile isn't cached, the cached copy is used instead
+        let fetched = fetch_listfile_cached(&url, &cache_path).unwrap();
+        assert_eq!(
+            "downloaded listfile has no valid lines",
+            fetched.fetch_error.unwrap().to_string()
+        );
+        assert_eq!(
+            Some("Interface/Icons/INV_Misc_QuestionMark.blp"),
+            fetched.listfile.get_name(1)
+        );
+        assert_eq!(good.as_slice(), std::fs::read(&cache_path).unwrap());
+
+        let fetched = fetch_listfile_cached(&url, &cache_path).unwrap();
+        assert!(fetched.fetch_error.is_none());
+        assert_eq!(
+            Some("Interface/Icons/INV_Misc_QuestionMark.blp"),
+            fetched.listfile.get_name(1)
+        );
+
+        let requests = handle.join().unwrap();
+        assert!(!requests[0].contains("if-none-match"));
+        assert!(requests[1].contains("if-none-match: \"v1\""));
+        assert!(requests[2].contains("if-none-match: \"v1\""));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
@@ -0,0 +1,84 @@
+use std::io::Read;
+
+/// Receives progress updates from long running operations, so frontends can show them however
+/// they like. Totals and increments are in bytes.
+pub trait ProgressSink {
+    /// Starts a new task, replacing any previous one
+    fn start_task(&self, total: u64);
+    fn inc(&self, n: u64);
+    fn message(&self, msg: &str);
+    fn finish(&self);
+
+    /// A nested task, like a single download during an install. Ignored by default.
+    fn sub_task(&self, _total: u64) -> Box<dyn ProgressSink + '_> {
+        Box::new(NoProgress)
+    }
+}
+
+/// Discards all progress updates
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn start_task(&self, _total: u64) {}
+    fn inc(&self, _n: u64) {}
+    fn message(&self, _msg: &str) {}
+    fn finish(&self) {}
+}
+
+/// Reports every byte read from `inner` to a sink
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    sink: &'a dyn ProgressSink,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    pub fn new(inner: R, sink: &'a dyn ProgressSink) -> Self {
+        ProgressReader { inner, sink }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.sink.inc(n as u64);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Counter {
+        total: Cell<u64>,
+        done: Cell<u64>,
+    }
+
+    impl ProgressSink for Counter {
+        fn start_task(&self, total: u64) {
+            self.total.set(total);
+            self.done.set(0);
+        }
+        fn inc(&self, n: u64) {
+            self.done.set(self.done.get() + n);
+        }
+        fn message(&self, _msg: &str) {}
+        fn finish(&self) {}
+    }
+
+    #[test]
+    fn test_progress_reader() {
+        let counter = Counter::default();
+        counter.start_task(10);
+
+        let mut out = vec![];
+        ProgressReader::new(&[1u8; 10][..], &counter)
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(10, out.len());
+        assert_eq!(counter.total.get(), counter.done.get());
+    }
+}
@@ -0,0 +1,640 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use crate::{
+    blte::{decode_blte, describe},
+    casc::CASC,
+    error::NgdpError,
+    tact::{
+        cdn::CDNClient,
+        config::{EncodedPair, HashSize},
+        encoding::Encoding,
+        index,
+        keys::TactKeys,
+        ContentKey, EncodingKey,
+    },
+};
+
+/// Something files can be read from, by either content or encoding key
+pub trait ContentSource {
+    fn read_by_ckey(&self, ckey: &ContentKey) -> Result<Vec<u8>, NgdpError>;
+    fn read_by_ekey(&self, ekey: &EncodingKey) -> Result<Vec<u8>, NgdpError>;
+}
+
+impl<T: ContentSource + ?Sized> ContentSource for &T {
+    fn read_by_ckey(&self, ckey: &ContentKey) -> Result<Vec<u8>, NgdpError> {
+        (**self).read_by_ckey(ckey)
+    }
+
+    fn read_by_ekey(&self, ekey: &EncodingKey) -> Result<Vec<u8>, NgdpError> {
+        (**self).read_by_ekey(ekey)
+    }
+}
+
+impl ContentSource for CASC {
+    fn read_by_ckey(&self, ckey: &ContentKey) -> Result<Vec<u8>, NgdpError> {
+        CASC::read_by_ckey(self, ckey)
+    }
+
+    fn read_by_ekey(&self, ekey: &EncodingKey) -> Result<Vec<u8>, NgdpError> {
+        CASC::read_by_ekey(self, ekey)
+    }
+}
+
+/// Map of encoding key to the archive containing it, and its location within that archive
+pub type ArchivedFiles = HashMap<EncodingKey, (EncodingKey, index::Entry)>;
+
+/// The archive ranges and loose files needed to fetch a set of files from a CDN
+#[derive(Debug, Default)]
+pub struct FetchPlan {
+    pub archives: HashMap<EncodingKey, ArchiveFetch>,
+    pub loose: Vec<EncodingKey>,
+    /// Content keys with no entry in the encoding table
+    pub missing: Vec<ContentKey>,
+}
+
+#[derive(Debug, Default)]
+pub struct ArchiveFetch {
+    pub entries: Vec<(EncodingKey, index::Entry)>,
+    pub total_bytes: u64,
+}
+
+/// Works out which archives, and which ranges within them, hold the given files.
+/// Files found in no archive are fetched loose. Each encoded file is only planned once.
+pub fn plan_fetch(
+    files: &[ContentKey],
+    encoding: &Encoding,
+    archived_files: &ArchivedFiles,
+) -> FetchPlan {
+    let mut missing = vec![];
+    let ekeys: Vec<_> = files
+        .iter()
+        .filter_map(|ckey| {
+            let ekey = encoding
+                .lookup_by_ckey(ckey)
+                .and_then(|ce_entry| ce_entry.ekeys.first());
+            if ekey.is_none() {
+                missing.push(ckey.clone());
+            }
+            ekey.cloned()
+        })
+        .collect();
+
+    let mut plan = plan_fetch_encoded(&ekeys, archived_files);
+    plan.missing = missing;
+    plan
+}
+
+/// Like `plan_fetch`, for files already known by encoding key, like download manifest entries
+pub fn plan_fetch_encoded(files: &[EncodingKey], archived_files: &ArchivedFiles) -> FetchPlan {
+    let mut plan = FetchPlan::default();
+    let mut seen = HashSet::new();
+
+    for ekey in files {
+        if !seen.insert(ekey) {
+            continue;
+        }
+
+        if let Some((archive, entry)) = archived_files.get(ekey) {
+            let fetch = plan.archives.entry(archive.clone()).or_default();
+            fetch.entries.push((ekey.clone(), entry.clone()));
+            fetch.total_bytes += entry.size;
+        } else {
+            plan.loose.push(ekey.clone());
+        }
+    }
+
+    plan
+}
+
+/// How much of an archive a fetch plan actually needs
+#[derive(Debug, Clone)]
+pub struct ArchiveEfficiency {
+    pub archive: EncodingKey,
+    pub needed_bytes: u64,
+    pub archive_size: u64,
+    /// Fraction of the archive that would be downloaded for nothing if fetched whole
+    pub waste: f64,
+}
+
+impl ArchiveEfficiency {
+    pub fn new(archive: EncodingKey, needed_bytes: u64, archive_size: u64) -> Self {
+        let waste = if archive_size == 0 {
+            0.0
+        } else {
+            1.0 - needed_bytes as f64 / archive_size as f64
+        };
+        ArchiveEfficiency {
+            archive,
+            needed_bytes,
+            archive_size,
+            waste,
+        }
+    }
+}
+
+/// Waste of every archive in a plan, most wasteful first. Archives missing from `archive_sizes`
+/// are assumed to be exactly what's needed.
+pub fn archive_efficiency(
+    plan: &FetchPlan,
+    archive_sizes: &HashMap<EncodingKey, u64>,
+) -> Vec<ArchiveEfficiency> {
+    let mut res: Vec<_> = plan
+        .archives
+        .iter()
+        .map(|(archive, fetch)| {
+            let size = archive_sizes
+                .get(archive)
+                .copied()
+                .unwrap_or(fetch.total_bytes);
+            ArchiveEfficiency::new(archive.clone(), fetch.total_bytes, size)
+        })
+        .collect();
+    res.sort_by(|a, b| b.waste.total_cmp(&a.waste));
+    res
+}
+
+/// Bytes downloaded per byte needed, if every archive were fetched whole
+pub fn overall_fetch_ratio(efficiencies: &[ArchiveEfficiency]) -> f64 {
+    let needed: u64 = efficiencies.iter().map(|e| e.needed_bytes).sum();
+    let fetched: u64 = efficiencies.iter().map(|e| e.archive_size).sum();
+    if needed == 0 {
+        1.0
+    } else {
+        fetched as f64 / needed as f64
+    }
+}
+
+/// Reads files from a CDN, using archive ranges where the file is archived, and loose reads otherwise
+pub struct CDNSource<'a> {
+    cdn: &'a CDNClient,
+    encoding: &'a Encoding,
+    tact_keys: &'a TactKeys,
+    archived_files: Option<&'a ArchivedFiles>,
+}
+
+impl<'a> CDNSource<'a> {
+    pub fn new(cdn: &'a CDNClient, encoding: &'a Encoding, tact_keys: &'a TactKeys) -> Self {
+        CDNSource {
+            cdn,
+            encoding,
+            tact_keys,
+            archived_files: None,
+        }
+    }
+
+    pub fn with_archived_files(mut self, archived_files: &'a ArchivedFiles) -> Self {
+        self.archived_files = Some(archived_files);
+        self
+    }
+}
+
+impl ContentSource for CDNSource<'_> {
+    fn read_by_ckey(&self, ckey: &ContentKey) -> Result<Vec<u8>, NgdpError> {
+        let ce_entry = self
+            .encoding
+            .lookup_by_ckey(ckey)
+            .ok_or_else(|| NgdpError::KeyNotInEncoding(ckey.clone()))?;
+
+        // Any encoding of the content will do, the CDN may not have all of them
+        let mut res = Err(NgdpError::KeyNotInEncoding(ckey.clone()));
+        for ekey in &ce_entry.ekeys {
+            res = self.read_by_ekey(ekey);
+            if res.is_ok() {
+                break;
+            }
+        }
+        res
+    }
+
+    fn read_by_ekey(&self, ekey: &EncodingKey) -> Result<Vec<u8>, NgdpError> {
+        let archived = self.archived_files.and_then(|files| files.get(ekey));
+        let data = if let Some((archive, entry)) = archived {
+            let size = entry.size as usize;
+            self.cdn
+                .read_data_part(archive, entry.offset as usize, size)
+                .map_err(NgdpError::network)?
+                .read_vec_limited(size, size)
+                .map_err(NgdpError::network)?
+        } else {
+            let mut reader = self.cdn.read_data(ekey).map_err(NgdpError::network)?;
+            match self.encoding.lookup_encoded_size(ekey) {
+                Some(size) => {
+                    let size = size as usize;
+                    reader.read_vec_limited(size, size)
+                }
+                None => reader.read_vec(0),
+            }
+            .map_err(NgdpError::network)?
+        };
+        decode_blte(self.tact_keys, &data)
+    }
+}
+
+/// Fetches a single object from the CDN and decodes it.
+/// Errors if the object isn't exactly `hs.size` bytes, or needs an encryption key we don't have.
+pub fn read_cdn_object(
+    cdn: &CDNClient,
+    tact_keys: &TactKeys,
+    hs: &HashSize<EncodingKey>,
+) -> Result<Vec<u8>, NgdpError> {
+    let data = cdn
+        .read_data(&hs.hash)
+        .map_err(NgdpError::network)?
+        .read_vec_limited(hs.size, hs.size)
+        .map_err(NgdpError::network)?;
+    if data.len() != hs.size {
+        return Err(NgdpError::CorruptBlte(format!(
+            "object {:?} was {} bytes, expected {}",
+            hs.hash,
+            data.len(),
+            hs.size
+        )));
+    }
+
+    let missing_keys: BTreeSet<_> = describe(&data)?
+        .chunks
+        .into_iter()
+        .filter_map(|chunk| chunk.key_name)
+        .filter(|key_name| tact_keys.get_key(key_name).is_none())
+        .collect();
+    if !missing_keys.is_empty() {
+        return Err(NgdpError::MissingEncryptionKey(missing_keys));
+    }
+
+    decode_blte(tact_keys, &data)
+}
+
+/// Fetches the still encoded file named by a build config entry. Entries without an encoded key
+/// are looked up in `encoding` if given, otherwise they're fetched by content key, which the CDN also serves.
+pub fn fetch_encoded_pair(
+    cdn: &CDNClient,
+    pair: &EncodedPair,
+    encoding: Option<&Encoding>,
+) -> Result<Vec<u8>, NgdpError> {
+    if let Some(hs) = &pair.encoded {
+        return cdn
+            .read_data(&hs.hash)
+            .map_err(NgdpError::network)?
+            .read_vec_limited(hs.size, hs.size)
+            .map_err(NgdpError::network);
+    }
+
+    let ekey = encoding
+        .and_then(|encoding| encoding.lookup_by_ckey(&pair.decoded.hash))
+        .and_then(|ce_entry| ce_entry.ekeys.first().cloned())
+        .unwrap_or_else(|| pair.decoded.hash.clone().unencoded());
+    let mut reader = cdn.read_data(&ekey).map_err(NgdpError::network)?;
+    match encoding.and_then(|encoding| encoding.lookup_encoded_size(&ekey)) {
+        Some(size) => reader.read_vec_limited(size as usize, size as usize),
+        None => reader.read_vec_limited(0, max_encoded_size(pair.decoded.size)),
+    }
+    .map_err(NgdpError::network)
+}
+
+/// Bound on the size of a BLTE file when only its decoded size is known. Encoded files are
+/// normally smaller, but chunk headers and incompressible chunks can add a little on top.
+fn max_encoded_size(decoded_size: usize) -> usize {
+    decoded_size.saturating_mul(2).saturating_add(4096)
+}
+
+/// Like `read_cdn_object`, but for a build config entry that may lack an encoded key, see `fetch_encoded_pair`
+pub fn read_cdn_pair(
+    cdn: &CDNClient,
+    tact_keys: &TactKeys,
+    pair: &EncodedPair,
+    encoding: Option<&Encoding>,
+) -> Result<Vec<u8>, NgdpError> {
+    if let Some(hs) = &pair.encoded {
+        return read_cdn_object(cdn, tact_keys, hs);
+    }
+
+    let data = decode_blte(tact_keys, &fetch_encoded_pair(cdn, pair, encoding)?)?;
+    if ContentKey::from_data(&data) != pair.decoded.hash {
+        return Err(NgdpError::CorruptBlte(format!(
+            "decoded file doesn't match content key {:?}",
+            pair.decoded.hash
+        )));
+    }
+    Ok(data)
+}
+
+/// Tries reading from the first source, falling back to the second if that fails
+pub struct Layered<A, B>(pub A, pub B);
+
+impl<A: ContentSource, B: ContentSource> ContentSource for Layered<A, B> {
+    fn read_by_ckey(&self, ckey: &ContentKey) -> Result<Vec<u8>, NgdpError> {
+        self.0.read_by_ckey(ckey).or_else(|first| {
+            self.1
+                .read_by_ckey(ckey)
+                .map_err(|second| both_failed(first, second))
+        })
+    }
+
+    fn read_by_ekey(&self, ekey: &EncodingKey) -> Result<Vec<u8>, NgdpError> {
+        self.0.read_by_ekey(ekey).or_else(|first| {
+            self.1
+                .read_by_ekey(ekey)
+                .map_err(|second| both_failed(first, second))
+        })
+    }
+}
+
+fn both_failed(first: NgdpError, second: NgdpError) -> NgdpError {
+    NgdpError::BothSourcesFailed {
+        first: Box::new(first),
+        second: Box::new(second),
+    }
+}
+
+#[cfg(test)]
+use crate::{
+    blte::{encode_blte, espec::ESpec},
+    casc::builder::{write_encoding_entries, EncodingEntries, ESPEC},
+};
+
+/// In-memory source for tests. Files are BLTE encoded into a single archive and read back through
+/// an encoding table and archive index, the same way `CDNSource` resolves them.
+#[cfg(test)]
+pub(crate) struct MemorySource {
+    entries: EncodingEntries,
+    encoding: Encoding,
+    archive: Vec<u8>,
+    index: HashMap<EncodingKey, index::Entry>,
+    tact_keys: TactKeys,
+}
+
+#[cfg(test)]
+impl Default for MemorySource {
+    fn default() -> Self {
+        let mut entries = EncodingEntries::default();
+        entries.especs.push(ESPEC.to_string());
+        MemorySource {
+            encoding: build_encoding(&entries),
+            entries,
+            archive: vec![],
+            index: HashMap::new(),
+            tact_keys: TactKeys::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+fn build_encoding(entries: &EncodingEntries) -> Encoding {
+    let data = write_encoding_entries(entries).unwrap();
+    crate::tact::encoding::parse_encoding(&data, true).unwrap()
+}
+
+#[cfg(test)]
+impl MemorySource {
+    /// Registers `data` under its content key, adding it to both the encoding table and the archive
+    pub fn with_file(mut self, data: &[u8]) -> Self {
+        let ekey = self.store(data);
+        let ckey = ContentKey::from_data(data);
+        self.entries
+            .by_ckey
+            .entry(ckey)
+            .or_insert_with(|| (data.len() as u64, vec![]))
+            .1
+            .push(ekey);
+        self.rebuild_encoding();
+        self
+    }
+
+    /// Lists `ekey` as the first encoding of `data` in the encoding table without storing it,
+    /// like a CDN that's missing one of a file's encodings
+    pub fn with_missing_encoding(mut self, ekey: EncodingKey, data: &[u8]) -> Self {
+        let ckey = ContentKey::from_data(data);
+        self.entries
+            .by_ckey
+            .entry(ckey)
+            .or_insert_with(|| (data.len() as u64, vec![]))
+            .1
+            .insert(0, ekey.clone());
+        self.entries.by_ekey.insert(ekey, (0, data.len() as u64));
+        self.rebuild_encoding();
+        self
+    }
+
+    /// Stores `data` in the archive under the given encoding key, leaving the encoding table alone
+    pub fn with_ekey(mut self, ekey: EncodingKey, data: &[u8]) -> Self {
+        let blte = self.encode(data);
+        self.insert_blte(ekey, blte);
+        self
+    }
+
+    fn encode(&self, data: &[u8]) -> Vec<u8> {
+        let espec: ESpec = ESPEC.parse().unwrap();
+        encode_blte(&self.tact_keys, &espec, data).unwrap()
+    }
+
+    fn store(&mut self, data: &[u8]) -> EncodingKey {
+        let blte = self.encode(data);
+        let ekey = EncodingKey::from_blte(&blte).unwrap();
+        self.entries
+            .by_ekey
+            .insert(ekey.clone(), (0, blte.len() as u64));
+        self.insert_blte(ekey.clone(), blte);
+        ekey
+    }
+
+    fn insert_blte(&mut self, ekey: EncodingKey, blte: Vec<u8>) {
+        let entry = index::Entry {
+            size: blte.len() as u64,
+            offset: self.archive.len() as u64,
+        };
+        self.archive.extend_from_slice(&blte);
+        self.index.insert(ekey, entry);
+    }
+
+    fn rebuild_encoding(&mut self) {
+        self.encoding = build_encoding(&self.entries);
+    }
+}
+
+#[cfg(test)]
+impl ContentSource for MemorySource {
+    fn read_by_ckey(&self, ckey: &ContentKey) -> Result<Vec<u8>, NgdpError> {
+        let ce_entry = self
+            .encoding
+            .lookup_by_ckey(ckey)
+            .ok_or_else(|| NgdpError::KeyNotInEncoding(ckey.clone()))?;
+
+        let mut res = Err(NgdpError::KeyNotInEncoding(ckey.clone()));
+        for ekey in &ce_entry.ekeys {
+            res = self.read_by_ekey(ekey);
+            if res.is_ok() {
+                break;
+            }
+        }
+        res
+    }
+
+    fn read_by_ekey(&self, ekey: &EncodingKey) -> Result<Vec<u8>, NgdpError> {
+        let entry = self
+            .index
+            .get(ekey)
+            .ok_or_else(|| NgdpError::IndexMiss(ekey.clone()))?;
+        let data = &self.archive[entry.offset as usize..][..entry.size as usize];
+        decode_blte(&self.tact_keys, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blte::espec::ESpec,
+        casc::builder::{write_encoding, EncodedFile, ESPEC},
+        tact::encoding::parse_encoding,
+    };
+
+    #[test]
+    fn test_layered_fallback() {
+        let ekey = EncodingKey::from_slice(&[0xab; 16]);
+        let first = MemorySource::default().with_file(b"first");
+        let second = MemorySource::default()
+            .with_file(b"first")
+            .with_file(b"second")
+            .with_ekey(ekey.clone(), b"by ekey");
+        let layered = Layered(&first, &second);
+
+        let read = |data: &[u8]| layered.read_by_ckey(&ContentKey::from_data(data));
+        assert_eq!(b"first".as_slice(), read(b"first").unwrap());
+        assert_eq!(b"second".as_slice(), read(b"second").unwrap());
+        assert_eq!(b"by ekey".as_slice(), layered.read_by_ekey(&ekey).unwrap());
+
+        let err = read(b"missing").unwrap_err();
+        assert!(format!("{:#}", err).contains("first source failed"));
+        assert!(matches!(
+            err,
+            NgdpError::BothSourcesFailed { second, .. }
+                if matches!(*second, NgdpError::KeyNotInEncoding(_))
+        ));
+    }
+
+    #[test]
+    fn test_fetch_encoded_pair_limit() {
+        use crate::tact::cdn::{response, serve};
+
+        let decoded = vec![0u8; 100];
+        let pair = EncodedPair {
+            decoded: HashSize {
+                hash: ContentKey::from_data(&decoded),
+                size: decoded.len(),
+            },
+            encoded: None,
+        };
+        let body = vec![0u8; max_encoded_size(decoded.len()) + 1];
+
+        let (server, handle) = serve(vec![response("200 OK", &[], &body)]);
+        let cdn = CDNClient::new(
+            ribbit::CDNS {
+                name: String::from("us"),
+                path: String::from("tpr/wow"),
+                hosts: vec![],
+                servers: vec![server],
+                config_path: String::from("tpr/configs/data"),
+            },
+            None,
+        );
+        let err = fetch_encoded_pair(&cdn, &pair, None).unwrap_err();
+        assert!(format!("{:#}", err).contains("size limit"), "{:#}", err);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_memory_source_resolution() {
+        let missing = EncodingKey::from_slice(&[0xcd; 16]);
+        let source = MemorySource::default()
+            .with_file(b"a")
+            .with_file(b"b")
+            .with_missing_encoding(missing.clone(), b"b");
+
+        // Resolved through the encoding table and the archive index
+        let ce_entry = source
+            .encoding
+            .lookup_by_ckey(&ContentKey::from_data(b"a"))
+            .unwrap();
+        assert_eq!(1, ce_entry.ekeys.len());
+        assert!(source.index.contains_key(&ce_entry.ekeys[0]));
+        assert_eq!(
+            b"a".as_slice(),
+            source.read_by_ckey(&ContentKey::from_data(b"a")).unwrap()
+        );
+
+        // The first encoding isn't stored, so the second one is used
+        let ce_entry = source
+            .encoding
+            .lookup_by_ckey(&ContentKey::from_data(b"b"))
+            .unwrap();
+        assert_eq!(missing, ce_entry.ekeys[0]);
+        assert_eq!(
+            b"b".as_slice(),
+            source.read_by_ckey(&ContentKey::from_data(b"b")).unwrap()
+        );
+        assert!(matches!(
+            source.read_by_ekey(&missing),
+            Err(NgdpError::IndexMiss(_))
+        ));
+
+        // With none of its encodings stored, the index miss is returned
+        let only_missing = MemorySource::default().with_missing_encoding(missing, b"c");
+        assert!(matches!(
+            only_missing.read_by_ckey(&ContentKey::from_data(b"c")),
+            Err(NgdpError::IndexMiss(_))
+        ));
+    }
+
+    #[test]
+    fn test_plan_fetch() {
+        let tact_keys = TactKeys::default();
+        let espec: ESpec = ESPEC.parse().unwrap();
+        let files = (0..4u32)
+            .map(|i| EncodedFile::new(&tact_keys, &espec, &i.to_le_bytes()).unwrap())
+            .collect::<Vec<_>>();
+        let encoding = parse_encoding(&write_encoding(&files).unwrap(), true).unwrap();
+
+        let archive = EncodingKey::from_slice(&[0xaa; 16]);
+        let archived_files = ArchivedFiles::from_iter(files[..2].iter().map(|f| {
+            let entry = index::Entry {
+                size: f.blte.len() as u64,
+                offset: 0,
+            };
+            (f.ekey.clone(), (archive.clone(), entry))
+        }));
+
+        let missing = ContentKey::from_data(b"missing");
+        let mut wanted: Vec<_> = files.iter().map(|f| f.ckey.clone()).collect();
+        wanted.push(files[0].ckey.clone());
+        wanted.push(missing.clone());
+
+        let plan = plan_fetch(&wanted, &encoding, &archived_files);
+        assert_eq!(1, plan.archives.len());
+        let fetch = &plan.archives[&archive];
+        assert_eq!(2, fetch.entries.len());
+        assert_eq!(
+            (files[0].blte.len() + files[1].blte.len()) as u64,
+            fetch.total_bytes
+        );
+        assert_eq!(
+            vec![files[2].ekey.clone(), files[3].ekey.clone()],
+            plan.loose
+        );
+        assert_eq!(vec![missing], plan.missing);
+
+        let archive_sizes = HashMap::from([(archive.clone(), fetch.total_bytes * 4)]);
+        let efficiency = archive_efficiency(&plan, &archive_sizes);
+        assert_eq!(1, efficiency.len());
+        assert_eq!(0.75, efficiency[0].waste);
+        assert_eq!(4.0, overall_fetch_ratio(&efficiency));
+
+        let ekeys: Vec<_> = [3, 0, 3, 1]
+            .iter()
+            .map(|&i| files[i].ekey.clone())
+            .collect();
+        let plan = plan_fetch_encoded(&ekeys, &archived_files);
+        assert_eq!(2, plan.archives[&archive].entries.len());
+        assert_eq!(vec![files[3].ekey.clone()], plan.loose);
+        assert!(plan.missing.is_empty());
+    }
+}
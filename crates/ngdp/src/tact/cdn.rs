@@ -1,13 +1,16 @@
 use std::{
+    collections::HashMap,
     io::Read,
-    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex},
     time::{Duration, Instant},
 };
 
-use anyhow::bail;
+use anyhow::{bail, Context};
+use byteorder::{ByteOrder, BE};
+use md5::{Digest, Md5};
 use reqwest::{
     blocking::{Client, ClientBuilder, Response},
-    header::RANGE,
+    header::{CONTENT_RANGE, RANGE},
 };
 use running_average::RealTimeRunningAverage;
 
@@ -18,18 +21,23 @@ use super::{ContentKey, EncodingKey};
 pub struct CDNClient {
     servers: Vec<String>,
     cdn_path: String,
+    config_path: String,
     cdn_override: Option<String>,
     client: Client,
+    verify_data: bool,
+    server_stats: Mutex<HashMap<String, Arc<Mutex<ServerStats>>>>,
+    limiter: Arc<HostLimiter>,
 }
 
 impl CDNClient {
     pub fn new(cdns: ribbit::CDNS, cdn_override: Option<String>) -> CDNClient {
-        let mut servers = vec![];
+        let mut servers: Vec<String> = vec![];
         for server in cdns.servers {
-            if let Some(query_pos) = server.find('?') {
-                let (server, _) = server.split_at(query_pos);
-                servers.push(server.to_string());
-            } else {
+            let server = match server.find('?') {
+                Some(query_pos) => server.split_at(query_pos).0.to_string(),
+                None => server,
+            };
+            if !servers.contains(&server) {
                 servers.push(server);
             }
         }
@@ -37,40 +45,74 @@ impl CDNClient {
         CDNClient {
             servers,
             cdn_path: cdns.path,
+            config_path: cdns.config_path,
             cdn_override,
             client: ClientBuilder::new()
                 .tcp_keepalive(Duration::from_secs(60))
                 .build()
                 .unwrap(),
+            verify_data: false,
+            server_stats: Mutex::new(HashMap::new()),
+            limiter: Arc::new(HostLimiter::new(Self::DEFAULT_MAX_CONNECTIONS_PER_HOST)),
         }
     }
 
-    pub fn rank_servers(&mut self, key: &EncodingKey) -> Result<(), anyhow::Error> {
-        let mut buf = vec![0; 16 * 1024 * 1024];
-        let mut servers = vec![];
+    pub const DEFAULT_MAX_CONNECTIONS_PER_HOST: usize = 6;
 
-        let path = self.data_path(key);
-        for server in &self.servers {
-            let url = format!("{}{}", server, path.display());
+    /// Caps how many requests can be in flight to each server at once, counting until each
+    /// returned reader is dropped. Further requests go to the best ranked server with room, waiting
+    /// for one to free up if none has. As readers hold their slot, a single thread keeping `max`
+    /// readers open to every server blocks forever on its next request.
+    pub fn set_max_connections_per_host(&mut self, max: usize) {
+        self.limiter = Arc::new(HostLimiter::new(max.max(1)));
+    }
 
-            let res = || -> Result<(), anyhow::Error> {
-                let resp = self.client.get(&url).send()?;
-                if resp.status().is_success() {
-                    let mut reader = CDNReader::new(resp);
+    /// Looks up the CDNs of `product` in `region` and creates a client for them
+    pub fn from_ribbit(
+        server: ribbit::Server,
+        region: &str,
+        product: &str,
+        cdn_override: Option<String>,
+    ) -> Result<CDNClient, anyhow::Error> {
+        let cdns = ribbit::cdns(server, product)?;
+        CDNClient::from_all_regions(&cdns, region, cdn_override)
+            .with_context(|| format!("looking up cdns of {}", product))
+    }
 
-                    let start = Instant::now();
-                    reader.read_exact(&mut buf)?;
+    /// Creates a client for `region`'s servers, falling back to every other region's servers.
+    /// Content is addressed by key so any region will do, but regions with a different path are skipped.
+    pub fn from_all_regions(
+        cdns_list: &[ribbit::CDNS],
+        region: &str,
+        cdn_override: Option<String>,
+    ) -> Result<CDNClient, anyhow::Error> {
+        Ok(CDNClient::new(
+            merge_regions(cdns_list, region)?,
+            cdn_override,
+        ))
+    }
 
-                    let duration = start.elapsed();
-                    servers.push((server.clone(), duration));
-                } else {
-                    bail!("404 fetching file: {}", url);
-                }
-                Ok(())
-            }();
+    /// Ranks servers by probing all of them at once with a small range request, falling back to
+    /// reading a larger chunk from servers that don't support ranges
+    pub fn rank_servers(&mut self, key: &EncodingKey) -> Result<(), anyhow::Error> {
+        let path = self.data_path(key);
+        let this = &*self;
+        let results: Vec<_> = std::thread::scope(|scope| {
+            let probes: Vec<_> = this
+                .servers
+                .iter()
+                .map(|server| (server, scope.spawn(|| this.probe_server(server, &path))))
+                .collect();
+            probes
+                .into_iter()
+                .map(|(server, probe)| (server.clone(), probe.join().unwrap()))
+                .collect()
+        });
 
+        let mut servers = vec![];
+        for (server, res) in results {
             match res {
-                Ok(_) => {}
+                Ok(duration) => servers.push((server, duration)),
                 Err(e) => eprintln!("Error ranking server {}: {}", server, e),
             }
         }
@@ -80,14 +122,100 @@ impl CDNClient {
         Ok(())
     }
 
+    /// Estimated time to fetch `RANK_FULL_SIZE` bytes from a server, from its first byte latency
+    /// and the throughput of a short sample
+    fn probe_server(&self, server: &str, path: &str) -> Result<Duration, anyhow::Error> {
+        const RANK_SAMPLE_SIZE: usize = 256 * 1024;
+        const RANK_FULL_SIZE: usize = 16 * 1024 * 1024;
+
+        let url = join_url(server, path);
+        let range = format!("bytes=0-{}", RANK_SAMPLE_SIZE - 1);
+
+        let permit = self.limiter.acquire(self.host_key(server));
+        let start = Instant::now();
+        let resp = self.client.get(&url).header(RANGE, &range).send()?;
+        if !resp.status().is_success() {
+            bail!("{} fetching file: {}", resp.status(), url);
+        }
+        let partial = resp.status() == reqwest::StatusCode::PARTIAL_CONTENT;
+        let mut reader = CDNReader::new(resp, self.stats_for(server), permit);
+
+        // Range requests unsupported, time reading the full chunk instead
+        if !partial {
+            let mut buf = vec![0; RANK_FULL_SIZE];
+            reader.read_exact(&mut buf)?;
+            return Ok(start.elapsed());
+        }
+
+        let mut buf = vec![0; RANK_SAMPLE_SIZE];
+        let first = reader.read(&mut buf)?;
+        let first_byte = start.elapsed();
+        let mut len = first;
+        while len < buf.len() {
+            match reader.read(&mut buf[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+
+        let sample_duration = start.elapsed() - first_byte;
+        let sample_bytes = len - first;
+        if sample_bytes == 0 || sample_duration.is_zero() {
+            return Ok(first_byte);
+        }
+        let throughput = sample_bytes as f64 / sample_duration.as_secs_f64();
+        Ok(first_byte + Duration::from_secs_f64(RANK_FULL_SIZE as f64 / throughput))
+    }
+
+    /// Servers in the order they'll be tried, along with their recently observed bandwidth in bytes/s.
+    /// The cdn override, if any, always comes first.
+    pub fn server_ranking(&self) -> Vec<(String, Option<f64>)> {
+        let stats = self.server_stats.lock().unwrap();
+        let bandwidth = |server: &String| {
+            stats
+                .get(server)
+                .and_then(|s| s.lock().unwrap().bandwidth())
+        };
+
+        let mut ranking: Vec<_> = self
+            .servers
+            .iter()
+            .map(|server| (server.clone(), bandwidth(server)))
+            .collect();
+        // Stable, so servers without observations keep their latency ranking
+        ranking.sort_by(|(_, a), (_, b)| b.unwrap_or(0.0).total_cmp(&a.unwrap_or(0.0)));
+
+        if let Some(server) = &self.cdn_override {
+            ranking.insert(0, (server.clone(), bandwidth(server)));
+        }
+        ranking
+    }
+
     pub fn read_config(&self, key: &ContentKey) -> Result<CDNReader, anyhow::Error> {
         let path = self.config_path(key);
         self.read(&path)
     }
 
+    /// Reads a product config, which unlike other configs lives under the CDN's config path
+    pub fn read_product_config(&self, key: &ContentKey) -> Result<CDNReader, anyhow::Error> {
+        let path = key_path(&self.config_path, &format_hex_bytes_le(&key.to_inner()), "");
+        self.read(&path)
+    }
+
+    /// When enabled, `read_data` checks the downloaded file against its encoding key, failing the
+    /// read that completes the hashed part of the file on a mismatch. Off by default, as BLTE chunks carry their own checksums.
+    /// Archives aren't named by encoding key, so don't read those with `read_data` while this is enabled.
+    pub fn set_verify_data(&mut self, verify: bool) {
+        self.verify_data = verify;
+    }
+
     pub fn read_data(&self, key: &EncodingKey) -> Result<CDNReader, anyhow::Error> {
         let path = self.data_path(key);
-        self.read(&path)
+        let mut reader = self.read(&path)?;
+        if self.verify_data {
+            reader.verifier = Some(EKeyVerifier::new(key.clone(), reader.content_length()));
+        }
+        Ok(reader)
     }
 
     pub fn read_index(&self, key: &EncodingKey) -> Result<CDNReader, anyhow::Error> {
@@ -105,13 +233,34 @@ impl CDNClient {
         self.read_part(&path, offset, size)
     }
 
-    fn read(&self, path: &Path) -> Result<CDNReader, anyhow::Error> {
+    /// Reads a data file, transparently resuming with range requests if the connection drops midway
+    pub fn read_data_resumable(
+        &self,
+        key: &EncodingKey,
+        max_retries: u32,
+    ) -> Result<ResumableReader<'_>, anyhow::Error> {
+        let path = self.data_path(key);
+        let reader = self.read(&path)?;
+        Ok(ResumableReader {
+            client: self,
+            expected_size: reader.content_length(),
+            path,
+            received: 0,
+            retries_left: max_retries,
+            reader,
+        })
+    }
+
+    fn read(&self, path: &str) -> Result<CDNReader, anyhow::Error> {
         let mut last_error = anyhow::anyhow!("No CDNs defined");
-        for server in self.servers() {
-            let url = self.cdn_url(server, path);
+        let mut untried = self.servers();
+        while let Some((server, permit)) = self.acquire_any(&mut untried) {
+            let url = self.cdn_url(&server, path);
             let resp = self.client.get(url).send();
             match resp {
-                Ok(resp) if resp.status().is_success() => return Ok(CDNReader::new(resp)),
+                Ok(resp) if resp.status().is_success() => {
+                    return Ok(CDNReader::new(resp, self.stats_for(&server), permit))
+                }
                 _ => {
                     if let Err(e) = resp {
                         last_error = e.into();
@@ -126,100 +275,771 @@ impl CDNClient {
 
     fn read_part(
         &self,
-        path: &Path,
+        path: &str,
         offset: usize,
         size: usize,
     ) -> Result<CDNReader, anyhow::Error> {
-        for server in self.servers() {
-            let url = self.cdn_url(server, path);
-            let resp = self
-                .client
-                .get(url)
-                .header(RANGE, format!("bytes={}-{}", offset, offset + size))
-                .send()?;
-            if resp.status().is_success() {
-                return Ok(CDNReader::new(resp));
-            } else {
-                // Try next cdn
+        let end = (offset + size).saturating_sub(1);
+        self.read_range(path, format!("bytes={}-{}", offset, end), None)
+    }
+
+    /// Requests `range` from each server in turn. With `resume_from` set, only a partial response
+    /// starting at that offset is accepted, as anything else would repeat or skip bytes.
+    fn read_range(
+        &self,
+        path: &str,
+        range: String,
+        resume_from: Option<u64>,
+    ) -> Result<CDNReader, anyhow::Error> {
+        let mut last_error = anyhow::anyhow!("No CDNs defined");
+        let mut untried = self.servers();
+        while let Some((server, permit)) = self.acquire_any(&mut untried) {
+            let url = self.cdn_url(&server, path);
+            let resp = match self.client.get(&url).header(RANGE, &range).send() {
+                Ok(resp) => resp,
+                Err(e) => {
+                    last_error = e.into();
+                    continue;
+                }
+            };
+            if !resp.status().is_success() {
+                last_error = anyhow::anyhow!("{} fetching file: {}", resp.status(), url);
                 continue;
             }
+            if let Some(start) = resume_from {
+                if let Err(e) = check_resumed(&resp, start) {
+                    last_error = e.context(url);
+                    continue;
+                }
+            }
+            return Ok(CDNReader::new(resp, self.stats_for(&server), permit));
         }
-        bail!("404 fetching file: {}", path.display())
+        Err(last_error)
     }
 
-    fn config_path(&self, key: &ContentKey) -> PathBuf {
-        let key = format_hex_bytes_le(&key.to_inner());
-        PathBuf::from(&self.cdn_path)
-            .join("config")
-            .join(&key[0..2])
-            .join(&key[2..4])
-            .join(key)
+    fn config_path(&self, key: &ContentKey) -> String {
+        let prefix = format!("{}/config", self.cdn_path.trim_matches('/'));
+        key_path(&prefix, &format_hex_bytes_le(&key.to_inner()), "")
     }
 
-    fn data_path(&self, key: &EncodingKey) -> PathBuf {
-        let key = format_hex_bytes_le(&key.to_inner());
-        PathBuf::from(&self.cdn_path)
-            .join("data")
-            .join(&key[0..2])
-            .join(&key[2..4])
-            .join(key)
+    fn data_path(&self, key: &EncodingKey) -> String {
+        let prefix = format!("{}/data", self.cdn_path.trim_matches('/'));
+        key_path(&prefix, &format_hex_bytes_le(&key.to_inner()), "")
     }
 
-    fn index_path(&self, key: &EncodingKey) -> PathBuf {
-        let key = format_hex_bytes_le(&key.to_inner());
-        PathBuf::from(&self.cdn_path)
-            .join("data")
-            .join(&key[0..2])
-            .join(&key[2..4])
-            .join(format!("{}.index", key))
+    fn index_path(&self, key: &EncodingKey) -> String {
+        let prefix = format!("{}/data", self.cdn_path.trim_matches('/'));
+        key_path(&prefix, &format_hex_bytes_le(&key.to_inner()), ".index")
     }
 
-    fn servers(&self) -> impl Iterator<Item = &String> {
-        self.cdn_override.iter().chain(self.servers.iter())
+    fn servers(&self) -> Vec<String> {
+        self.server_ranking()
+            .into_iter()
+            .map(|(server, _)| server)
+            .collect()
     }
 
-    fn cdn_url(&self, server: &str, path: &Path) -> String {
+    /// Takes a slot on the first of `servers` with room, removing it from the list.
+    /// Waits for a slot on any of them if they're all at their limit.
+    fn acquire_any(&self, servers: &mut Vec<String>) -> Option<(String, HostPermit)> {
+        if servers.is_empty() {
+            return None;
+        }
+        let hosts: Vec<_> = servers.iter().map(|s| self.host_key(s)).collect();
+        let (index, permit) = self.limiter.acquire_any(&hosts);
+        Some((servers.remove(index), permit))
+    }
+
+    /// What a server's connections are counted under, the override if there is one
+    fn host_key<'a>(&'a self, server: &'a str) -> &'a str {
+        self.cdn_override.as_deref().unwrap_or(server)
+    }
+
+    fn stats_for(&self, server: &str) -> Arc<Mutex<ServerStats>> {
+        let server = self.cdn_override.as_deref().unwrap_or(server);
+        self.server_stats
+            .lock()
+            .unwrap()
+            .entry(server.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(ServerStats::new())))
+            .clone()
+    }
+
+    fn cdn_url(&self, server: &str, path: &str) -> String {
         let server = self.cdn_override.as_deref().unwrap_or(server);
-        format!("{}{}", server, path.display())
+        join_url(server, path)
+    }
+}
+
+/// Path of a file under `prefix`, e.g. `tpr/wow/data/ab/cd/abcd...`
+fn key_path(prefix: &str, key: &str, suffix: &str) -> String {
+    format!(
+        "{}/{}/{}/{}{}",
+        prefix.trim_matches('/'),
+        &key[0..2],
+        &key[2..4],
+        key,
+        suffix
+    )
+}
+
+/// Joins a server and a path with exactly one slash between them
+fn join_url(server: &str, path: &str) -> String {
+    format!(
+        "{}/{}",
+        server.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    )
+}
+
+/// Bandwidth observed from a single server, measured as bytes received per second spent reading
+struct ServerStats {
+    bytes: RealTimeRunningAverage<f32>,
+    busy_secs: RealTimeRunningAverage<f32>,
+    last_bandwidth: Option<f64>,
+}
+
+impl ServerStats {
+    fn new() -> Self {
+        ServerStats {
+            bytes: RealTimeRunningAverage::new(Duration::from_secs(30)),
+            busy_secs: RealTimeRunningAverage::new(Duration::from_secs(30)),
+            last_bandwidth: None,
+        }
+    }
+
+    fn record(&mut self, bytes: usize, elapsed: Duration) {
+        self.bytes.insert(bytes as f32);
+        self.busy_secs.insert(elapsed.as_secs_f32());
+    }
+
+    /// Bandwidth over the last 30 seconds, or the last known bandwidth if the server has been idle since
+    fn bandwidth(&mut self) -> Option<f64> {
+        let busy_secs = *self.busy_secs.measurement().value();
+        if busy_secs > 0.0 {
+            let bytes = *self.bytes.measurement().value();
+            self.last_bandwidth = Some(bytes as f64 / busy_secs as f64);
+        }
+        self.last_bandwidth
+    }
+}
+
+/// Limits the number of requests in flight to each host
+struct HostLimiter {
+    max_per_host: usize,
+    in_flight: Mutex<HashMap<String, usize>>,
+    released: Condvar,
+}
+
+impl HostLimiter {
+    fn new(max_per_host: usize) -> HostLimiter {
+        HostLimiter {
+            max_per_host,
+            in_flight: Mutex::new(HashMap::new()),
+            released: Condvar::new(),
+        }
+    }
+
+    #[cfg(test)]
+    fn has_capacity(&self, host: &str) -> bool {
+        let in_flight = self.in_flight.lock().unwrap();
+        in_flight.get(host).copied().unwrap_or(0) < self.max_per_host
+    }
+
+    /// Waits until `host` has a free slot and takes it
+    fn acquire(self: &Arc<Self>, host: &str) -> HostPermit {
+        self.acquire_any(&[host]).1
+    }
+
+    /// Takes a slot on the first of `hosts` with room, waiting until any of them has one.
+    /// Returns the index of the host the slot is for.
+    fn acquire_any(self: &Arc<Self>, hosts: &[&str]) -> (usize, HostPermit) {
+        assert!(!hosts.is_empty());
+        let mut in_flight = self.in_flight.lock().unwrap();
+        loop {
+            let free = hosts
+                .iter()
+                .position(|host| in_flight.get(*host).copied().unwrap_or(0) < self.max_per_host);
+            if let Some(index) = free {
+                let host = hosts[index];
+                *in_flight.entry(host.to_string()).or_default() += 1;
+                let permit = HostPermit {
+                    limiter: self.clone(),
+                    host: host.to_string(),
+                };
+                return (index, permit);
+            }
+            in_flight = self.released.wait(in_flight).unwrap();
+        }
+    }
+}
+
+/// A slot for one request to a host, freed on drop
+struct HostPermit {
+    limiter: Arc<HostLimiter>,
+    host: String,
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        let mut in_flight = self.limiter.in_flight.lock().unwrap();
+        if let Some(count) = in_flight.get_mut(&self.host) {
+            *count -= 1;
+        }
+        self.limiter.released.notify_all();
     }
 }
 
 pub struct CDNReader {
     resp: Response,
     bandwidth: RealTimeRunningAverage<f32>,
+    verifier: Option<EKeyVerifier>,
+    server_stats: Arc<Mutex<ServerStats>>,
+    permit: Option<HostPermit>,
 }
 
 impl CDNReader {
-    fn new(resp: Response) -> CDNReader {
+    fn new(resp: Response, server_stats: Arc<Mutex<ServerStats>>, permit: HostPermit) -> CDNReader {
         CDNReader {
             resp,
             bandwidth: RealTimeRunningAverage::new(Duration::from_secs(10)),
+            verifier: None,
+            server_stats,
+            permit: Some(permit),
         }
     }
 
     pub fn avg_bandwidth(&mut self) -> f64 {
         self.bandwidth.measurement().rate()
     }
+
+    pub fn content_length(&self) -> Option<u64> {
+        self.resp.content_length()
+    }
 }
 
 impl CDNReader {
+    /// Reads the whole response, erroring if it's shorter than `expected_size` or the response's
+    /// content length. An `expected_size` of 0 means unknown.
     pub fn read_vec(&mut self, expected_size: usize) -> std::io::Result<Vec<u8>> {
         let mut buf = Vec::with_capacity(expected_size);
         self.read_to_end(&mut buf)?;
+        self.check_complete(buf.len(), expected_size)?;
+        Ok(buf)
+    }
+
+    /// Like `read_vec`, but errors if the response is larger than `max_size` bytes.
+    pub fn read_vec_limited(
+        &mut self,
+        expected_size: usize,
+        max_size: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(expected_size.min(max_size));
+        self.take(max_size as u64 + 1).read_to_end(&mut buf)?;
+        if buf.len() > max_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("response exceeded size limit of {} bytes", max_size),
+            ));
+        }
+        self.check_complete(buf.len(), expected_size)?;
         Ok(buf)
     }
 
     pub fn read_string(&mut self) -> std::io::Result<String> {
         let mut buf = String::new();
         self.read_to_string(&mut buf)?;
+        self.check_complete(buf.len(), 0)?;
         Ok(buf)
     }
+
+    fn check_complete(&self, read: usize, expected_size: usize) -> std::io::Result<()> {
+        check_complete(read, expected_size, self.content_length())
+    }
+}
+
+/// Catches connections closed early, which `read_to_end` otherwise treats as a normal EOF
+pub(crate) fn check_complete(
+    read: usize,
+    expected_size: usize,
+    content_length: Option<u64>,
+) -> std::io::Result<()> {
+    let expected = content_length.unwrap_or(0).max(expected_size as u64);
+    if (read as u64) < expected {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::UnexpectedEof,
+            format!("response ended early, got {} of {} bytes", read, expected),
+        ));
+    }
+    Ok(())
 }
 
 impl Read for CDNReader {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let start = Instant::now();
         let res = self.resp.read(buf)?;
         self.bandwidth.insert(res as f32);
+        self.server_stats
+            .lock()
+            .unwrap()
+            .record(res, start.elapsed());
+
+        // Callers using `read_exact` never see EOF, so check as soon as the hashed part is in
+        if let Some(verifier) = &mut self.verifier {
+            verifier.update(&buf[..res]);
+            if verifier.is_complete() || (res == 0 && !buf.is_empty()) {
+                self.verifier.take().unwrap().finish()?;
+            }
+        }
         Ok(res)
     }
 }
+
+/// Hashes a BLTE stream the way its encoding key is computed:
+/// the whole file for chunkless files, only the header for chunked ones.
+/// Without a content length, a chunkless file is only checked at EOF.
+struct EKeyVerifier {
+    expected: EncodingKey,
+    hasher: Md5,
+    header: Vec<u8>,
+    position: u64,
+    hash_len: u64,
+}
+
+impl EKeyVerifier {
+    fn new(expected: EncodingKey, total_len: Option<u64>) -> Self {
+        EKeyVerifier {
+            expected,
+            hasher: Md5::new(),
+            header: Vec::with_capacity(8),
+            position: 0,
+            hash_len: total_len.unwrap_or(u64::MAX),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.header.len() == 8 && self.position >= self.hash_len
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        if self.header.len() < 8 {
+            let needed = (8 - self.header.len()).min(data.len());
+            self.header.extend_from_slice(&data[..needed]);
+            if self.header.len() == 8 {
+                let header_size = BE::read_u32(&self.header[4..8]) as u64;
+                if header_size > 0 {
+                    self.hash_len = header_size;
+                }
+            }
+        }
+
+        let remaining = self.hash_len.saturating_sub(self.position);
+        let hashed = (remaining.min(data.len() as u64)) as usize;
+        self.hasher.update(&data[..hashed]);
+        self.position += data.len() as u64;
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        let computed = EncodingKey::from_slice(&self.hasher.finalize());
+        if computed != self.expected {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "data did not match its key - expected: {:?}, computed: {:?}",
+                    self.expected, computed
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reader returned by `CDNClient::read_data_resumable`
+pub struct ResumableReader<'a> {
+    client: &'a CDNClient,
+    path: String,
+    expected_size: Option<u64>,
+    received: u64,
+    retries_left: u32,
+    reader: CDNReader,
+}
+
+impl ResumableReader<'_> {
+    pub fn avg_bandwidth(&mut self) -> f64 {
+        self.reader.avg_bandwidth()
+    }
+
+    /// Size of the whole file, from the first response
+    pub fn content_length(&self) -> Option<u64> {
+        self.expected_size
+    }
+
+    fn resume(&mut self) -> std::io::Result<()> {
+        self.retries_left -= 1;
+        // The dead connection shouldn't count against the limit while reconnecting
+        self.reader.permit = None;
+        self.reader = self
+            .client
+            .read_range(
+                &self.path,
+                format!("bytes={}-", self.received),
+                Some(self.received),
+            )
+            .map_err(|e| std::io::Error::other(format!("{:#}", e)))?;
+        Ok(())
+    }
+}
+
+impl Read for ResumableReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match self.reader.read(buf) {
+                Ok(0) if !buf.is_empty() && self.expected_size > Some(self.received) => {
+                    // Connection closed before we got everything
+                    if self.retries_left == 0 {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                    self.resume()?;
+                }
+                Ok(n) => {
+                    self.received += n as u64;
+                    return Ok(n);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => {
+                    if self.retries_left == 0 {
+                        return Err(e);
+                    }
+                    self.resume()?;
+                }
+            }
+        }
+    }
+}
+
+/// Checks a response to a resuming range request continues exactly where we left off
+fn check_resumed(resp: &Response, start: u64) -> Result<(), anyhow::Error> {
+    if resp.status() != reqwest::StatusCode::PARTIAL_CONTENT {
+        bail!(
+            "expected a partial response when resuming at {}, got {}",
+            start,
+            resp.status()
+        );
+    }
+    let content_range = resp
+        .headers()
+        .get(CONTENT_RANGE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    if !content_range.starts_with(&format!("bytes {}-", start)) {
+        bail!(
+            "resumed response has content range {:?}, expected it to start at {}",
+            content_range,
+            start
+        );
+    }
+    Ok(())
+}
+
+/// `region`'s CDNS with the hosts and servers of other regions with the same paths appended
+fn merge_regions(cdns_list: &[ribbit::CDNS], region: &str) -> Result<ribbit::CDNS, anyhow::Error> {
+    let mut merged = ribbit::cdns_for_region(cdns_list, region)?.clone();
+    for other in cdns_list {
+        if other.path != merged.path || other.config_path != merged.config_path {
+            continue;
+        }
+        for host in &other.hosts {
+            if !merged.hosts.contains(host) {
+                merged.hosts.push(host.clone());
+            }
+        }
+        for server in &other.servers {
+            if !merged.servers.contains(server) {
+                merged.servers.push(server.clone());
+            }
+        }
+    }
+    Ok(merged)
+}
+
+/// Serves one canned response per connection, returning each request's head
+#[cfg(test)]
+pub(crate) fn serve(responses: Vec<Vec<u8>>) -> (String, std::thread::JoinHandle<Vec<String>>) {
+    use std::io::Write;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let server = format!("http://{}", listener.local_addr().unwrap());
+    let handle = std::thread::spawn(move || {
+        let mut requests = vec![];
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buf = [0; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).unwrap();
+                assert!(n > 0, "client closed the connection");
+                request.extend_from_slice(&buf[..n]);
+            }
+            requests.push(String::from_utf8(request).unwrap().to_lowercase());
+            stream.write_all(&response).unwrap();
+        }
+        requests
+    });
+    (server, handle)
+}
+
+#[cfg(test)]
+pub(crate) fn response(status: &str, headers: &[String], body: &[u8]) -> Vec<u8> {
+    let mut res = format!("HTTP/1.1 {}\r\nconnection: close\r\n", status);
+    for header in headers {
+        res += header;
+        res += "\r\n";
+    }
+    let mut res = (res + "\r\n").into_bytes();
+    res.extend_from_slice(body);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(path: &str, servers: &[&str], cdn_override: Option<&str>) -> CDNClient {
+        CDNClient::new(
+            ribbit::CDNS {
+                name: String::from("us"),
+                path: path.to_string(),
+                hosts: vec![],
+                servers: servers.iter().map(|s| s.to_string()).collect(),
+                config_path: String::from("tpr/configs/data"),
+            },
+            cdn_override.map(String::from),
+        )
+    }
+
+    #[test]
+    fn test_check_complete() {
+        assert!(check_complete(10, 10, None).is_ok());
+        assert!(check_complete(10, 0, Some(10)).is_ok());
+        assert!(check_complete(10, 0, None).is_ok());
+
+        let err = check_complete(5, 10, None).unwrap_err();
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, err.kind());
+        let err = check_complete(5, 0, Some(10)).unwrap_err();
+        assert_eq!(std::io::ErrorKind::UnexpectedEof, err.kind());
+    }
+
+    #[test]
+    fn test_merge_regions() {
+        let cdns = |name: &str, path: &str, servers: &[&str]| ribbit::CDNS {
+            name: name.to_string(),
+            path: path.to_string(),
+            hosts: servers
+                .iter()
+                .map(|s| s.trim_start_matches("http://").to_string())
+                .collect(),
+            servers: servers
+                .iter()
+                .map(|s| format!("{}/?maxhosts=4", s))
+                .collect(),
+            config_path: String::from("tpr/configs/data"),
+        };
+        let cdns_list = vec![
+            cdns("us", "tpr/wow", &["http://us.cdn", "http://shared.cdn"]),
+            cdns("eu", "tpr/wow", &["http://shared.cdn", "http://eu.cdn"]),
+            cdns("cn", "tpr/other", &["http://cn.cdn"]),
+        ];
+
+        let merged = merge_regions(&cdns_list, "eu").unwrap();
+        assert_eq!("eu", merged.name);
+        assert_eq!(vec!["shared.cdn", "eu.cdn", "us.cdn"], merged.hosts);
+
+        let cdn = CDNClient::from_all_regions(&cdns_list, "eu", None).unwrap();
+        assert_eq!(
+            vec!["http://shared.cdn/", "http://eu.cdn/", "http://us.cdn/"],
+            cdn.servers()
+        );
+
+        assert!(merge_regions(&cdns_list, "kr").is_err());
+    }
+
+    #[test]
+    fn test_resume() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let key = EncodingKey::from_slice(&[0x12; 16]);
+
+        // The first response is cut off after 40 bytes, the second picks up from there
+        let (server, handle) = serve(vec![
+            response(
+                "200 OK",
+                &[format!("content-length: {}", data.len())],
+                &data[..40],
+            ),
+            response(
+                "206 Partial Content",
+                &[
+                    format!("content-length: {}", data.len() - 40),
+                    format!("content-range: bytes 40-99/{}", data.len()),
+                ],
+                &data[40..],
+            ),
+        ]);
+        let cdn = client("tpr/wow", &[&server], None);
+        let mut reader = cdn.read_data_resumable(&key, 1).unwrap();
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(data, buf);
+
+        let requests = handle.join().unwrap();
+        assert!(!requests[0].contains("range:"));
+        assert!(requests[1].contains("range: bytes=40-"));
+
+        // A server ignoring the range would send everything again, which must not be appended
+        let (server, handle) = serve(vec![
+            response(
+                "200 OK",
+                &[format!("content-length: {}", data.len())],
+                &data[..40],
+            ),
+            response(
+                "200 OK",
+                &[format!("content-length: {}", data.len())],
+                &data,
+            ),
+        ]);
+        let cdn = client("tpr/wow", &[&server], None);
+        let mut reader = cdn.read_data_resumable(&key, 1).unwrap();
+        let err = reader.read_to_end(&mut vec![]).unwrap_err();
+        assert!(err.to_string().contains("partial response"), "{}", err);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_verify_data() {
+        use crate::{blte::encode_blte, tact::keys::TactKeys};
+
+        let keys = TactKeys::default();
+        let input: Vec<u8> = (0..100u8).collect();
+        for espec in ["n", "b:{16=n,*=z}"] {
+            let data = encode_blte(&keys, &espec.parse().unwrap(), &input).unwrap();
+            let hash_len = match BE::read_u32(&data[4..8]) as usize {
+                0 => data.len(),
+                header_size => header_size,
+            };
+            let key = EncodingKey::from_slice(&Md5::digest(&data[..hash_len]));
+
+            let mut corrupted = data.clone();
+            corrupted[hash_len - 1] ^= 0xff;
+
+            let headers = [format!("content-length: {}", data.len())];
+            let (server, handle) = serve(vec![
+                response("200 OK", &headers, &data),
+                response("200 OK", &headers, &corrupted),
+            ]);
+            let mut cdn = client("tpr/wow", &[&server], None);
+            cdn.set_verify_data(true);
+
+            // `read_exact` stops at the last byte and never sees EOF
+            let mut buf = vec![0; data.len()];
+            cdn.read_data(&key).unwrap().read_exact(&mut buf).unwrap();
+            assert_eq!(data, buf);
+
+            let err = cdn
+                .read_data(&key)
+                .unwrap()
+                .read_exact(&mut buf)
+                .unwrap_err();
+            assert_eq!(std::io::ErrorKind::InvalidData, err.kind(), "{}", espec);
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_host_limiter() {
+        let limiter = Arc::new(HostLimiter::new(2));
+        let a = limiter.acquire("a");
+        let _b = limiter.acquire("a");
+        assert!(!limiter.has_capacity("a"));
+        assert!(limiter.has_capacity("b"));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let waiter = {
+            let limiter = limiter.clone();
+            std::thread::spawn(move || {
+                let _permit = limiter.acquire("a");
+                tx.send(()).unwrap();
+            })
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        drop(a);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        waiter.join().unwrap();
+        assert!(limiter.has_capacity("a"));
+    }
+
+    #[test]
+    fn test_host_limiter_any() {
+        let limiter = Arc::new(HostLimiter::new(1));
+        let (index, a) = limiter.acquire_any(&["a", "b"]);
+        assert_eq!(0, index);
+        // "a" is full, so the next request goes to "b" instead of waiting on "a"
+        let (index, _b) = limiter.acquire_any(&["a", "b"]);
+        assert_eq!(1, index);
+
+        // With both full, whichever frees up first is taken
+        let (tx, rx) = std::sync::mpsc::channel();
+        let waiter = {
+            let limiter = limiter.clone();
+            std::thread::spawn(move || {
+                let (index, _permit) = limiter.acquire_any(&["b", "a"]);
+                tx.send(index).unwrap();
+            })
+        };
+        assert!(rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        drop(a);
+        assert_eq!(1, rx.recv_timeout(Duration::from_secs(5)).unwrap());
+        waiter.join().unwrap();
+    }
+
+    #[test]
+    fn test_join_url() {
+        for server in ["http://cdn.example.com", "http://cdn.example.com/"] {
+            for path in ["tpr/wow/data", "/tpr/wow/data"] {
+                assert_eq!(
+                    "http://cdn.example.com/tpr/wow/data",
+                    join_url(server, path)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_data_url() {
+        let key = EncodingKey::parse("0123456789abcdef0123456789abcdef").unwrap();
+
+        for path in ["tpr/wow", "/tpr/wow/"] {
+            let cdn = client(path, &["http://cdn.example.com/?maxhosts=4"], None);
+            let server = &cdn.servers()[0];
+            assert_eq!(
+                "http://cdn.example.com/tpr/wow/data/01/23/0123456789abcdef0123456789abcdef",
+                cdn.cdn_url(server, &cdn.data_path(&key))
+            );
+            assert_eq!(
+                "http://cdn.example.com/tpr/wow/data/01/23/0123456789abcdef0123456789abcdef.index",
+                cdn.cdn_url(server, &cdn.index_path(&key))
+            );
+        }
+
+        let cdn = client("tpr/wow", &[], Some("http://localhost:8080"));
+        let server = &cdn.servers()[0];
+        assert_eq!(
+            "http://localhost:8080/tpr/wow/config/01/23/0123456789abcdef0123456789abcdef",
+            cdn.cdn_url(
+                server,
+                &cdn.config_path(&ContentKey::from_slice(key.as_slice()))
+            )
+        );
+    }
+}
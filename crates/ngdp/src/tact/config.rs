@@ -1,6 +1,9 @@
-use std::{collections::HashMap, fmt::Debug, iter::FromIterator};
+use std::{collections::HashMap, fmt::Debug};
 
-use super::{ContentKey, EncodingKey};
+use anyhow::Context;
+
+use super::{cdn::CDNClient, ContentKey, EncodingKey};
+use crate::blte::espec::ESpec;
 
 fn parse_rough(config: &str) -> HashMap<&str, &str> {
     let mut res = HashMap::new();
@@ -45,6 +48,35 @@ pub struct BuildConfig<'a> {
     pub build_product: Option<&'a str>,
     pub build_t1_manifest_version: Option<&'a str>,
     pub build_uid: Option<&'a str>,
+    pub vfs_root: Option<EncodedPair>,
+    /// `vfs-N` entries, ordered by N
+    pub vfs: Vec<EncodedPair>,
+}
+
+impl BuildConfig<'_> {
+    /// Checks that everything the installer needs is present, listing all missing fields at once
+    pub fn validate_for_install(&self) -> Result<(), anyhow::Error> {
+        let needed = [
+            ("encoding", &self.encoding),
+            ("install", &self.install),
+            ("download", &self.download),
+        ];
+
+        // Entries with only a content key can still be fetched, see `source::fetch_encoded_pair`
+        let missing: Vec<_> = needed
+            .iter()
+            .filter(|(_, pair)| pair.is_none())
+            .map(|(name, _)| *name)
+            .collect();
+
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "build config is missing fields needed to install: {}",
+                missing.join(", ")
+            );
+        }
+        Ok(())
+    }
 }
 
 pub fn parse_build_config(input: &str) -> Result<BuildConfig, anyhow::Error> {
@@ -69,8 +101,55 @@ pub fn parse_build_config(input: &str) -> Result<BuildConfig, anyhow::Error> {
         .map(ContentKey::parse)
         .collect::<Result<_, _>>()?;
 
+    let vfs_root = match rough.get("vfs-root") {
+        Some(hash) => Some(
+            parse_pair_hashkey(Some(hash), rough.get("vfs-root-size").copied())
+                .ok_or_else(|| anyhow::anyhow!("vfs-root present without vfs-root-size"))?,
+        ),
+        None => None,
+    };
+
+    let mut vfs_indices = rough
+        .keys()
+        .filter_map(|key| key.strip_prefix("vfs-"))
+        .filter_map(|num| num.parse::<usize>().ok())
+        .collect::<Vec<_>>();
+    vfs_indices.sort_unstable();
+
+    let mut vfs = Vec::with_capacity(vfs_indices.len());
+    for (expected, num) in (1..).zip(vfs_indices) {
+        if num != expected {
+            anyhow::bail!(
+                "gap in vfs entries: expected vfs-{}, found vfs-{}",
+                expected,
+                num
+            );
+        }
+
+        let size_key = format!("vfs-{}-size", num);
+        let pair = parse_pair_hashkey(
+            rough.get(format!("vfs-{}", num).as_str()).copied(),
+            rough.get(size_key.as_str()).copied(),
+        )
+        .ok_or_else(|| anyhow::anyhow!("vfs-{} present without {}", num, size_key))?;
+        vfs.push(pair);
+    }
+
+    if let Some(orphan) = rough
+        .keys()
+        .filter_map(|key| key.strip_prefix("vfs-")?.strip_suffix("-size"))
+        .filter(|num| num.parse::<usize>().is_ok())
+        .find(|num| !rough.contains_key(format!("vfs-{}", num).as_str()))
+    {
+        anyhow::bail!("vfs-{}-size present without vfs-{}", orphan, orphan);
+    }
+
     Ok(BuildConfig {
-        root: ContentKey::parse(rough["root"])?,
+        root: ContentKey::parse(
+            rough
+                .get("root")
+                .ok_or_else(|| anyhow::anyhow!("build config has no root"))?,
+        )?,
         install: parse_pair_hashkey(
             rough.get("install").copied(),
             rough.get("install-size").copied(),
@@ -106,11 +185,13 @@ pub fn parse_build_config(input: &str) -> Result<BuildConfig, anyhow::Error> {
         build_product: rough.get("build-product").copied(),
         build_t1_manifest_version: rough.get("build-t1-manifest-version").copied(),
         build_uid: rough.get("build-uid").copied(),
+        vfs_root,
+        vfs,
     })
 }
 
 #[derive(Debug)]
-pub struct CDNConfig<'a> {
+pub struct CDNConfig {
     pub archives: Vec<EncodingKey>,
     pub archives_index_size: Vec<usize>,
     pub archive_group: Option<EncodingKey>,
@@ -119,46 +200,76 @@ pub struct CDNConfig<'a> {
     pub patch_archives_index_size: Vec<usize>,
     pub patch_archive_group: Option<EncodingKey>,
     pub patch_file_index: Option<HashSize<EncodingKey>>,
-    pub builds: Vec<&'a str>,
+    /// Build configs of the builds the CDN still hosts
+    pub builds: Vec<ContentKey>,
 }
 
-pub fn parse_cdn_config(input: &str) -> CDNConfig {
+pub fn parse_cdn_config(input: &str) -> Result<CDNConfig, anyhow::Error> {
     let rough = parse_rough(input);
 
-    // TODO: Assumptions about list lengths are made here
     let archives = rough
         .get("archives")
         .iter()
         .flat_map(|v| v.split(' '))
-        .map(|s| EncodingKey::parse(s).unwrap())
-        .collect();
+        .map(|s| {
+            EncodingKey::parse(s)
+                .with_context(|| format!("parsing archives entry {:?} in cdn config", s))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
     let archives_index_size = rough
         .get("archives-index-size")
         .iter()
         .flat_map(|v| v.split(' '))
-        .map(|v| v.parse().expect("archive index size was not integer"))
-        .collect();
+        .map(|v| {
+            v.parse()
+                .with_context(|| format!("parsing archive index size {:?} in cdn config", v))
+        })
+        .collect::<Result<Vec<usize>, _>>()?;
 
     let patch_archives = rough
         .get("patch-archives")
         .iter()
         .flat_map(|v| v.split(' '))
-        .map(|s| EncodingKey::parse(s).unwrap())
-        .collect();
+        .map(|s| {
+            EncodingKey::parse(s)
+                .with_context(|| format!("parsing patch-archives entry {:?} in cdn config", s))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
 
     let patch_archives_index_size = rough
         .get("patch-archives-index-size")
         .iter()
         .flat_map(|v| v.split(' '))
-        .map(|v| v.parse().expect("patch archive index size was not integer"))
-        .collect();
+        .map(|v| {
+            v.parse()
+                .with_context(|| format!("parsing patch archive index size {:?} in cdn config", v))
+        })
+        .collect::<Result<Vec<usize>, _>>()?;
+
+    check_list_lengths(
+        "archives",
+        &archives,
+        "archives-index-size",
+        &archives_index_size,
+    )?;
+    check_list_lengths(
+        "patch-archives",
+        &patch_archives,
+        "patch-archives-index-size",
+        &patch_archives_index_size,
+    )?;
 
     let builds = rough
         .get("builds")
-        .map_or_else(Vec::new, |v| Vec::from_iter(v.split_ascii_whitespace()));
+        .iter()
+        .flat_map(|v| v.split_ascii_whitespace())
+        .map(|s| {
+            ContentKey::parse(s).with_context(|| format!("parsing build {:?} in cdn config", s))
+        })
+        .collect::<Result<_, _>>()?;
 
-    CDNConfig {
+    Ok(CDNConfig {
         archives,
         archives_index_size,
         archive_group: rough
@@ -179,7 +290,119 @@ pub fn parse_cdn_config(input: &str) -> CDNConfig {
             rough.get("patch-file-index-size").copied(),
         ),
         builds,
+    })
+}
+
+/// Archive lists are paired up with their sizes by position, so they have to line up
+fn check_list_lengths<A, B>(
+    a_name: &str,
+    a: &[A],
+    b_name: &str,
+    b: &[B],
+) -> Result<(), anyhow::Error> {
+    if a.len() != b.len() {
+        anyhow::bail!(
+            "cdn config has {} {} but {} {}",
+            a.len(),
+            a_name,
+            b.len(),
+            b_name
+        );
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct PatchConfig {
+    /// The patch manifest
+    pub patch: HashSize<EncodingKey>,
+    pub entries: Vec<PatchConfigEntry>,
+}
+
+impl PatchConfig {
+    /// Looks up the entry for a build file, e.g. `encoding` or `install`
+    pub fn entry(&self, name: &str) -> Option<&PatchConfigEntry> {
+        self.entries.iter().find(|e| e.name == name)
+    }
+}
+
+/// A `patch-entry` line, describing how a build file is encoded and which patches produce it
+#[derive(Debug)]
+pub struct PatchConfigEntry {
+    pub name: String,
+    pub ckey: ContentKey,
+    pub content_size: usize,
+    pub ekey: EncodingKey,
+    pub encoded_size: usize,
+    pub espec: ESpec,
+    pub patches: Vec<PatchConfigPatch>,
+}
+
+#[derive(Debug)]
+pub struct PatchConfigPatch {
+    pub old_ekey: EncodingKey,
+    pub old_size: usize,
+    pub patch_key: EncodingKey,
+    pub patch_size: usize,
+}
+
+pub fn parse_patch_config(input: &str) -> Result<PatchConfig, anyhow::Error> {
+    let rough = parse_rough(input);
+
+    let patch = parse_hashsize_encoding(
+        rough.get("patch").copied(),
+        rough.get("patch-size").copied(),
+    )
+    .ok_or_else(|| anyhow::anyhow!("missing patch from patch-config"))?;
+
+    // patch-entry is repeated, so parse_rough only keeps the last one
+    let entries = input
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .filter(|(key, _)| key.trim() == "patch-entry")
+        .map(|(_, value)| {
+            parse_patch_entry(value.trim())
+                .with_context(|| format!("parsing patch-entry {:?}", value.trim()))
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(PatchConfig { patch, entries })
+}
+
+fn parse_patch_entry(value: &str) -> Result<PatchConfigEntry, anyhow::Error> {
+    let fields: Vec<_> = value.split_ascii_whitespace().collect();
+    let [name, ckey, content_size, ekey, encoded_size, espec, patches @ ..] = fields.as_slice()
+    else {
+        anyhow::bail!("expected at least 6 fields, got {}", fields.len());
+    };
+    if patches.len() % 4 != 0 {
+        anyhow::bail!("patch fields don't come in groups of 4");
     }
+
+    Ok(PatchConfigEntry {
+        name: name.to_string(),
+        ckey: ContentKey::parse(ckey)?,
+        content_size: content_size.parse()?,
+        ekey: EncodingKey::parse(ekey)?,
+        encoded_size: encoded_size.parse()?,
+        espec: espec.parse()?,
+        patches: patches
+            .chunks(4)
+            .map(|p| {
+                Ok(PatchConfigPatch {
+                    old_ekey: EncodingKey::parse(p[0])?,
+                    old_size: p[1].parse()?,
+                    patch_key: EncodingKey::parse(p[2])?,
+                    patch_size: p[3].parse()?,
+                })
+            })
+            .collect::<Result<_, anyhow::Error>>()?,
+    })
+}
+
+pub fn fetch_patch_config(cdn: &CDNClient, key: &ContentKey) -> Result<PatchConfig, anyhow::Error> {
+    let text = cdn.read_config(key)?.read_string()?;
+    parse_patch_config(&text).with_context(|| format!("parsing patch config {:?}", key))
 }
 
 #[derive(Debug)]
@@ -225,3 +448,169 @@ fn parse_hashsize_encoding(
         size: size.parse().expect("hash size was not a number"),
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cdn_config_builds() {
+        let config = "file-index = 0123456789abcdef0123456789abcdef\nfile-index-size = 10\n";
+        let build = "00112233445566778899aabbccddeeff";
+
+        let parsed =
+            parse_cdn_config(&format!("{}builds = {} {}\n", config, build, build)).unwrap();
+        assert_eq!(vec![ContentKey::parse(build).unwrap(); 2], parsed.builds);
+
+        let err = parse_cdn_config(&format!("{}builds = {} nothex\n", config, build)).unwrap_err();
+        assert!(format!("{:#}", err).contains("parsing build \"nothex\""));
+    }
+
+    #[test]
+    fn test_cdn_config_bad_archives() {
+        let config = "file-index = 0123456789abcdef0123456789abcdef\nfile-index-size = 10\n";
+        let a = "00112233445566778899aabbccddeeff";
+
+        let err = parse_cdn_config(&format!(
+            "{config}archives = {a} nothex\narchives-index-size = 1 2\n"
+        ))
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("parsing archives entry \"nothex\""));
+
+        let err = parse_cdn_config(&format!(
+            "{config}archives = {a}\narchives-index-size = big\n"
+        ))
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("parsing archive index size \"big\""));
+
+        let err = parse_cdn_config(&format!(
+            "{config}patch-archives = {a}\npatch-archives-index-size = -1\n"
+        ))
+        .unwrap_err();
+        assert!(format!("{:#}", err).contains("parsing patch archive index size \"-1\""));
+    }
+
+    #[test]
+    fn test_cdn_config_list_lengths() {
+        let config = "file-index = 0123456789abcdef0123456789abcdef\nfile-index-size = 10\n";
+        let a = "00112233445566778899aabbccddeeff";
+        let b = "ffeeddccbbaa99887766554433221100";
+
+        let parsed = parse_cdn_config(&format!(
+            "{config}archives = {a} {b}\narchives-index-size = 1 2\n"
+        ))
+        .unwrap();
+        assert_eq!(vec![1, 2], parsed.archives_index_size);
+
+        let err = parse_cdn_config(&format!(
+            "{config}archives = {a} {b}\narchives-index-size = 1\n"
+        ))
+        .unwrap_err();
+        assert_eq!(
+            "cdn config has 2 archives but 1 archives-index-size",
+            err.to_string()
+        );
+
+        let err = parse_cdn_config(&format!("{config}patch-archives = {a}\n")).unwrap_err();
+        assert!(err.to_string().contains("0 patch-archives-index-size"));
+    }
+
+    #[test]
+    fn test_build_config_vfs() {
+        let a = "00112233445566778899aabbccddeeff";
+        let b = "ffeeddccbbaa99887766554433221100";
+        let config = format!("root = {a}\n");
+
+        let input = format!(
+            "{config}vfs-root = {a} {b}\nvfs-root-size = 10 8\n\
+             vfs-1 = {a}\nvfs-1-size = 1\nvfs-2 = {b} {a}\nvfs-2-size = 2 3\n"
+        );
+        let parsed = parse_build_config(&input).unwrap();
+        assert_eq!(10, parsed.vfs_root.unwrap().decoded.size);
+        assert_eq!(2, parsed.vfs.len());
+        assert_eq!(1, parsed.vfs[0].decoded.size);
+        assert!(parsed.vfs[0].encoded.is_none());
+        assert_eq!(3, parsed.vfs[1].encoded.as_ref().unwrap().size);
+
+        let err = parse_build_config(&format!(
+            "{config}vfs-1 = {a}\nvfs-1-size = 1\nvfs-3 = {a}\nvfs-3-size = 1\n"
+        ))
+        .unwrap_err();
+        assert_eq!(
+            "gap in vfs entries: expected vfs-2, found vfs-3",
+            err.to_string()
+        );
+
+        let err = parse_build_config(&format!("{config}vfs-1 = {a}\n")).unwrap_err();
+        assert_eq!("vfs-1 present without vfs-1-size", err.to_string());
+
+        let err = parse_build_config(&format!(
+            "{config}vfs-1 = {a}\nvfs-1-size = 1\nvfs-2-size = 1\n"
+        ))
+        .unwrap_err();
+        assert_eq!("vfs-2-size present without vfs-2", err.to_string());
+    }
+
+    #[test]
+    fn test_validate_for_install() {
+        let a = "00112233445566778899aabbccddeeff";
+        let b = "ffeeddccbbaa99887766554433221100";
+
+        let input = format!(
+            "root = {a}\nencoding = {a} {b}\nencoding-size = 10 8\n\
+             install = {a} {b}\ninstall-size = 10 8\ndownload = {a} {b}\ndownload-size = 10 8\n"
+        );
+        assert!(parse_build_config(&input)
+            .unwrap()
+            .validate_for_install()
+            .is_ok());
+
+        // Content keys alone are enough, the encoding table resolves the rest
+        let input = format!(
+            "root = {a}\nencoding = {a}\nencoding-size = 10\n\
+             install = {a}\ninstall-size = 10\ndownload = {a}\ndownload-size = 10\n"
+        );
+        assert!(parse_build_config(&input)
+            .unwrap()
+            .validate_for_install()
+            .is_ok());
+
+        let input = format!("root = {a}\ninstall = {a}\ninstall-size = 10\ndownload = {a}\n");
+        let err = parse_build_config(&input)
+            .unwrap()
+            .validate_for_install()
+            .unwrap_err();
+        assert_eq!(
+            "build config is missing fields needed to install: encoding, download",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn test_parse_patch_config() {
+        let a = "00112233445566778899aabbccddeeff";
+        let b = "ffeeddccbbaa99887766554433221100";
+        let config = format!(
+            "# Patch Configuration\npatch = {a}\npatch-size = 123\n\
+             patch-entry = encoding {a} 1000 {b} 400 b:{{22=n,*=z}}\n\
+             patch-entry = install {b} 50 {a} 60 n {a} 10 {b} 20 {b} 30 {a} 40\n"
+        );
+
+        let parsed = parse_patch_config(&config).unwrap();
+        assert_eq!(123, parsed.patch.size);
+        assert_eq!(2, parsed.entries.len());
+
+        let encoding = parsed.entry("encoding").unwrap();
+        assert_eq!(1000, encoding.content_size);
+        assert_eq!("b:{22=n,*=z}", encoding.espec.to_string());
+        assert!(encoding.patches.is_empty());
+
+        let install = parsed.entry("install").unwrap();
+        assert_eq!(2, install.patches.len());
+        assert_eq!(40, install.patches[1].patch_size);
+
+        let bad = format!("patch = {a}\npatch-size = 1\npatch-entry = install {a} 1 {b} 1 n {a}\n");
+        let err = parse_patch_config(&bad).unwrap_err();
+        assert!(format!("{:#}", err).contains("groups of 4"), "{:#}", err);
+    }
+}
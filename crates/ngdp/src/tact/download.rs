@@ -1,10 +1,13 @@
-use std::{collections::HashSet, io::Cursor};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Cursor,
+};
 
 use binrw::BinRead;
 use bitvec::{prelude::Msb0, vec::BitVec};
 
 use super::{keys::TactKeys, EncodingKey};
-use crate::{blte::decode_blte, util::hexdump};
+use crate::{blte::decode_blte_reporting, util::hexdump};
 
 #[derive(Debug)]
 pub struct DownloadManifest {
@@ -14,6 +17,16 @@ pub struct DownloadManifest {
 }
 
 impl DownloadManifest {
+    /// Tag names grouped by category, in manifest order. Tags in one category are
+    /// alternatives (e.g. locales), a selection should pick from each category
+    pub fn tag_categories(&self) -> BTreeMap<u16, Vec<&str>> {
+        let mut categories: BTreeMap<u16, Vec<&str>> = BTreeMap::new();
+        for tag in &self.tags {
+            categories.entry(tag.type_).or_default().push(&tag.name);
+        }
+        categories
+    }
+
     pub fn entries_with_tags<'a>(
         &'a self,
         tags: &HashSet<String>,
@@ -35,15 +48,30 @@ impl DownloadManifest {
             .filter_map(|(idx, val)| val.then_some(idx))
             .map(|idx| &self.entries[idx])
     }
+
+    /// Like `entries_with_tags`, but also skipping entries with any of the `exclude` flags set
+    pub fn entries_with_tags_and_flags<'a>(
+        &'a self,
+        tags: &HashSet<String>,
+        exclude: DownloadFlags,
+    ) -> impl Iterator<Item = &'a Entry> {
+        self.entries_with_tags(tags)
+            .filter(move |e| !e.attributes().intersects(exclude))
+    }
 }
 
 pub fn parse_download_manifest(
     tact_keys: &TactKeys,
     content: &[u8],
 ) -> Result<DownloadManifest, anyhow::Error> {
-    let content = decode_blte(tact_keys, content)?;
+    let content =
+        decode_blte_reporting(tact_keys, content)?.require_all_keys("download manifest")?;
     hexdump(&content, 0, 256);
+    parse_download_manifest_decoded(&content)
+}
 
+/// Like `parse_download_manifest`, for content that's already been BLTE decoded
+pub fn parse_download_manifest_decoded(content: &[u8]) -> Result<DownloadManifest, anyhow::Error> {
     let res = repr::DownloadManifest::read(&mut Cursor::new(content))?;
     assert_eq!(16, res.hash_size);
 
@@ -85,9 +113,23 @@ pub struct Entry {
     pub file_size: u64,
     pub download_priority: u8,
     pub checksum: Option<u32>,
+    /// Raw flag bytes, only present from version 2 on
     pub flags: Vec<u8>,
 }
 
+impl Entry {
+    pub fn attributes(&self) -> DownloadFlags {
+        DownloadFlags::from_bits_truncate(self.flags.first().copied().unwrap_or(0))
+    }
+}
+
+bitflags::bitflags! {
+    pub struct DownloadFlags: u8 {
+        const PLUGIN = 0x1;
+        const PLUGIN_DATA = 0x2;
+    }
+}
+
 pub struct Tag {
     pub name: String,
     pub type_: u16,
@@ -180,3 +222,83 @@ mod repr {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entries_with_tags_and_flags() {
+        let entry = |i: u8, flags: Vec<u8>| Entry {
+            key: EncodingKey::from_slice(&[i; 16]),
+            file_size: 100,
+            download_priority: 0,
+            checksum: None,
+            flags,
+        };
+        let manifest = DownloadManifest {
+            base_priority: 0,
+            entries: vec![
+                entry(0, vec![]),
+                entry(1, vec![0x1]),
+                entry(2, vec![0x2, 0xff]),
+                entry(3, vec![0x80]),
+            ],
+            tags: vec![],
+        };
+
+        assert!(manifest.entries[1]
+            .attributes()
+            .contains(DownloadFlags::PLUGIN));
+        assert_eq!(vec![0x2, 0xff], manifest.entries[2].flags);
+
+        let kept: Vec<_> = manifest
+            .entries_with_tags_and_flags(&HashSet::new(), DownloadFlags::PLUGIN)
+            .map(|e| e.key.clone())
+            .collect();
+        assert_eq!(
+            vec![
+                manifest.entries[0].key.clone(),
+                manifest.entries[2].key.clone(),
+                manifest.entries[3].key.clone()
+            ],
+            kept
+        );
+        assert_eq!(
+            4,
+            manifest
+                .entries_with_tags_and_flags(&HashSet::new(), DownloadFlags::empty())
+                .count()
+        );
+    }
+
+    #[test]
+    fn test_tag_categories() {
+        let tag = |name: &str, type_| Tag {
+            name: name.to_string(),
+            type_,
+            entries: BitVec::new(),
+        };
+        let manifest = DownloadManifest {
+            base_priority: 0,
+            entries: vec![],
+            tags: vec![
+                tag("Windows", 1),
+                tag("OSX", 1),
+                tag("enUS", 3),
+                tag("x86_64", 2),
+                tag("deDE", 3),
+            ],
+        };
+
+        let categories = manifest.tag_categories();
+        assert_eq!(
+            vec![
+                (1, vec!["Windows", "OSX"]),
+                (2, vec!["x86_64"]),
+                (3, vec!["enUS", "deDE"])
+            ],
+            categories.into_iter().collect::<Vec<_>>()
+        );
+    }
+}
@@ -1,14 +1,19 @@
 use binrw::BinRead;
-use std::io::Cursor;
+use byteorder::{ByteOrder, BE};
+use std::{collections::BTreeSet, io::Cursor};
 
-use crate::blte::espec::ESpec;
+use crate::blte::{compute_md5, espec::ESpec};
 
-use super::{ContentKey, EncodingKey};
+use super::{keys::TactKeys, ContentKey, EncodingKey};
 
 pub struct Encoding {
     pub hash_size_ckey: u8,
     pub hash_size_ekey: u8,
     pub especs: Vec<ESpec>,
+    /// The espec the encoding file itself is encoded with, stored after the page tables
+    pub own_espec: Option<ESpec>,
+    /// Problems that didn't stop the file from being parsed, for the caller to report
+    pub warnings: Vec<String>,
     pub cekey_page_headers: Vec<repr::CEKeyPageHeader>,
     pub cekey_pages: Vec<repr::CEKeyPage>,
     pub ekey_spec_page_headers: Vec<repr::EKeySpecPageHeader>,
@@ -20,18 +25,7 @@ impl Encoding {
         // TODO: Avoid turning it into a slice
         assert_eq!(self.hash_size_ckey as usize, 16);
 
-        let mut page_idx = 0;
-        let mut prev_first_key = &self.cekey_page_headers[0].first_key;
-        for (i, page_header) in self.cekey_page_headers.iter().enumerate().skip(1) {
-            let next_first_key = &page_header.first_key;
-            if ckey >= prev_first_key && ckey < next_first_key {
-                break;
-            } else {
-                page_idx = i;
-                prev_first_key = next_first_key;
-            }
-        }
-
+        let page_idx = page_index(&self.cekey_page_headers, |h| &h.first_key, ckey);
         self.cekey_pages[page_idx]
             .entries
             .0
@@ -56,29 +50,133 @@ impl Encoding {
         None
     }
 
-    pub fn lookup_espec(&self, ekey: &EncodingKey) -> Option<&ESpec> {
-        let mut page_idx = 0;
-        let mut prev_first_key = &self.ekey_spec_page_headers[0].first_key;
-        for (i, page_header) in self.ekey_spec_page_headers.iter().enumerate().skip(1) {
-            let next_first_key = &page_header.first_key;
-            if ekey >= prev_first_key && ekey <= next_first_key {
-                break;
-            } else {
-                page_idx = i;
-                prev_first_key = next_first_key;
-            }
-        }
-
+    fn lookup_ekey_spec(&self, ekey: &EncodingKey) -> Option<&repr::EKeySpecEntry> {
+        let page_idx = page_index(&self.ekey_spec_page_headers, |h| &h.first_key, ekey);
         self.ekey_spec_pages[page_idx]
             .entries
             .0
             .iter()
             .find(|entry| &entry.ekey == ekey)
+    }
+
+    pub fn lookup_espec(&self, ekey: &EncodingKey) -> Option<&ESpec> {
+        self.lookup_ekey_spec(ekey)
             .and_then(|e| self.especs.get(e.espec_index as usize))
     }
+
+    /// Size of the encoded file, as stored on the CDN
+    pub fn lookup_encoded_size(&self, ekey: &EncodingKey) -> Option<u64> {
+        self.lookup_ekey_spec(ekey).map(|e| e.file_size.get())
+    }
+
+    /// Like `lookup_espec`, but by the truncated 9 byte ekey CASC indexes use. Also returns the full ekey.
+    pub fn lookup_espec_by_short(&self, short: &[u8; 9]) -> Option<(&EncodingKey, &ESpec)> {
+        // Keys sharing the prefix may straddle a page boundary, so keep going while pages can hold it
+        let start = self
+            .ekey_spec_page_headers
+            .iter()
+            .rposition(|header| header.first_key.short() < *short)
+            .unwrap_or(0);
+
+        self.ekey_spec_page_headers
+            .iter()
+            .zip(&self.ekey_spec_pages)
+            .enumerate()
+            .skip(start)
+            .take_while(|(i, (header, _))| *i == start || header.first_key.short() <= *short)
+            .flat_map(|(_, (_, page))| page.entries.0.iter())
+            .find(|entry| entry.ekey.short() == *short)
+            .and_then(|e| Some((&e.ekey, self.especs.get(e.espec_index as usize)?)))
+    }
+
+    /// Looks up the espec of the first encoding of the given content
+    pub fn lookup_espec_by_ckey(&self, ckey: &ContentKey) -> Option<&ESpec> {
+        let ce_entry = self.lookup_by_ckey(ckey)?;
+        self.lookup_espec(ce_entry.ekeys.first()?)
+    }
+
+    /// The canonical espec string of the first encoding of the given content, e.g. `b:{256K*=z}`
+    pub fn espec_string_for_ckey(&self, ckey: &ContentKey) -> Option<String> {
+        self.lookup_espec_by_ckey(ckey)
+            .map(|espec| espec.to_string())
+    }
+
+    /// Checks every ekey spec entry refers to an espec that exists, so lookups can't silently miss
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        for (page_idx, page) in self.ekey_spec_pages.iter().enumerate() {
+            // Zeroed page padding parses as entries with a zero key
+            let bad_entry = page.entries.0.iter().find(|e| {
+                e.ekey != EncodingKey::ZERO && e.espec_index as usize >= self.especs.len()
+            });
+            if let Some(entry) = bad_entry {
+                anyhow::bail!(
+                    "ekey spec entry {:?} in page {} has espec index {}, but there are only {} especs",
+                    entry.ekey,
+                    page_idx,
+                    entry.espec_index,
+                    self.especs.len()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Collects every encryption key referenced by the build's especs, split by whether `tact_keys` has it
+    pub fn required_keys(&self, tact_keys: &TactKeys) -> RequiredKeys {
+        let mut res = RequiredKeys::default();
+        for key_name in self.especs.iter().flat_map(|espec| espec.key_names()) {
+            if tact_keys.get_key(&key_name).is_some() {
+                res.present.insert(key_name);
+            } else {
+                res.missing.insert(key_name);
+            }
+        }
+        res
+    }
+}
+
+/// The page that would hold `key`, i.e. the last one starting at or before it.
+/// A key equal to a page's first key is always in that page, not the one before.
+fn page_index<H, K: Ord>(headers: &[H], first_key: impl Fn(&H) -> &K, key: &K) -> usize {
+    headers
+        .partition_point(|h| first_key(h) <= key)
+        .saturating_sub(1)
+}
+
+#[derive(Debug, Default)]
+pub struct RequiredKeys {
+    pub present: BTreeSet<[u8; 8]>,
+    pub missing: BTreeSet<[u8; 8]>,
+}
+
+/// Parses the espec the encoding file itself was encoded with, stored after the tables.
+/// It's only used for checking, so anything unreadable is added to `warnings` and ignored.
+fn parse_own_espec(trailer: &[u8], warnings: &mut Vec<String>) -> Option<ESpec> {
+    let s = match std::str::from_utf8(trailer) {
+        Ok(s) => s.trim_matches(|c: char| c == '\0' || c.is_ascii_whitespace()),
+        Err(e) => {
+            warnings.push(format!("encoding file espec is not valid UTF-8: {}", e));
+            return None;
+        }
+    };
+    if s.is_empty() {
+        return None;
+    }
+    match s.parse() {
+        Ok(espec) => Some(espec),
+        Err(e) => {
+            warnings.push(format!(
+                "couldn't parse encoding file espec {:?}: {:#}",
+                s, e
+            ));
+            None
+        }
+    }
 }
 
-pub fn parse_encoding(content: &[u8]) -> Result<Encoding, anyhow::Error> {
+/// Parses an encoding table. If `verify_pages` is set, every page is checked against the MD5 in its page header.
+pub fn parse_encoding(content: &[u8], verify_pages: bool) -> Result<Encoding, anyhow::Error> {
+    let tables_end = check_table_size(content)?;
     let res = repr::EncodingTable::read(&mut Cursor::new(content))?;
 
     assert_eq!(1, res.version);
@@ -86,7 +184,14 @@ pub fn parse_encoding(content: &[u8]) -> Result<Encoding, anyhow::Error> {
     assert_eq!(16, res.hash_size_ekey);
     assert_eq!(0, res.unk);
 
-    Ok(Encoding {
+    if verify_pages {
+        verify_page_md5s(content, &res)?;
+    }
+
+    let mut warnings = vec![];
+    let own_espec = parse_own_espec(&content[tables_end..], &mut warnings);
+
+    let encoding = Encoding {
         hash_size_ckey: res.hash_size_ckey,
         hash_size_ekey: res.hash_size_ekey,
         especs: res
@@ -95,11 +200,100 @@ pub fn parse_encoding(content: &[u8]) -> Result<Encoding, anyhow::Error> {
             .into_iter()
             .map(|s| s.to_string().parse())
             .collect::<Result<Vec<_>, _>>()?,
+        own_espec,
+        warnings,
         cekey_page_headers: res.cekey_page_headers,
         cekey_pages: res.cekey_pages,
         ekey_spec_page_headers: res.ekey_spec_page_headers,
         ekey_spec_pages: res.ekey_spec_pages,
-    })
+    };
+    encoding.validate()?;
+    Ok(encoding)
+}
+
+/// Checks the page tables declared in the header fit in the file, so truncated files get a
+/// clear error instead of a binrw EOF deep in page parsing. Returns where the tables end.
+fn check_table_size(content: &[u8]) -> Result<usize, anyhow::Error> {
+    const PAGE_HEADER_SIZE: u64 = 32;
+
+    let header = content
+        .get(..repr::EncodingTable::HEADER_SIZE)
+        .ok_or_else(|| anyhow::anyhow!("encoding file too short for header"))?;
+    let cekey_page_size_kb = BE::read_u16(&header[5..]);
+    let espec_page_size_kb = BE::read_u16(&header[7..]);
+    let cekey_page_count = BE::read_u32(&header[9..]);
+    let espec_page_count = BE::read_u32(&header[13..]);
+    let espec_block_size = BE::read_u32(&header[18..]);
+
+    if cekey_page_count > 0 && cekey_page_size_kb == 0 {
+        anyhow::bail!("encoding file has {cekey_page_count} cekey pages but a page size of 0");
+    }
+    if espec_page_count > 0 && espec_page_size_kb == 0 {
+        anyhow::bail!("encoding file has {espec_page_count} espec pages but a page size of 0");
+    }
+
+    let table_size =
+        |count: u32, size_kb: u16| count as u64 * (PAGE_HEADER_SIZE + size_kb as u64 * 1024);
+    let expected = repr::EncodingTable::HEADER_SIZE as u64
+        + espec_block_size as u64
+        + table_size(cekey_page_count, cekey_page_size_kb)
+        + table_size(espec_page_count, espec_page_size_kb);
+    if (content.len() as u64) < expected {
+        anyhow::bail!(
+            "encoding file is truncated - header declares {} cekey pages of {}KB and {} espec pages of {}KB ({} bytes), but file is {} bytes",
+            cekey_page_count,
+            cekey_page_size_kb,
+            espec_page_count,
+            espec_page_size_kb,
+            expected,
+            content.len()
+        );
+    }
+    Ok(expected as usize)
+}
+
+fn verify_page_md5s(content: &[u8], res: &repr::EncodingTable) -> Result<(), anyhow::Error> {
+    const PAGE_HEADER_SIZE: usize = 32;
+
+    let mut pos = repr::EncodingTable::HEADER_SIZE + res.espec_block_size as usize;
+
+    let cekey_page_size = res.cekey_page_table_page_size_kb as usize * 1024;
+    pos += res.cekey_page_headers.len() * PAGE_HEADER_SIZE;
+    for (idx, header) in res.cekey_page_headers.iter().enumerate() {
+        let page = content
+            .get(pos..pos + cekey_page_size)
+            .ok_or_else(|| anyhow::anyhow!("cekey page {} out of bounds", idx))?;
+        let md5 = compute_md5(page);
+        if md5 != header.page_md5 {
+            anyhow::bail!(
+                "cekey page {} md5 mismatch: expected {}, got {}",
+                idx,
+                hex::encode(header.page_md5),
+                hex::encode(md5)
+            );
+        }
+        pos += cekey_page_size;
+    }
+
+    let ekey_spec_page_size = res.ekey_spec_page_table_page_size_kb as usize * 1024;
+    pos += res.ekey_spec_page_headers.len() * PAGE_HEADER_SIZE;
+    for (idx, header) in res.ekey_spec_page_headers.iter().enumerate() {
+        let page = content
+            .get(pos..pos + ekey_spec_page_size)
+            .ok_or_else(|| anyhow::anyhow!("ekey spec page {} out of bounds", idx))?;
+        let md5 = compute_md5(page);
+        if md5 != header.page_md5 {
+            anyhow::bail!(
+                "ekey spec page {} md5 mismatch: expected {}, got {}",
+                idx,
+                hex::encode(header.page_md5),
+                hex::encode(md5)
+            );
+        }
+        pos += ekey_spec_page_size;
+    }
+
+    Ok(())
 }
 
 mod repr {
@@ -145,6 +339,11 @@ mod repr {
         pub ekey_spec_pages: Vec<EKeySpecPage>,
     }
 
+    impl EncodingTable {
+        /// Size of the fixed header, including magic
+        pub const HEADER_SIZE: usize = 22;
+    }
+
     #[derive(BinRead)]
     pub struct CEKeyPageHeader {
         pub first_key: ContentKey,
@@ -189,3 +388,159 @@ mod repr {
         pub file_size: u40,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        binrw_ext::Block,
+        casc::builder::{write_encoding, EncodedFile, ESPEC},
+    };
+
+    #[test]
+    fn test_validate_espec_indices() {
+        let ekey = EncodingKey::from_slice(&[0xab; 16]);
+        let mut encoding = Encoding {
+            hash_size_ckey: 16,
+            hash_size_ekey: 16,
+            especs: vec!["z".parse().unwrap()],
+            own_espec: None,
+            warnings: vec![],
+            cekey_page_headers: vec![],
+            cekey_pages: vec![],
+            ekey_spec_page_headers: vec![repr::EKeySpecPageHeader {
+                first_key: ekey.clone(),
+                page_md5: [0; 16],
+            }],
+            ekey_spec_pages: vec![repr::EKeySpecPage {
+                entries: Block(vec![
+                    repr::EKeySpecEntry {
+                        ekey: ekey.clone(),
+                        espec_index: 0,
+                        file_size: 10u32.into(),
+                    },
+                    repr::EKeySpecEntry {
+                        ekey: EncodingKey::ZERO,
+                        espec_index: 0,
+                        file_size: 0u32.into(),
+                    },
+                ]),
+            }],
+        };
+        encoding.validate().unwrap();
+        assert!(encoding.lookup_espec(&ekey).is_some());
+
+        encoding.ekey_spec_pages[0].entries.0[0].espec_index = 1;
+        let err = encoding.validate().unwrap_err();
+        assert!(err.to_string().contains("has espec index 1"));
+    }
+
+    #[test]
+    fn test_check_table_size() {
+        let header = |page_size_kb: u16, count: u32| {
+            let mut buf = b"EN".to_vec();
+            buf.extend_from_slice(&[1, 16, 16]);
+            buf.extend_from_slice(&page_size_kb.to_be_bytes());
+            buf.extend_from_slice(&page_size_kb.to_be_bytes());
+            buf.extend_from_slice(&count.to_be_bytes());
+            buf.extend_from_slice(&count.to_be_bytes());
+            buf.push(0);
+            buf.extend_from_slice(&0u32.to_be_bytes());
+            buf
+        };
+
+        check_table_size(&header(4, 0)).unwrap();
+
+        let err = parse_encoding(&header(0, 1), false)
+            .map(|_| ())
+            .unwrap_err();
+        assert!(err.to_string().contains("page size of 0"), "{}", err);
+
+        let mut content = header(4, 2);
+        content.extend_from_slice(&[0; 4096]);
+        let err = parse_encoding(&content, false).map(|_| ()).unwrap_err();
+        assert!(err.to_string().contains("truncated"), "{}", err);
+
+        assert!(check_table_size(b"EN").is_err());
+    }
+
+    #[test]
+    fn test_encoding_page_boundaries() {
+        let tact_keys = TactKeys::default();
+        let espec: ESpec = ESPEC.parse().unwrap();
+        let files = (0..1000u32)
+            .map(|i| EncodedFile::new(&tact_keys, &espec, &i.to_le_bytes()).unwrap())
+            .collect::<Vec<_>>();
+
+        let encoding = parse_encoding(&write_encoding(&files).unwrap(), true).unwrap();
+        assert!(encoding.cekey_page_headers.len() > 2);
+        assert!(encoding.ekey_spec_page_headers.len() > 2);
+
+        // Every page's first key, and the key just below it at the end of the previous page
+        for file in &files {
+            let ce_entry = encoding.lookup_by_ckey(&file.ckey).unwrap();
+            assert_eq!(ce_entry.ekeys[0], file.ekey);
+            assert!(encoding.lookup_espec(&file.ekey).is_some());
+        }
+        for header in &encoding.cekey_page_headers {
+            assert!(encoding.lookup_by_ckey(&header.first_key).is_some());
+        }
+        for header in &encoding.ekey_spec_page_headers {
+            assert!(encoding.lookup_espec(&header.first_key).is_some());
+        }
+
+        let missing = ContentKey::from_data(b"missing");
+        assert!(encoding.lookup_by_ckey(&missing).is_none());
+        assert!(encoding.lookup_espec(&missing.unencoded()).is_none());
+    }
+
+    #[test]
+    fn test_verify_page_md5s() {
+        let tact_keys = TactKeys::default();
+        let espec: ESpec = ESPEC.parse().unwrap();
+        let files = (0..10u32)
+            .map(|i| EncodedFile::new(&tact_keys, &espec, &i.to_le_bytes()).unwrap())
+            .collect::<Vec<_>>();
+        let data = write_encoding(&files).unwrap();
+
+        let encoding = parse_encoding(&data, true).unwrap();
+        let cekey_pages = encoding.cekey_page_headers.len();
+        let espec_pages = encoding.ekey_spec_page_headers.len();
+        let cekey_page_size = u16::from_be_bytes([data[5], data[6]]) as usize * 1024;
+        let espec_block_size = u32::from_be_bytes(data[18..22].try_into().unwrap()) as usize;
+
+        let cekey_start = repr::EncodingTable::HEADER_SIZE + espec_block_size + cekey_pages * 32;
+        let espec_start = cekey_start + cekey_pages * cekey_page_size + espec_pages * 32;
+
+        let corrupt = |offset: usize| {
+            let mut data = data.clone();
+            data[offset] ^= 1;
+            // Without verification the flipped bit goes unnoticed
+            parse_encoding(&data, false).unwrap();
+            parse_encoding(&data, true)
+                .map(|_| ())
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(corrupt(cekey_start + 20).starts_with("cekey page 0 md5 mismatch"));
+        assert!(corrupt(espec_start + 20).starts_with("ekey spec page 0 md5 mismatch"));
+    }
+
+    #[test]
+    fn test_parse_own_espec() {
+        let mut warnings = vec![];
+        assert_eq!(
+            Some("b:{256K*=z}".parse::<ESpec>().unwrap().to_string()),
+            parse_own_espec(b"b:{256K*=z}\0", &mut warnings).map(|e| e.to_string())
+        );
+        assert!(parse_own_espec(b"\0\0", &mut warnings).is_none());
+        assert!(warnings.is_empty());
+
+        // Unreadable especs are reported rather than failing the whole file
+        assert!(parse_own_espec(b"b:{not an espec", &mut warnings).is_none());
+        assert!(parse_own_espec(&[0xff, 0xfe], &mut warnings).is_none());
+        assert_eq!(2, warnings.len());
+        assert!(warnings[0].starts_with("couldn't parse encoding file espec"));
+        assert!(warnings[1].starts_with("encoding file espec is not valid UTF-8"));
+    }
+}
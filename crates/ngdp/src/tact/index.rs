@@ -1,9 +1,10 @@
 use std::{collections::HashMap, io::Cursor};
 
 use binrw::BinRead;
-use byteorder::{ByteOrder, BE};
+use byteorder::{ByteOrder, BE, LE};
 
 use super::EncodingKey;
+use crate::blte::compute_md5;
 
 #[derive(Debug)]
 pub struct Index {
@@ -16,9 +17,24 @@ pub struct Entry {
     pub offset: u64,
 }
 
+impl Index {
+    /// Entries sorted by offset, for reading an archive front to back.
+    /// Entries may overlap, with one range nested inside another. Enclosing entries sort first.
+    pub fn entries_by_offset(&self) -> Vec<(&EncodingKey, &Entry)> {
+        let mut res: Vec<_> = self.entries.iter().collect();
+        res.sort_by_key(|(_key, entry)| (entry.offset, std::cmp::Reverse(entry.size)));
+        res
+    }
+}
+
 pub fn parse_index(content: &[u8]) -> Result<Index, anyhow::Error> {
     let res = repr::Index::read(&mut Cursor::new(content))?;
-    assert_eq!(res.footer.key_size_in_bytes, 16);
+    if res.footer.key_size_in_bytes != 16 {
+        anyhow::bail!(
+            "unsupported index key size: expected 16 bytes, got {}",
+            res.footer.key_size_in_bytes
+        );
+    }
 
     let mut entries = HashMap::new();
     for block in res.blocks {
@@ -32,18 +48,224 @@ pub fn parse_index(content: &[u8]) -> Result<Index, anyhow::Error> {
             let size = BE::read_uint(&entry.size, res.footer.size_bytes as usize);
             let offset = BE::read_uint(&entry.offset, res.footer.offset_bytes as usize);
 
-            assert!(!entries.contains_key(&key));
+            if entries.contains_key(&key) {
+                anyhow::bail!("duplicate key in index: {:?}", key);
+            }
 
             let entry = Entry { size, offset };
             entries.insert(key, entry);
         }
     }
 
-    assert_eq!(res.footer.num_elements as usize, entries.len());
+    if res.footer.num_elements as usize != entries.len() {
+        anyhow::bail!(
+            "index element count mismatch: footer declares {}, found {}",
+            res.footer.num_elements,
+            entries.len()
+        );
+    }
 
     Ok(Index { entries })
 }
 
+/// Layout of an index, as described by its footer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexFooter {
+    pub block_size_kb: u8,
+    pub offset_bytes: u8,
+    pub size_bytes: u8,
+    pub key_size_in_bytes: u8,
+    pub checksum_size: u8,
+    pub num_elements: u32,
+}
+
+impl IndexFooter {
+    /// Footer size when `checksum_size` is 8, the only size `parse_index` handles
+    pub const SIZE: usize = 28;
+
+    /// Archive indexes have 4 byte offsets, group indexes prefix them with a 2 byte archive number
+    pub fn new(offset_bytes: u8, num_elements: u32) -> IndexFooter {
+        IndexFooter {
+            block_size_kb: 4,
+            offset_bytes,
+            size_bytes: 4,
+            key_size_in_bytes: 16,
+            checksum_size: 8,
+            num_elements,
+        }
+    }
+
+    fn from_bytes(footer: &[u8]) -> IndexFooter {
+        IndexFooter {
+            block_size_kb: footer[11],
+            offset_bytes: footer[12],
+            size_bytes: footer[13],
+            key_size_in_bytes: footer[14],
+            checksum_size: footer[15],
+            num_elements: LE::read_u32(&footer[16..20]),
+        }
+    }
+
+    /// Rejects layouts the block math can't handle, like empty blocks or entries, or fields
+    /// wider than the u64 they're read into
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        if self.block_size_kb == 0 {
+            anyhow::bail!("index block size is 0");
+        }
+        if self.key_size_in_bytes == 0 || self.size_bytes == 0 {
+            anyhow::bail!(
+                "index has {} byte keys and {} byte sizes",
+                self.key_size_in_bytes,
+                self.size_bytes
+            );
+        }
+        if self.size_bytes > 8 || self.offset_bytes > 8 {
+            anyhow::bail!(
+                "index has {} byte sizes and {} byte offsets, at most 8 are supported",
+                self.size_bytes,
+                self.offset_bytes
+            );
+        }
+        Ok(())
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size_kb as usize * 1024
+    }
+
+    pub fn entry_size(&self) -> usize {
+        (self.key_size_in_bytes + self.size_bytes + self.offset_bytes) as usize
+    }
+
+    pub fn entries_per_block(&self) -> usize {
+        self.block_size() / self.entry_size()
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        (self.num_elements as usize).div_ceil(self.entries_per_block())
+    }
+
+    /// Size of the table of contents: the last key of each block, then a hash of each block
+    pub fn toc_size(&self) -> usize {
+        self.num_blocks() * (self.key_size_in_bytes + self.checksum_size) as usize
+    }
+
+    /// Writes the footer following `toc`, filling in both hashes
+    pub fn write(&self, toc: &[u8], w: &mut Vec<u8>) {
+        let start = w.len();
+        w.extend_from_slice(&self.truncated_md5(toc));
+        w.extend_from_slice(&[
+            1, // version
+            0,
+            0,
+            self.block_size_kb,
+            self.offset_bytes,
+            self.size_bytes,
+            self.key_size_in_bytes,
+            self.checksum_size,
+        ]);
+        w.extend_from_slice(&self.num_elements.to_le_bytes());
+
+        let checksum = self.footer_checksum(&w[start + 8..]);
+        w.extend_from_slice(&checksum);
+    }
+
+    /// Checksum of the footer from the version on, with the checksum itself zeroed
+    fn footer_checksum(&self, footer_fields: &[u8]) -> Vec<u8> {
+        let mut buf = footer_fields[..12].to_vec();
+        buf.resize(12 + self.checksum_size as usize, 0);
+        self.truncated_md5(&buf)
+    }
+
+    fn truncated_md5(&self, data: &[u8]) -> Vec<u8> {
+        compute_md5(data)[..self.checksum_size as usize].to_vec()
+    }
+}
+
+/// Writes an index of `entries`, sorted by key into zero padded blocks and followed by the
+/// table of contents and footer
+pub fn write_index(
+    entries: &[(EncodingKey, Entry)],
+    offset_bytes: u8,
+) -> Result<Vec<u8>, anyhow::Error> {
+    let footer = IndexFooter::new(offset_bytes, entries.len() as u32);
+    footer.validate()?;
+
+    let mut sorted: Vec<_> = entries.iter().collect();
+    sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    let mut res = vec![];
+    let mut last_keys = vec![];
+    let mut block_hashes = vec![];
+    for block in sorted.chunks(footer.entries_per_block()) {
+        let start = res.len();
+        for (key, entry) in block {
+            let fits = |value: u64, bytes: u8| bytes >= 8 || value >> (bytes * 8) == 0;
+            if !fits(entry.size, footer.size_bytes) || !fits(entry.offset, footer.offset_bytes) {
+                anyhow::bail!("index entry for {:?} doesn't fit: {:?}", key, entry);
+            }
+            res.extend_from_slice(key.as_slice());
+            res.extend_from_slice(&entry.size.to_be_bytes()[8 - footer.size_bytes as usize..]);
+            res.extend_from_slice(&entry.offset.to_be_bytes()[8 - offset_bytes as usize..]);
+        }
+        res.resize(start + footer.block_size(), 0);
+
+        last_keys.extend_from_slice(block.last().unwrap().0.as_slice());
+        block_hashes.extend(footer.truncated_md5(&res[start..]));
+    }
+
+    let mut toc = last_keys;
+    toc.extend(block_hashes);
+    res.extend_from_slice(&toc);
+    footer.write(&toc, &mut res);
+    Ok(res)
+}
+
+/// Checks the footer checksum, table of contents hash and every block hash of an index
+pub fn verify_index(content: &[u8]) -> Result<IndexFooter, anyhow::Error> {
+    let footer_start = content
+        .len()
+        .checked_sub(IndexFooter::SIZE)
+        .ok_or_else(|| anyhow::anyhow!("index too short for footer"))?;
+    let footer_bytes = &content[footer_start..];
+    let footer = IndexFooter::from_bytes(footer_bytes);
+    if footer.checksum_size != 8 {
+        anyhow::bail!("unsupported index checksum size {}", footer.checksum_size);
+    }
+
+    if footer.footer_checksum(&footer_bytes[8..]) != footer_bytes[20..] {
+        anyhow::bail!("index footer checksum mismatch");
+    }
+    footer.validate()?;
+
+    let blocks_size = footer.num_blocks() * footer.block_size();
+    if blocks_size + footer.toc_size() != footer_start {
+        anyhow::bail!(
+            "index is {} bytes, footer describes {} blocks of {}KB",
+            content.len(),
+            footer.num_blocks(),
+            footer.block_size_kb
+        );
+    }
+    let toc = &content[blocks_size..footer_start];
+    if footer.truncated_md5(toc) != footer_bytes[..8] {
+        anyhow::bail!("index toc hash mismatch");
+    }
+
+    let block_hashes = &toc[footer.num_blocks() * footer.key_size_in_bytes as usize..];
+    let checksum_size = footer.checksum_size as usize;
+    for (idx, block) in content[..blocks_size]
+        .chunks(footer.block_size())
+        .enumerate()
+    {
+        if footer.truncated_md5(block) != block_hashes[idx * checksum_size..][..checksum_size] {
+            anyhow::bail!("index block {} hash mismatch", idx);
+        }
+    }
+
+    Ok(footer)
+}
+
 mod repr {
     use std::io::SeekFrom;
 
@@ -157,3 +379,119 @@ mod repr {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(count: u32) -> Vec<(EncodingKey, Entry)> {
+        (0..count)
+            .map(|i| {
+                let mut key = [0x5a; 16];
+                key[..4].copy_from_slice(&(i + 1).to_be_bytes());
+                let entry = Entry {
+                    size: 100 + i as u64,
+                    // Group index offsets: archive number in the top 2 of 6 bytes
+                    offset: ((i as u64 % 3) << 32) | (i as u64 * 1000),
+                };
+                (EncodingKey::from_slice(&key), entry)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_group_index_round_trip() {
+        let per_block = IndexFooter::new(6, 0).entries_per_block();
+        assert_eq!(4096 / 26, per_block);
+
+        for (count, blocks) in [
+            (0, 0),
+            (1, 1),
+            (per_block as u32, 1),
+            (per_block as u32 + 1, 2),
+            (400, 3),
+        ] {
+            let entries = entries(count);
+            let data = write_index(&entries, 6).unwrap();
+
+            let footer = verify_index(&data).unwrap();
+            assert_eq!(IndexFooter::new(6, count), footer);
+            assert_eq!(blocks, footer.num_blocks(), "{} entries", count);
+            assert_eq!(
+                blocks * 4096 + blocks * 24 + IndexFooter::SIZE,
+                data.len(),
+                "{} entries",
+                count
+            );
+
+            let index = parse_index(&data).unwrap();
+            assert_eq!(count as usize, index.entries.len());
+            for (key, entry) in &entries {
+                let parsed = &index.entries[key];
+                assert_eq!((entry.size, entry.offset), (parsed.size, parsed.offset));
+            }
+        }
+    }
+
+    #[test]
+    fn test_verify_index_hashes() {
+        let data = write_index(&entries(400), 6).unwrap();
+        let footer_start = data.len() - IndexFooter::SIZE;
+
+        // Footer checksum is the truncated md5 of the footer fields, with the checksum zeroed
+        let mut fields = data[footer_start + 8..footer_start + 20].to_vec();
+        fields.extend_from_slice(&[0; 8]);
+        assert_eq!(compute_md5(&fields)[..8], data[footer_start + 20..]);
+
+        // Toc hash is the truncated md5 of the last keys and block hashes
+        let toc = &data[3 * 4096..footer_start];
+        assert_eq!(3 * (16 + 8), toc.len());
+        assert_eq!(compute_md5(toc)[..8], data[footer_start..footer_start + 8]);
+
+        let corrupt = |offset: usize| {
+            let mut data = data.clone();
+            data[offset] ^= 1;
+            verify_index(&data).unwrap_err().to_string()
+        };
+        assert_eq!("index block 1 hash mismatch", corrupt(4096 + 10));
+        assert_eq!("index toc hash mismatch", corrupt(3 * 4096));
+        assert_eq!("index footer checksum mismatch", corrupt(footer_start + 16));
+
+        assert!(write_index(
+            &[(
+                EncodingKey::from_slice(&[1; 16]),
+                Entry {
+                    size: 1 << 32,
+                    offset: 0
+                }
+            )],
+            6
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_verify_index_bad_footer() {
+        let data = write_index(&entries(10), 6).unwrap();
+        let footer_start = data.len() - IndexFooter::SIZE;
+
+        // Sets a footer field and fixes up the footer checksum, so only the layout is wrong
+        let with_field = |field: usize, value: u8| {
+            let mut data = data.clone();
+            data[footer_start + field] = value;
+            let footer = IndexFooter::from_bytes(&data[footer_start..]);
+            let checksum = footer.footer_checksum(&data[footer_start + 8..]);
+            data[footer_start + 20..].copy_from_slice(&checksum);
+            verify_index(&data).unwrap_err().to_string()
+        };
+        assert_eq!("index block size is 0", with_field(11, 0));
+        assert_eq!("index has 0 byte keys and 4 byte sizes", with_field(14, 0));
+        assert_eq!("index has 16 byte keys and 0 byte sizes", with_field(13, 0));
+        assert_eq!(
+            "index has 4 byte sizes and 9 byte offsets, at most 8 are supported",
+            with_field(12, 9)
+        );
+
+        assert!(write_index(&entries(1), 9).is_err());
+    }
+}
@@ -1,10 +1,13 @@
-use std::{collections::HashSet, io::Cursor};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io::Cursor,
+};
 
 use binrw::BinRead;
 use bitvec::{prelude::Msb0, vec::BitVec};
 
 use super::{keys::TactKeys, ContentKey};
-use crate::blte::decode_blte;
+use crate::blte::decode_blte_reporting;
 
 #[derive(Debug)]
 pub struct InstallManifest {
@@ -14,6 +17,16 @@ pub struct InstallManifest {
 }
 
 impl InstallManifest {
+    /// Tag names grouped by category, in manifest order. Tags in one category are
+    /// alternatives (e.g. locales), a selection should pick from each category
+    pub fn tag_categories(&self) -> BTreeMap<u16, Vec<&str>> {
+        let mut categories: BTreeMap<u16, Vec<&str>> = BTreeMap::new();
+        for tag in &self.tags {
+            categories.entry(tag.type_).or_default().push(&tag.name);
+        }
+        categories
+    }
+
     pub fn files_with_tags<'a>(&'a self, tags: &HashSet<String>) -> impl Iterator<Item = &'a File> {
         let mut files = BitVec::from_iter(std::iter::repeat(true).take(self.files.len()));
 
@@ -60,8 +73,13 @@ pub fn parse_install_manifest(
     tact_keys: &TactKeys,
     content: &[u8],
 ) -> Result<InstallManifest, anyhow::Error> {
-    let content = decode_blte(tact_keys, content)?;
+    let content =
+        decode_blte_reporting(tact_keys, content)?.require_all_keys("install manifest")?;
+    parse_install_manifest_decoded(&content)
+}
 
+/// Like `parse_install_manifest`, for content that's already been BLTE decoded
+pub fn parse_install_manifest_decoded(content: &[u8]) -> Result<InstallManifest, anyhow::Error> {
     let res = repr::InstallManifest::read(&mut Cursor::new(content))?;
     assert_eq!(16, res.hash_size);
 
@@ -146,3 +164,43 @@ mod repr {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest() -> Vec<u8> {
+        let mut data = b"BLTE".to_vec();
+        data.extend_from_slice(&0u32.to_be_bytes()); // chunkless
+        data.push(b'N');
+
+        data.extend_from_slice(b"IN");
+        data.push(1); // version
+        data.push(16); // hash_size
+        data.extend_from_slice(&1u16.to_be_bytes()); // num_tags
+        data.extend_from_slice(&1u32.to_be_bytes()); // num_entries
+
+        data.extend_from_slice(b"Windows\0");
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.push(0x80);
+
+        data.extend_from_slice(b"Wow.exe\0");
+        data.extend_from_slice(&[0xaa; 16]);
+        data.extend_from_slice(&1234u32.to_be_bytes());
+        data
+    }
+
+    #[test]
+    fn test_parse_install_manifest() {
+        let tact_keys = TactKeys::default();
+
+        let parsed = parse_install_manifest(&tact_keys, &manifest()).unwrap();
+        assert_eq!("Wow.exe", parsed.files[0].name);
+        assert_eq!(1234, parsed.files[0].size);
+
+        // Without the 9 byte chunkless BLTE wrapper
+        let decoded = parse_install_manifest_decoded(&manifest()[9..]).unwrap();
+        assert_eq!("Wow.exe", decoded.files[0].name);
+        assert_eq!(vec!["Windows"], decoded.tag_categories()[&1]);
+    }
+}
@@ -6,6 +6,27 @@ pub struct TactKeys {
 }
 
 impl TactKeys {
+    /// Size of a single key ring record: an 8 byte key name followed by the 16 byte key
+    pub const RING_RECORD_SIZE: usize = 24;
+
+    /// Loads keys from a binary key ring, a flat list of fixed width name + key records
+    pub fn load_ring(data: &[u8]) -> Result<TactKeys, anyhow::Error> {
+        if !data.len().is_multiple_of(Self::RING_RECORD_SIZE) {
+            anyhow::bail!(
+                "key ring size {} is not a multiple of the record size {}",
+                data.len(),
+                Self::RING_RECORD_SIZE
+            );
+        }
+
+        let mut res = TactKeys::default();
+        for record in data.chunks_exact(Self::RING_RECORD_SIZE) {
+            let (key_name, key) = record.split_at(8);
+            res.add_key(key_name.try_into()?, key.try_into()?);
+        }
+        Ok(res)
+    }
+
     pub fn get_key(&self, key_name: &[u8]) -> Option<&[u8; 16]> {
         self.keys.get(key_name)
     }
@@ -13,4 +34,100 @@ impl TactKeys {
     pub fn add_key(&mut self, key_name: [u8; 8], key: [u8; 16]) {
         self.keys.insert(key_name, key);
     }
+
+    pub fn add_keys(&mut self, keys: impl IntoIterator<Item = ([u8; 8], [u8; 16])>) {
+        self.keys.extend(keys);
+    }
+
+    /// Like `add_keys`, but fails without adding anything if a key name is already known (or
+    /// repeated in `keys`) with a different key. Repeats with the same key are fine.
+    pub fn add_keys_checked(
+        &mut self,
+        keys: impl IntoIterator<Item = ([u8; 8], [u8; 16])>,
+    ) -> Result<(), anyhow::Error> {
+        let mut new_keys: HashMap<[u8; 8], [u8; 16]> = HashMap::new();
+        let mut conflicts = vec![];
+        for (key_name, key) in keys {
+            let existing = self.keys.get(&key_name).or(new_keys.get(&key_name));
+            match existing {
+                Some(existing) if *existing != key => conflicts.push(key_name),
+                _ => {
+                    new_keys.insert(key_name, key);
+                }
+            }
+        }
+
+        if !conflicts.is_empty() {
+            conflicts.sort();
+            conflicts.dedup();
+            anyhow::bail!(
+                "conflicting keys for key names: {}",
+                conflicts
+                    .iter()
+                    .map(hex::encode)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        self.keys.extend(new_keys);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_keys_checked() {
+        let mut keys = TactKeys::default();
+        keys.add_keys([([1; 8], [0x11; 16]), ([2; 8], [0x22; 16])]);
+
+        // Same name with the same key is fine, including within one batch
+        keys.add_keys_checked([
+            ([1; 8], [0x11; 16]),
+            ([3; 8], [0x33; 16]),
+            ([3; 8], [0x33; 16]),
+        ])
+        .unwrap();
+        assert_eq!(Some(&[0x33; 16]), keys.get_key(&[3; 8]));
+
+        let err = keys
+            .add_keys_checked([([4; 8], [0x44; 16]), ([2; 8], [0xff; 16])])
+            .unwrap_err();
+        assert!(err.to_string().contains(&hex::encode([2u8; 8])), "{}", err);
+        assert_eq!(Some(&[0x22; 16]), keys.get_key(&[2; 8]));
+        assert_eq!(None, keys.get_key(&[4; 8]));
+
+        let err = keys
+            .add_keys_checked([([5; 8], [0x55; 16]), ([5; 8], [0x56; 16])])
+            .unwrap_err();
+        assert!(err.to_string().contains(&hex::encode([5u8; 8])), "{}", err);
+        assert_eq!(None, keys.get_key(&[5; 8]));
+    }
+
+    #[test]
+    fn test_load_ring() {
+        let mut ring = vec![];
+        ring.extend_from_slice(&[1; 8]);
+        ring.extend_from_slice(&[0x11; 16]);
+        ring.extend_from_slice(&[2; 8]);
+        ring.extend_from_slice(&[0x22; 16]);
+
+        let keys = TactKeys::load_ring(&ring).unwrap();
+        assert_eq!(Some(&[0x11; 16]), keys.get_key(&[1; 8]));
+        assert_eq!(Some(&[0x22; 16]), keys.get_key(&[2; 8]));
+        assert_eq!(None, keys.get_key(&[0x11; 8]));
+
+        assert!(TactKeys::load_ring(&[]).unwrap().get_key(&[1; 8]).is_none());
+
+        let Err(err) = TactKeys::load_ring(&ring[..30]) else {
+            panic!("loaded a truncated key ring");
+        };
+        assert_eq!(
+            "key ring size 30 is not a multiple of the record size 24",
+            err.to_string()
+        );
+    }
 }
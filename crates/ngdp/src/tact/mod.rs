@@ -12,6 +12,9 @@ pub mod encoding;
 pub mod index;
 pub mod install;
 pub mod keys;
+pub mod patch;
+pub mod product_config;
+pub mod resolve;
 pub mod root;
 
 /// MD5 hash of a file's uncompressed contents
@@ -39,6 +42,34 @@ impl ContentKey {
     }
 }
 
+/// Reader that hashes everything read through it, to verify data while it's streamed
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: Md5,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R) -> Self {
+        HashingReader {
+            inner,
+            hasher: Md5::new(),
+        }
+    }
+
+    /// Content key of everything read so far
+    pub fn content_key(&self) -> ContentKey {
+        ContentKey(self.hasher.clone().finalize().into())
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
 /// MD5 hash based on part of the a files encoding:
 /// * For chunkless BLTE files, the hash is of the entire file
 /// * For chunked BLTE files, the hash is of the header, including chunk infos.
@@ -48,6 +79,25 @@ impl ContentKey {
 )]
 pub struct EncodingKey([u8; 16]);
 
+impl EncodingKey {
+    /// Computes the key of a BLTE encoded file, see above
+    pub fn from_blte(blte: &[u8]) -> Result<Self, anyhow::Error> {
+        if blte.len() < 8 || &blte[..4] != b"BLTE" {
+            anyhow::bail!("not a blte file");
+        }
+        let header_size = u32::from_be_bytes(blte[4..8].try_into().unwrap()) as usize;
+        let hashed = match header_size {
+            0 => blte,
+            _ => blte
+                .get(..header_size)
+                .ok_or_else(|| anyhow::anyhow!("blte header larger than file"))?,
+        };
+        let mut hasher = Md5::new();
+        hasher.write_all(hashed).unwrap();
+        Ok(Self(hasher.finalize().into()))
+    }
+}
+
 macro_rules! impl_Key {
     ($($name:ident),*) => {$(
         impl $name {
@@ -106,3 +156,33 @@ macro_rules! impl_Key {
 }
 
 impl_Key!(ContentKey, EncodingKey);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blte::encode_blte;
+    use crate::tact::keys::TactKeys;
+
+    #[test]
+    fn test_encoding_key_from_blte() {
+        let tact_keys = TactKeys::default();
+        let data = vec![7u8; 4096];
+
+        // Chunkless files are keyed by the whole file
+        let chunkless = encode_blte(&tact_keys, &"n".parse().unwrap(), &data).unwrap();
+        assert_eq!(
+            ContentKey::from_data(&chunkless).unencoded(),
+            EncodingKey::from_blte(&chunkless).unwrap()
+        );
+
+        // Chunked ones by their header
+        let chunked = encode_blte(&tact_keys, &"b:{1K*=n}".parse().unwrap(), &data).unwrap();
+        let header_size = u32::from_be_bytes(chunked[4..8].try_into().unwrap()) as usize;
+        assert_eq!(
+            ContentKey::from_data(&chunked[..header_size]).unencoded(),
+            EncodingKey::from_blte(&chunked).unwrap()
+        );
+
+        assert!(EncodingKey::from_blte(b"not blte").is_err());
+    }
+}
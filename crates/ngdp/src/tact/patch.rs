@@ -0,0 +1,263 @@
+use std::io::{Cursor, Seek, SeekFrom};
+
+use binrw::BinRead;
+use byteorder::ReadBytesExt;
+
+use super::{keys::TactKeys, ContentKey, EncodingKey};
+use crate::blte::decode_blte;
+
+#[derive(Debug)]
+pub struct PatchManifest {
+    pub version: u8,
+    pub flags: u8,
+    /// The new build's encoding file, which the manifest describes alongside its patches
+    pub encoding_ckey: ContentKey,
+    pub encoding_ekey: EncodingKey,
+    pub encoding_decoded_size: u32,
+    pub encoding_encoded_size: u32,
+    pub encoding_espec: String,
+    pub entries: Vec<Entry>,
+}
+
+/// A file that can be patched to the given target content from one or more older encodings
+#[derive(Debug)]
+pub struct Entry {
+    pub target_ckey: ContentKey,
+    pub decoded_size: u64,
+    pub patches: Vec<Patch>,
+}
+
+#[derive(Debug)]
+pub struct Patch {
+    pub source_ekey: EncodingKey,
+    pub source_decoded_size: u64,
+    pub patch_ekey: EncodingKey,
+    pub patch_size: u32,
+    pub patch_index: u8,
+}
+
+/// Parses a patch manifest, which may or may not be BLTE encoded
+/// Blocks are 64KB in practice, anything past 4GB is a corrupt header
+const MAX_BLOCK_SIZE_BITS: u8 = 32;
+
+pub fn parse_patch_manifest(
+    tact_keys: &TactKeys,
+    content: &[u8],
+) -> Result<PatchManifest, anyhow::Error> {
+    let decoded;
+    let content = if content.starts_with(b"BLTE") {
+        decoded = decode_blte(tact_keys, content)?;
+        decoded.as_slice()
+    } else {
+        content
+    };
+
+    let mut r = Cursor::new(content);
+    let header = repr::Header::read(&mut r)?;
+
+    for (name, size) in [
+        ("file key", header.file_key_size),
+        ("old key", header.old_key_size),
+        ("patch key", header.patch_key_size),
+    ] {
+        if size != 16 {
+            anyhow::bail!("unsupported patch manifest {} size: {}", name, size);
+        }
+    }
+
+    if header.block_size_bits > MAX_BLOCK_SIZE_BITS {
+        anyhow::bail!(
+            "patch manifest block size of 2^{} bytes is larger than the 2^{} supported",
+            header.block_size_bits,
+            MAX_BLOCK_SIZE_BITS
+        );
+    }
+    let block_size = 1u64
+        .checked_shl(header.block_size_bits as u32)
+        .ok_or_else(|| anyhow::anyhow!("patch manifest block size overflows"))?;
+
+    let mut entries = vec![];
+    for block in &header.blocks {
+        let block_start = block.block_offset as u64;
+        let block_end = block_start
+            .checked_add(block_size)
+            .ok_or_else(|| anyhow::anyhow!("patch manifest block at {} overflows", block_start))?;
+        r.seek(SeekFrom::Start(block_start))?;
+
+        while r.position() < block_end {
+            let num_patches = r.read_u8()?;
+            if num_patches == 0 {
+                // A zero patch count terminates the block
+                break;
+            }
+
+            let entry = repr::FileEntry::read_args(&mut r, (num_patches,))?;
+
+            entries.push(Entry {
+                target_ckey: entry.target_ckey,
+                decoded_size: entry.decoded_size.get(),
+                patches: entry
+                    .patches
+                    .into_iter()
+                    .map(|p| Patch {
+                        source_ekey: p.source_ekey,
+                        source_decoded_size: p.source_decoded_size.get(),
+                        patch_ekey: p.patch_ekey,
+                        patch_size: p.patch_size,
+                        patch_index: p.patch_index,
+                    })
+                    .collect(),
+            });
+        }
+    }
+
+    Ok(PatchManifest {
+        version: header.version,
+        flags: header.flags,
+        encoding_ckey: header.encoding_ckey,
+        encoding_ekey: header.encoding_ekey,
+        encoding_decoded_size: header.encoding_decoded_size,
+        encoding_encoded_size: header.encoding_encoded_size,
+        encoding_espec: String::from_utf8_lossy(&header.encoding_espec).into_owned(),
+        entries,
+    })
+}
+
+mod repr {
+    use binrw::BinRead;
+
+    use crate::{
+        binrw_ext::u40,
+        tact::{ContentKey, EncodingKey},
+    };
+
+    #[derive(BinRead)]
+    #[br(big, magic = b"PA")]
+    pub struct Header {
+        pub version: u8,
+        pub file_key_size: u8,
+        pub old_key_size: u8,
+        pub patch_key_size: u8,
+        pub block_size_bits: u8,
+        pub _block_count: u16,
+        pub flags: u8,
+        pub encoding_ckey: ContentKey,
+        pub encoding_ekey: EncodingKey,
+        pub encoding_decoded_size: u32,
+        pub encoding_encoded_size: u32,
+        pub _espec_length: u8,
+        #[br(count = _espec_length)]
+        pub encoding_espec: Vec<u8>,
+
+        #[br(args { count: _block_count as usize, inner: (file_key_size,) })]
+        pub blocks: Vec<BlockHeader>,
+    }
+
+    #[derive(BinRead)]
+    #[br(big, import(file_key_size: u8))]
+    pub struct BlockHeader {
+        #[br(count = file_key_size)]
+        pub _last_file_ckey: Vec<u8>,
+        pub _block_md5: [u8; 16],
+        pub block_offset: u32,
+    }
+
+    #[derive(BinRead)]
+    #[br(big, import(num_patches: u8))]
+    pub struct FileEntry {
+        pub target_ckey: ContentKey,
+        pub decoded_size: u40,
+
+        #[br(count = num_patches)]
+        pub patches: Vec<FilePatch>,
+    }
+
+    #[derive(BinRead)]
+    #[br(big)]
+    pub struct FilePatch {
+        pub source_ekey: EncodingKey,
+        pub source_decoded_size: u40,
+        pub patch_ekey: EncodingKey,
+        pub patch_size: u32,
+        pub patch_index: u8,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blte::compute_md5;
+
+    fn push_u40(buf: &mut Vec<u8>, v: u64) {
+        buf.extend_from_slice(&v.to_be_bytes()[3..]);
+    }
+
+    #[test]
+    fn test_parse_patch_manifest() {
+        let espec = b"b:{22=n,54=z,192=n,24576=n,128512=n,*=z}";
+        let target = ContentKey::from_slice(&[0x11; 16]);
+        let source = EncodingKey::from_slice(&[0x22; 16]);
+        let patch = EncodingKey::from_slice(&[0x33; 16]);
+
+        // One block holding one file with one patch, terminated by a zero patch count
+        let mut block = vec![1];
+        block.extend_from_slice(target.as_slice());
+        push_u40(&mut block, 1000);
+        block.extend_from_slice(source.as_slice());
+        push_u40(&mut block, 900);
+        block.extend_from_slice(patch.as_slice());
+        block.extend_from_slice(&120u32.to_be_bytes());
+        block.push(1);
+        block.push(0);
+
+        let mut data = b"PA".to_vec();
+        data.extend_from_slice(&[2, 16, 16, 16, 16]);
+        data.extend_from_slice(&1u16.to_be_bytes());
+        data.push(0);
+        data.extend_from_slice(&[0xaa; 16]);
+        data.extend_from_slice(&[0xbb; 16]);
+        data.extend_from_slice(&5000u32.to_be_bytes());
+        data.extend_from_slice(&2000u32.to_be_bytes());
+        data.push(espec.len() as u8);
+        data.extend_from_slice(espec);
+
+        // Block header: last file ckey, block md5, offset
+        let block_offset = data.len() + 16 + 16 + 4;
+        data.extend_from_slice(target.as_slice());
+        data.extend_from_slice(&compute_md5(&block));
+        data.extend_from_slice(&(block_offset as u32).to_be_bytes());
+        data.extend_from_slice(&block);
+
+        let manifest = parse_patch_manifest(&TactKeys::default(), &data).unwrap();
+        assert_eq!((2, 0), (manifest.version, manifest.flags));
+        assert_eq!(ContentKey::from_slice(&[0xaa; 16]), manifest.encoding_ckey);
+        assert_eq!(EncodingKey::from_slice(&[0xbb; 16]), manifest.encoding_ekey);
+        assert_eq!(
+            (5000, 2000),
+            (
+                manifest.encoding_decoded_size,
+                manifest.encoding_encoded_size
+            )
+        );
+        assert_eq!(std::str::from_utf8(espec).unwrap(), manifest.encoding_espec);
+
+        assert_eq!(1, manifest.entries.len());
+        let entry = &manifest.entries[0];
+        assert_eq!((&target, 1000), (&entry.target_ckey, entry.decoded_size));
+        assert_eq!(1, entry.patches.len());
+        let p = &entry.patches[0];
+        assert_eq!((&source, 900), (&p.source_ekey, p.source_decoded_size));
+        assert_eq!(
+            (&patch, 120, 1),
+            (&p.patch_ekey, p.patch_size, p.patch_index)
+        );
+
+        // Block size bits that would overflow the shift are rejected
+        for bits in [33, 64, 255] {
+            let mut data = data.clone();
+            data[6] = bits;
+            let err = parse_patch_manifest(&TactKeys::default(), &data).unwrap_err();
+            assert!(err.to_string().contains("block size"), "{}", err);
+        }
+    }
+}
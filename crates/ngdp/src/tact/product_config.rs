@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use serde::Deserialize;
+
+use super::{cdn::CDNClient, ContentKey};
+
+/// Product level settings, referenced by the `product_config` column of a product's versions
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ProductConfig {
+    pub all: ProductConfigSection,
+    /// Platform specific overrides, keyed by platform name, e.g. `win` or `mac`
+    pub platform: HashMap<String, ProductConfigSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ProductConfigSection {
+    pub config: ProductSettings,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct ProductSettings {
+    pub product: Option<String>,
+    pub data_dir: Option<String>,
+    pub decryption_key_name: Option<String>,
+    pub shared_container_default_subfolder: Option<String>,
+    pub supported_locales: Vec<String>,
+    pub display_locales: Vec<String>,
+}
+
+impl ProductConfig {
+    /// Name of the key ring used to decrypt the product's build configs, if it has one
+    pub fn decryption_key_name(&self) -> Option<&str> {
+        self.all.config.decryption_key_name.as_deref()
+    }
+}
+
+pub fn parse_product_config(input: &str) -> Result<ProductConfig, anyhow::Error> {
+    Ok(serde_json::from_str(input)?)
+}
+
+pub fn fetch_product_config(
+    cdn: &CDNClient,
+    key: &ContentKey,
+) -> Result<ProductConfig, anyhow::Error> {
+    let text = cdn.read_product_config(key)?.read_string()?;
+    parse_product_config(&text).with_context(|| format!("parsing product config {:?}", key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_product_config() {
+        let config = parse_product_config(
+            r#"{
+                "all": {
+                    "config": {
+                        "data_dir": "Data/",
+                        "decryption_key_name": "wow",
+                        "product": "WoW",
+                        "supported_locales": ["enUS", "deDE"],
+                        "enable_block_copy_patch": true
+                    }
+                },
+                "platform": {
+                    "mac": { "config": { "shared_container_default_subfolder": "_retail_" } }
+                },
+                "enUS": { "config": { "install": [] } }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(Some("wow"), config.decryption_key_name());
+        assert_eq!(vec!["enUS", "deDE"], config.all.config.supported_locales);
+        assert_eq!(
+            Some("_retail_"),
+            config.platform["mac"]
+                .config
+                .shared_container_default_subfolder
+                .as_deref()
+        );
+    }
+}
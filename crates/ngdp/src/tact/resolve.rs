@@ -0,0 +1,93 @@
+use anyhow::Context;
+use ribbit::{version_for_region, versions, Server, Version};
+
+use super::{
+    cdn::CDNClient,
+    config::{
+        fetch_patch_config, parse_build_config, parse_cdn_config, BuildConfig, CDNConfig,
+        PatchConfig,
+    },
+    product_config::{fetch_product_config, ProductConfig},
+    ContentKey,
+};
+
+/// The current build of a product in a region, along with a CDN client to fetch it from
+pub struct ResolvedBuild {
+    pub version: Version,
+    pub cdn: CDNClient,
+    pub build_config_text: String,
+    pub cdn_config_text: String,
+}
+
+impl ResolvedBuild {
+    pub fn build_config(&self) -> Result<BuildConfig<'_>, anyhow::Error> {
+        parse_build_config(&self.build_config_text)
+    }
+
+    pub fn cdn_config(&self) -> Result<CDNConfig, anyhow::Error> {
+        parse_cdn_config(&self.cdn_config_text)
+    }
+
+    pub fn product_config(&self) -> Result<ProductConfig, anyhow::Error> {
+        fetch_product_config(&self.cdn, &ContentKey::parse(&self.version.product_config)?)
+    }
+
+    /// Fetches the build's patch config, if it has one
+    pub fn patch_config(&self) -> Result<Option<PatchConfig>, anyhow::Error> {
+        self.build_config()?
+            .patch_config
+            .map(|key| fetch_patch_config(&self.cdn, &key))
+            .transpose()
+    }
+}
+
+/// Looks up the current version and CDNs of `product` in `region`, and fetches its build and CDN configs
+pub fn resolve_build(
+    server: Server,
+    region: &str,
+    product: &str,
+    cdn_override: Option<String>,
+) -> Result<ResolvedBuild, anyhow::Error> {
+    let version = version_for_region(&versions(server, product)?, region)
+        .with_context(|| format!("looking up version of {}", product))?
+        .clone();
+
+    let cdn = CDNClient::from_ribbit(server, region, product, cdn_override)?;
+
+    let build_config_text = cdn
+        .read_config(&ContentKey::parse(&version.build_config)?)?
+        .read_string()?;
+    let cdn_config_text = cdn
+        .read_config(&ContentKey::parse(&version.cdn_config)?)?
+        .read_string()?;
+
+    let res = ResolvedBuild {
+        version,
+        cdn,
+        build_config_text,
+        cdn_config_text,
+    };
+    // Make sure the configs are valid up front
+    res.build_config()?;
+    res.cdn_config()?;
+    Ok(res)
+}
+
+/// Fetches the build config of every build the CDN still hosts, checking each one parses
+pub fn fetch_cdn_builds(
+    cdn: &CDNClient,
+    cdn_config: &CDNConfig,
+) -> Result<Vec<(ContentKey, String)>, anyhow::Error> {
+    cdn_config
+        .builds
+        .iter()
+        .map(|key| {
+            || -> Result<_, anyhow::Error> {
+                let text = cdn.read_config(key)?.read_string()?;
+                parse_build_config(&text)?;
+                Ok((key.clone(), text))
+            }()
+            .with_context(|| format!("fetching build config {:?}", key))
+        })
+        .collect()
+}
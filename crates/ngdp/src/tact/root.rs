@@ -3,9 +3,9 @@ use std::fmt::Debug;
 use std::io::Cursor;
 
 use binrw::BinRead;
-use lookup3::hashlittle2;
 
 use super::ContentKey;
+use crate::util::{normalize_path, PathStyle};
 
 #[derive(Debug)]
 pub struct Root {
@@ -17,6 +17,19 @@ pub struct Root {
     pub record_types_by_name_hash: HashMap<u64, Vec<u32>>,
 }
 
+/// Counts from a root's header, and counted from its parsed contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RootStats {
+    pub total_file_count: u32,
+    pub named_file_count: u32,
+    /// Records in every block, so a file with several locales counts several times
+    pub records: usize,
+    pub named_records: usize,
+    pub distinct_file_ids: usize,
+    pub distinct_name_hashes: usize,
+    pub record_types: usize,
+}
+
 impl Root {
     // TODO: Construct this in a streaming manner if memory becomes an issue
     fn new(total_file_count: u32, named_file_count: u32, blocks: Vec<repr::Block>) -> Root {
@@ -71,9 +84,14 @@ impl Root {
                 }
             }
 
+            let (content_flags, unknown_content_bits) =
+                ContentFlags::from_bits_lenient(block.flags);
+            let (locale_flags, unknown_locale_bits) = LocaleFlags::from_bits_lenient(block.locale);
             record_types.push(RecordType {
-                content_flags: block.flags,
-                locale_flags: block.locale,
+                content_flags,
+                locale_flags,
+                unknown_content_bits,
+                unknown_locale_bits,
                 records_by_file_data_id,
                 file_data_id_by_name_hash,
             });
@@ -88,16 +106,36 @@ impl Root {
         }
     }
 
+    pub fn stats(&self) -> RootStats {
+        let records = || {
+            self.record_types
+                .iter()
+                .flat_map(|rt| rt.records_by_file_data_id.values())
+        };
+        RootStats {
+            total_file_count: self.total_file_count,
+            named_file_count: self.named_file_count,
+            records: records().count(),
+            named_records: records().filter(|r| r.name_hash.is_some()).count(),
+            distinct_file_ids: self.record_types_by_file_data_id.len(),
+            distinct_name_hashes: self.record_types_by_name_hash.len(),
+            record_types: self.record_types.len(),
+        }
+    }
+
     pub fn lookup_path(&self, path: &str) -> Option<&[u32]> {
-        let hash = Self::hashpath(path);
-        println!("hash: {hash:08x}");
+        self.lookup_by_name_hash(Self::hashpath(path))
+    }
+
+    /// Indexes of the record types that have a file with the given name hash, see `hashpath`
+    pub fn lookup_by_name_hash(&self, hash: u64) -> Option<&[u32]> {
         self.record_types_by_name_hash.get(&hash).map(Vec::as_slice)
     }
 
-    fn hashpath(path: &str) -> u64 {
-        let path = path.to_uppercase().replace('/', "\\");
-        let (pc, pb) = hashlittle2(path.as_bytes(), 0, 0);
-        pb as u64 | ((pc as u64) << 32)
+    /// The name hash root files use for a path: the 64 bit `hashlittle2` of the uppercased,
+    /// backslash separated path. This is the only name hash root uses, `util::sstrhash` is unrelated.
+    pub fn hashpath(path: &str) -> u64 {
+        lookup3::hashpath(normalize_path(path, PathStyle::ForHash).as_bytes())
     }
 
     pub fn lookup_by_fileid_and_flags(
@@ -111,21 +149,95 @@ impl Root {
             .iter()
             .copied()
             .map(|r| &self.record_types[r as usize])
-            .find(|rec| {
-                rec.content_flags.contains(content_flags) && rec.locale_flags.contains(locale_flags)
-            })?;
+            .find(|rec| rec.matches(content_flags, locale_flags))?;
         let record = rec_type.records_by_file_data_id.get(&file_id)?;
         Some(record)
     }
+
+    /// The content key of a file regardless of flags, for callers that just need any variant
+    pub fn ckey_for_id(&self, file_id: i32) -> Option<&ContentKey> {
+        self.record_for_id(file_id, None).map(|r| &r.content_key)
+    }
+
+    /// A record for a file regardless of content flags. Prefers a variant for `locale` if given,
+    /// falling back to the first variant
+    pub fn record_for_id(&self, file_id: i32, locale: Option<LocaleFlags>) -> Option<&Record> {
+        let records = self.all_records_for_id(file_id);
+        locale
+            .and_then(|locale| {
+                records
+                    .iter()
+                    .find(|(rec_type, _)| rec_type.matches(ContentFlags::empty(), locale))
+            })
+            .or_else(|| records.first())
+            .map(|(_, record)| *record)
+    }
+
+    /// Every variant of a file, e.g. one per locale, along with the block it's in
+    pub fn all_records_for_id(&self, file_id: i32) -> Vec<(&RecordType, &Record)> {
+        let Some(rec_types) = self.record_types_by_file_data_id.get(&file_id) else {
+            return vec![];
+        };
+        rec_types
+            .iter()
+            .map(|r| &self.record_types[*r as usize])
+            .filter_map(|rec_type| {
+                Some((rec_type, rec_type.records_by_file_data_id.get(&file_id)?))
+            })
+            .collect()
+    }
+
+    /// Like `lookup_by_fileid_and_flags`, but finds the file by the hash of its path.
+    /// Useful for files missing from the listfile.
+    pub fn lookup_by_name_and_flags(
+        &self,
+        path: &str,
+        content_flags: ContentFlags,
+        locale_flags: LocaleFlags,
+    ) -> Option<&Record> {
+        let hash = Self::hashpath(path);
+        let rec_types = self.record_types_by_name_hash.get(&hash)?;
+        rec_types
+            .iter()
+            .copied()
+            .map(|r| &self.record_types[r as usize])
+            .filter(|rec| rec.matches(content_flags, locale_flags))
+            .find_map(|rec| {
+                let file_id = rec.file_data_id_by_name_hash.get(&hash)?;
+                rec.records_by_file_data_id.get(file_id)
+            })
+    }
+
+    pub fn iter_records(&self) -> impl Iterator<Item = (i32, &RecordType, &Record)> {
+        self.record_types.iter().flat_map(|rec_type| {
+            rec_type
+                .records_by_file_data_id
+                .iter()
+                .map(move |(file_id, record)| (*file_id, rec_type, record))
+        })
+    }
 }
 
 pub struct RecordType {
     pub content_flags: ContentFlags,
     pub locale_flags: LocaleFlags,
+    /// Content flag bits not in `ContentFlags`, dropped from `content_flags`
+    pub unknown_content_bits: u32,
+    /// Locale flag bits not in `LocaleFlags`, dropped from `locale_flags`
+    pub unknown_locale_bits: u32,
     pub records_by_file_data_id: HashMap<i32, Record>,
     pub file_data_id_by_name_hash: HashMap<u64, i32>,
 }
 
+impl RecordType {
+    /// Whether this block has the given content flags and applies to the given locale.
+    /// Blocks without any locale flags apply to every locale.
+    pub fn matches(&self, content_flags: ContentFlags, locale_flags: LocaleFlags) -> bool {
+        self.content_flags.contains(content_flags)
+            && (self.locale_flags.is_empty() || self.locale_flags.contains(locale_flags))
+    }
+}
+
 impl std::fmt::Debug for RecordType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
         f.debug_struct("RecordType")
@@ -143,11 +255,21 @@ pub struct Record {
 
 pub fn parse_root(content: &[u8]) -> Result<Root, anyhow::Error> {
     let res = repr::Root::read(&mut Cursor::new(content))?;
-    Ok(Root::new(
-        res.total_file_count,
-        res.named_file_count,
-        res.blocks,
-    ))
+    let root = Root::new(res.total_file_count, res.named_file_count, res.blocks);
+
+    let stats = root.stats();
+    if stats.records != root.total_file_count as usize
+        || stats.named_records != root.named_file_count as usize
+    {
+        anyhow::bail!(
+            "root is truncated or corrupt - header says {} files ({} named), found {} ({} named)",
+            root.total_file_count,
+            root.named_file_count,
+            stats.records,
+            stats.named_records
+        );
+    }
+    Ok(root)
 }
 
 bitflags::bitflags! {
@@ -170,6 +292,37 @@ bitflags::bitflags! {
     }
 }
 
+impl LocaleFlags {
+    /// Splits off bits we don't know about instead of failing, so newer roots can still be read.
+    /// Returns the known flags and the unknown bits
+    fn from_bits_lenient(bits: u32) -> (LocaleFlags, u32) {
+        let flags = LocaleFlags::from_bits_truncate(bits);
+        (flags, bits & !flags.bits())
+    }
+
+    pub fn from_name(name: &str) -> Option<LocaleFlags> {
+        let flag = match name {
+            "enUS" => LocaleFlags::EN_US,
+            "koKR" => LocaleFlags::KO_KR,
+            "frFR" => LocaleFlags::FR_FR,
+            "deDE" => LocaleFlags::DE_DE,
+            "zhCN" => LocaleFlags::ZH_CN,
+            "esES" => LocaleFlags::ES_ES,
+            "zhTW" => LocaleFlags::ZH_TW,
+            "enGB" => LocaleFlags::EN_GB,
+            "enCN" => LocaleFlags::EN_CN,
+            "enTW" => LocaleFlags::EN_TW,
+            "esMX" => LocaleFlags::ES_MX,
+            "ruRU" => LocaleFlags::RU_RU,
+            "ptBR" => LocaleFlags::PT_BR,
+            "itIT" => LocaleFlags::IT_IT,
+            "ptPT" => LocaleFlags::PT_PT,
+            _ => return None,
+        };
+        Some(flag)
+    }
+}
+
 bitflags::bitflags! {
     pub struct ContentFlags: u32 {
         const LOAD_ON_WINDOWS     =        0x8;            // macOS clients do not read block if flags & 0x108 != 0
@@ -194,13 +347,22 @@ bitflags::bitflags! {
     }
 }
 
+impl ContentFlags {
+    /// Splits off bits we don't know about instead of failing, so newer roots can still be read.
+    /// Returns the known flags and the unknown bits
+    fn from_bits_lenient(bits: u32) -> (ContentFlags, u32) {
+        let flags = ContentFlags::from_bits_truncate(bits);
+        (flags, bits & !flags.bits())
+    }
+}
+
 // TODO: Support pre 8.2 representation
 mod repr {
     use binrw::{until_eof, BinRead};
 
     use crate::tact::ContentKey;
 
-    use super::{ContentFlags, LocaleFlags};
+    use super::ContentFlags;
 
     #[derive(BinRead)]
     #[br(little, magic = b"TSFM")]
@@ -217,11 +379,8 @@ mod repr {
     pub struct Block {
         pub num_records: u32,
 
-        #[br(try_map = |x| ContentFlags::from_bits(x).ok_or_else(|| anyhow::anyhow!("ContentFlags: invalid bits")))]
-        pub flags: ContentFlags,
-
-        #[br(try_map = |x| LocaleFlags::from_bits(x).ok_or_else(|| anyhow::anyhow!("LocaleFlags: invalid bits")))]
-        pub locale: LocaleFlags,
+        pub flags: u32,
+        pub locale: u32,
 
         #[br(count = num_records)]
         pub file_data_id_deltas: Vec<i32>,
@@ -229,7 +388,121 @@ mod repr {
         #[br(count = num_records)]
         pub content_keys: Vec<ContentKey>,
 
-        #[br(if(!(allow_non_named_files && flags.contains(ContentFlags::NO_NAME_HASH))), count = num_records)]
+        #[br(if(!(allow_non_named_files && flags & ContentFlags::NO_NAME_HASH.bits() != 0)), count = num_records)]
         pub name_hashes: Vec<u64>,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_flag_bits() {
+        let mut data = vec![];
+        data.extend_from_slice(b"TSFM");
+        data.extend_from_slice(&1u32.to_le_bytes()); // total_file_count
+        data.extend_from_slice(&1u32.to_le_bytes()); // named_file_count
+
+        data.extend_from_slice(&1u32.to_le_bytes()); // num_records
+        data.extend_from_slice(&(ContentFlags::LOAD_ON_WINDOWS.bits() | 0x1).to_le_bytes());
+        data.extend_from_slice(&(LocaleFlags::EN_US.bits() | 0x8000_0000).to_le_bytes());
+        data.extend_from_slice(&5i32.to_le_bytes()); // file data id delta
+        data.extend_from_slice(&[0xAA; 16]); // content key
+        data.extend_from_slice(&0x1234u64.to_le_bytes()); // name hash
+
+        let root = parse_root(&data).unwrap();
+        assert_eq!(root.record_types.len(), 1);
+
+        let rec_type = &root.record_types[0];
+        assert_eq!(rec_type.content_flags, ContentFlags::LOAD_ON_WINDOWS);
+        assert_eq!(rec_type.locale_flags, LocaleFlags::EN_US);
+        assert_eq!(rec_type.unknown_content_bits, 0x1);
+        assert_eq!(rec_type.unknown_locale_bits, 0x8000_0000);
+
+        let record = root
+            .lookup_by_fileid_and_flags(5, ContentFlags::LOAD_ON_WINDOWS, LocaleFlags::EN_US)
+            .unwrap();
+        assert_eq!(record.name_hash, Some(0x1234));
+    }
+
+    #[test]
+    fn test_all_records_for_id() {
+        let mut data = vec![];
+        data.extend_from_slice(b"TSFM");
+        data.extend_from_slice(&2u32.to_le_bytes()); // total_file_count
+        data.extend_from_slice(&2u32.to_le_bytes()); // named_file_count
+
+        for (locale, ckey) in [(LocaleFlags::EN_US, 0x11), (LocaleFlags::DE_DE, 0x22)] {
+            data.extend_from_slice(&1u32.to_le_bytes()); // num_records
+            data.extend_from_slice(&ContentFlags::empty().bits().to_le_bytes());
+            data.extend_from_slice(&locale.bits().to_le_bytes());
+            data.extend_from_slice(&3i32.to_le_bytes()); // file data id delta
+            data.extend_from_slice(&[ckey; 16]); // content key
+            data.extend_from_slice(&0x1234u64.to_le_bytes()); // name hash
+        }
+
+        let root = parse_root(&data).unwrap();
+        let variants = root.all_records_for_id(3);
+        assert_eq!(2, variants.len());
+        assert_eq!(LocaleFlags::EN_US, variants[0].0.locale_flags);
+        assert_eq!(
+            ContentKey::from_slice(&[0x11; 16]),
+            variants[0].1.content_key
+        );
+        assert_eq!(LocaleFlags::DE_DE, variants[1].0.locale_flags);
+        assert_eq!(
+            ContentKey::from_slice(&[0x22; 16]),
+            variants[1].1.content_key
+        );
+
+        assert!(root.all_records_for_id(4).is_empty());
+
+        let en_us = ContentKey::from_slice(&[0x11; 16]);
+        let de_de = ContentKey::from_slice(&[0x22; 16]);
+        assert_eq!(Some(&en_us), root.ckey_for_id(3));
+        assert_eq!(None, root.ckey_for_id(4));
+        let record = root.record_for_id(3, Some(LocaleFlags::DE_DE)).unwrap();
+        assert_eq!(de_de, record.content_key);
+        // No variant for the hinted locale, so any will do
+        let record = root.record_for_id(3, Some(LocaleFlags::FR_FR)).unwrap();
+        assert_eq!(en_us, record.content_key);
+
+        let stats = root.stats();
+        assert_eq!(2, stats.records);
+        assert_eq!(2, stats.named_records);
+        assert_eq!(1, stats.distinct_file_ids);
+        assert_eq!(1, stats.distinct_name_hashes);
+        assert_eq!(2, stats.record_types);
+
+        // A header claiming more files than there are
+        data[4..8].copy_from_slice(&3u32.to_le_bytes());
+        data[8..12].copy_from_slice(&3u32.to_le_bytes());
+        let err = parse_root(&data).unwrap_err();
+        assert!(err.to_string().contains("header says 3 files"));
+    }
+
+    #[test]
+    fn test_zero_locale_block_matches_any_locale() {
+        let mut data = vec![];
+        data.extend_from_slice(b"TSFM");
+        data.extend_from_slice(&1u32.to_le_bytes()); // total_file_count
+        data.extend_from_slice(&1u32.to_le_bytes()); // named_file_count
+
+        data.extend_from_slice(&1u32.to_le_bytes()); // num_records
+        data.extend_from_slice(&ContentFlags::empty().bits().to_le_bytes());
+        data.extend_from_slice(&LocaleFlags::empty().bits().to_le_bytes());
+        data.extend_from_slice(&7i32.to_le_bytes()); // file data id delta
+        data.extend_from_slice(&[0xBB; 16]); // content key
+        data.extend_from_slice(&Root::hashpath("a/b.txt").to_le_bytes());
+
+        let root = parse_root(&data).unwrap();
+        let record = root
+            .lookup_by_fileid_and_flags(7, ContentFlags::empty(), LocaleFlags::EN_GB)
+            .unwrap();
+        assert_eq!(record.content_key, ContentKey::from_slice(&[0xBB; 16]));
+        assert!(root
+            .lookup_by_name_and_flags("a/b.txt", ContentFlags::empty(), LocaleFlags::EN_GB)
+            .is_some());
+    }
+}
@@ -1,4 +1,4 @@
-use std::{borrow::Cow, fmt::Write, print, println, unreachable};
+use std::{borrow::Cow, fmt::Write, print, unreachable};
 
 fn upper_backslash(c: u8) -> u8 {
     if c == b'/' {
@@ -13,19 +13,25 @@ const S_HASHTABLE: [u32; 16] = [
     0xD6057177, 0x904ECE93, 0x1C38024F, 0x98FD323B, 0xE3061AE7, 0xA39B0FA1, 0x9797F25F, 0xE4444563,
 ];
 
+/// Storm's 32 bit `SStrHash`, used by some client side name tables.
+/// Not used by root files - their name hashes are the 64 bit `hashlittle2` of the path, see `Root::hashpath`.
 pub fn sstrhash(val: &[u8], no_caseconv: bool, mut seed: u32) -> u32 {
     if seed == 0 {
         seed = 0x7FED7FED;
     }
 
-    let mut shift = 0xEEEEEEEE;
+    let mut shift: u32 = 0xEEEEEEEE;
     for mut c in val.iter().copied() {
         if !no_caseconv {
             c = upper_backslash(c);
         }
 
-        seed = (S_HASHTABLE[c as usize >> 4] - S_HASHTABLE[c as usize & 0xF]) ^ (shift + seed);
-        shift = c as u32 + seed + 33 * shift + 3;
+        seed = (S_HASHTABLE[c as usize >> 4].wrapping_sub(S_HASHTABLE[c as usize & 0xF]))
+            ^ shift.wrapping_add(seed);
+        shift = (c as u32)
+            .wrapping_add(seed)
+            .wrapping_add(shift.wrapping_mul(33))
+            .wrapping_add(3);
     }
 
     if seed != 0 {
@@ -35,6 +41,22 @@ pub fn sstrhash(val: &[u8], no_caseconv: bool, mut seed: u32) -> u32 {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathStyle {
+    /// Uppercase with backslashes, as hashed into root name hashes
+    ForHash,
+    /// Lowercase with forward slashes, as used by listfiles and installed files
+    ForFs,
+}
+
+/// Normalizes the case and separators of a game path, so the same file compares equal everywhere
+pub fn normalize_path(path: &str, style: PathStyle) -> String {
+    match style {
+        PathStyle::ForHash => path.to_uppercase().replace('/', "\\"),
+        PathStyle::ForFs => path.to_lowercase().replace('\\', "/"),
+    }
+}
+
 pub fn parse_hex_bytes<const N: usize>(s: &str) -> Option<[u8; N]> {
     if s.len() != N * 2 {
         return None;
@@ -63,61 +85,187 @@ pub fn format_hex_bytes_le<const N: usize>(val: &[u8; N]) -> String {
     res
 }
 
-pub fn asciiz(val: &[u8]) -> Cow<str> {
+fn until_zero(val: &[u8]) -> &[u8] {
     let first_zero = val.iter().position(|&b| b == 0).unwrap_or(val.len());
-    let val = &val[..first_zero];
-    String::from_utf8_lossy(val)
+    &val[..first_zero]
+}
+
+/// Reads a zero terminated string, replacing invalid UTF-8 sequences. Prefer `asciiz_strict` when corruption matters.
+pub fn asciiz(val: &[u8]) -> Cow<str> {
+    String::from_utf8_lossy(until_zero(val))
+}
+
+/// Reads a zero terminated string, returning `None` if it isn't valid UTF-8
+pub fn asciiz_strict(val: &[u8]) -> Option<&str> {
+    std::str::from_utf8(until_zero(val)).ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    /// UTF-8, replacing invalid sequences
+    Utf8Lossy,
+    Latin1,
+    Windows1252,
+}
+
+/// Reads a zero terminated string in the given encoding
+pub fn asciiz_with(val: &[u8], encoding: TextEncoding) -> Cow<'_, str> {
+    let val = until_zero(val);
+    match encoding {
+        TextEncoding::Utf8Lossy => String::from_utf8_lossy(val),
+        _ if val.is_ascii() => Cow::Borrowed(std::str::from_utf8(val).unwrap()),
+        TextEncoding::Latin1 => Cow::Owned(val.iter().map(|&b| b as char).collect()),
+        TextEncoding::Windows1252 => Cow::Owned(val.iter().map(|&b| cp1252_char(b)).collect()),
+    }
+}
+
+fn cp1252_char(b: u8) -> char {
+    // Only 0x80..=0x9f differ from latin-1. Undefined bytes map to their latin-1 control character.
+    const HIGH: [char; 32] = [
+        '\u{20ac}', '\u{81}', '\u{201a}', '\u{192}', '\u{201e}', '\u{2026}', '\u{2020}',
+        '\u{2021}', '\u{2c6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8d}', '\u{17d}',
+        '\u{8f}', '\u{90}', '\u{2018}', '\u{2019}', '\u{201c}', '\u{201d}', '\u{2022}', '\u{2013}',
+        '\u{2014}', '\u{2dc}', '\u{2122}', '\u{161}', '\u{203a}', '\u{153}', '\u{9d}', '\u{17e}',
+        '\u{178}',
+    ];
+    match b {
+        0x80..=0x9f => HIGH[(b - 0x80) as usize],
+        b => b as char,
+    }
 }
 
 pub fn hexdump(val: &[u8], start: usize, end: usize) {
+    print!("{}", hexdump_string(&val[start..end], start));
+}
+
+/// Formats `data` as 16 byte lines of offset, hex and printable ASCII, with offsets starting at
+/// `base_offset`. Runs of all zero lines are collapsed into a single line.
+pub fn hexdump_string(data: &[u8], base_offset: usize) -> String {
     const CHUNK_SIZE: usize = 16;
 
-    let mut skipping_zeroes = false;
-    let mut zeroes_start = 0;
-    for (chunk_idx, chunk) in val[start..end].chunks(CHUNK_SIZE).enumerate() {
-        let chunk_start = start + CHUNK_SIZE * chunk_idx;
+    let mut res = String::new();
+    let mut zeroes_start = None;
+    for (chunk_idx, chunk) in data.chunks(CHUNK_SIZE).enumerate() {
+        let chunk_start = base_offset + CHUNK_SIZE * chunk_idx;
 
         if chunk.iter().all(|b| *b == 0) {
-            if !skipping_zeroes {
-                skipping_zeroes = true;
-                zeroes_start = chunk_start;
-            }
+            zeroes_start.get_or_insert(chunk_start);
             continue;
         }
 
-        if skipping_zeroes {
-            println!(
-                "{:08x} => {:08x} was zero ({} bytes)",
-                zeroes_start,
-                chunk_start,
-                chunk_start - zeroes_start
-            );
-            skipping_zeroes = false;
+        if let Some(zeroes_start) = zeroes_start.take() {
+            write_zeroes(&mut res, zeroes_start, chunk_start);
         }
 
-        print!("{:08x}: ", chunk_start);
+        write!(res, "{:08x}: ", chunk_start).unwrap();
 
+        let hex_start = res.len();
         for word in chunk.chunks(2) {
             match word {
-                [a, b] => print!("{:02x}{:02x} ", a, b),
-                [a] => print!("{:02x}   ", a),
+                [a, b] => write!(res, "{:02x}{:02x} ", a, b).unwrap(),
+                [a] => write!(res, "{:02x}   ", a).unwrap(),
                 _ => unreachable!(),
             }
         }
 
-        let bytes_left = CHUNK_SIZE - chunk.len();
-        for _ in 0..(bytes_left + bytes_left / 2) {
-            print!(" ");
+        // Pad short lines so the ASCII column lines up, each word being 4 hex digits and a space
+        let hex_width = CHUNK_SIZE / 2 * 5;
+        for _ in res.len() - hex_start..hex_width {
+            res.push(' ');
         }
 
         for byte in chunk {
             if byte.is_ascii_graphic() {
-                print!("{}", *byte as char);
+                res.push(*byte as char);
             } else {
-                print!(".");
+                res.push('.');
             }
         }
 
-        println!();
+        res.push('\n');
+    }
+
+    if let Some(zeroes_start) = zeroes_start {
+        write_zeroes(&mut res, zeroes_start, base_offset + data.len());
+    }
+
+    res
+}
+
+fn write_zeroes(res: &mut String, start: usize, end: usize) {
+    writeln!(
+        res,
+        "{:08x} => {:08x} was zero ({} bytes)",
+        start,
+        end,
+        end - start
+    )
+    .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sstrhash_normalizes_path() {
+        let hash = sstrhash(b"Interface/Icons/a.blp", false, 0);
+        assert_eq!(hash, sstrhash(b"INTERFACE\\ICONS\\A.BLP", false, 0));
+        assert_eq!(hash, sstrhash(b"INTERFACE\\ICONS\\A.BLP", true, 0));
+        assert_ne!(hash, sstrhash(b"Interface/Icons/a.blp", true, 0));
+        assert_ne!(hash, sstrhash(b"Interface/Icons/a.blp", false, 1));
+    }
+
+    #[test]
+    fn test_hexdump_string() {
+        let mut data = b"hello, world!\x00\x01\xff".to_vec();
+        data.extend_from_slice(&[0; 32]);
+        data.extend_from_slice(b"end");
+
+        assert_eq!(
+            "00001000: 6865 6c6c 6f2c 2077 6f72 6c64 2100 01ff hello,.world!...\n\
+             00001010 => 00001030 was zero (32 bytes)\n\
+             00001030: 656e 64                                 end\n",
+            hexdump_string(&data, 0x1000)
+        );
+        assert_eq!(
+            "00000000 => 00000004 was zero (4 bytes)\n",
+            hexdump_string(&[0; 4], 0)
+        );
+    }
+
+    #[test]
+    fn test_asciiz_with() {
+        // ASCII reads the same in every encoding, and is borrowed up to the terminator
+        for encoding in [
+            TextEncoding::Utf8Lossy,
+            TextEncoding::Latin1,
+            TextEncoding::Windows1252,
+        ] {
+            let res = asciiz_with(b"Data\\enUS\0junk", encoding);
+            assert_eq!("Data\\enUS", res);
+            assert!(matches!(res, Cow::Borrowed(_)));
+            assert_eq!("", asciiz_with(b"\0abc", encoding));
+        }
+
+        let text = b"caf\xe9 \x80\x99\x81\0";
+        assert_eq!(
+            "caf\u{fffd} \u{fffd}\u{fffd}\u{fffd}",
+            asciiz_with(text, TextEncoding::Utf8Lossy)
+        );
+        assert_eq!(
+            "caf\u{e9} \u{80}\u{99}\u{81}",
+            asciiz_with(text, TextEncoding::Latin1)
+        );
+        assert_eq!(
+            "caf\u{e9} \u{20ac}\u{2122}\u{81}",
+            asciiz_with(text, TextEncoding::Windows1252)
+        );
+
+        // Without a terminator the whole slice is read
+        assert_eq!(
+            "\u{e9}t\u{e9}",
+            asciiz_with(b"\xe9t\xe9", TextEncoding::Latin1)
+        );
     }
 }
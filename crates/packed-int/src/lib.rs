@@ -0,0 +1,176 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+//! Big endian 24 and 40 bit integers stored as byte arrays, as found in CASC and TACT files.
+//! Only needs `core`; the `binrw` feature adds `BinRead`/`BinWrite` impls.
+
+use byteorder::{ByteOrder, BE};
+
+#[derive(Default, Clone, Copy)]
+#[cfg_attr(feature = "binrw", derive(binrw::BinRead, binrw::BinWrite))]
+#[allow(non_camel_case_types)]
+pub struct u24([u8; 3]);
+
+macro_rules! defer_fmt {
+    ($type:ty: $getter:ident => $($trait:ident),* ) => {$(
+        impl core::fmt::$trait for $type {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                core::fmt::$trait::fmt(&<$type>::$getter(self), f)
+            }
+        }
+    )*};
+}
+
+defer_fmt!(u24: get => Debug, Display, LowerHex, UpperHex);
+
+/// Error converting an integer that doesn't fit into a `u24` or `u40`
+#[derive(Debug)]
+pub struct OutOfRangeError {
+    pub value: u64,
+    pub type_name: &'static str,
+}
+
+impl core::fmt::Display for OutOfRangeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{} out of range for {}", self.value, self.type_name)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for OutOfRangeError {}
+
+impl u24 {
+    pub const ZERO: u24 = u24([0; 3]);
+    pub const MAX: u24 = u24([0xff; 3]);
+
+    /// Truncates `val` to 24 bits, see `checked_new` for a non-truncating version
+    pub fn new(val: u32) -> Self {
+        Self([
+            ((val >> 16) & 0xff) as u8,
+            ((val >> 8) & 0xff) as u8,
+            (val & 0xff) as u8,
+        ])
+    }
+
+    pub fn checked_new(val: u32) -> Option<Self> {
+        (val <= Self::MAX.get()).then(|| Self::new(val))
+    }
+
+    pub fn get(&self) -> u32 {
+        BE::read_u24(&self.0)
+    }
+}
+
+impl From<u16> for u24 {
+    fn from(val: u16) -> Self {
+        Self::new(val as u32)
+    }
+}
+
+impl TryFrom<u32> for u24 {
+    type Error = OutOfRangeError;
+
+    fn try_from(val: u32) -> Result<Self, Self::Error> {
+        Self::checked_new(val).ok_or(OutOfRangeError {
+            value: val as u64,
+            type_name: "u24",
+        })
+    }
+}
+
+impl From<u24> for u32 {
+    fn from(val: u24) -> Self {
+        val.get()
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+#[cfg_attr(feature = "binrw", derive(binrw::BinRead, binrw::BinWrite))]
+#[allow(non_camel_case_types)]
+pub struct u40([u8; 5]);
+
+defer_fmt!(u40: get => Debug, Display, LowerHex, UpperHex);
+
+impl u40 {
+    pub const ZERO: u40 = u40([0; 5]);
+    pub const MAX: u40 = u40([0xff; 5]);
+
+    const MASK_30: u64 = 0x3fffffff;
+    const MASK_10: u64 = 0x3ff;
+
+    pub fn checked_new(val: u64) -> Option<Self> {
+        if val > Self::MAX.get() {
+            return None;
+        }
+        let mut res = [0u8; 5];
+        BE::write_uint(&mut res, val, 5);
+        Some(u40(res))
+    }
+
+    pub fn get(&self) -> u64 {
+        BE::read_uint(&self.0, 5)
+    }
+
+    pub fn get_30_10(&self) -> (u32, u16) {
+        let val = self.get();
+        let large = (val & Self::MASK_30) as u32;
+        let small = ((val >> 30) & Self::MASK_10) as u16;
+        (large, small)
+    }
+
+    pub fn from_30_10(large: u32, small: u16) -> Option<u40> {
+        if large > Self::MASK_30 as u32 || small > Self::MASK_10 as u16 {
+            return None;
+        }
+        let val = (large as u64) & Self::MASK_30 | ((small as u64) & Self::MASK_10) << 30;
+
+        let mut res = [0u8; 5];
+        BE::write_uint(&mut res, val, 5);
+        Some(u40(res))
+    }
+}
+
+impl From<u32> for u40 {
+    fn from(val: u32) -> Self {
+        Self::checked_new(val as u64).unwrap()
+    }
+}
+
+impl TryFrom<u64> for u40 {
+    type Error = OutOfRangeError;
+
+    fn try_from(val: u64) -> Result<Self, Self::Error> {
+        Self::checked_new(val).ok_or(OutOfRangeError {
+            value: val,
+            type_name: "u40",
+        })
+    }
+}
+
+impl From<u40> for u64 {
+    fn from(val: u40) -> Self {
+        val.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversions() {
+        assert_eq!(0xabcdef, u32::from(u24::try_from(0xabcdefu32).unwrap()));
+        assert!(u24::try_from(0x1000000u32).is_err());
+        assert_eq!(0xffff, u24::from(0xffffu16).get());
+
+        assert_eq!(
+            0xff_ffff_ffff,
+            u64::from(u40::try_from(0xff_ffff_ffffu64).unwrap())
+        );
+        assert!(u40::try_from(0x100_0000_0000u64).is_err());
+        assert_eq!(u32::MAX as u64, u40::from(u32::MAX).get());
+
+        let packed = u40::from_30_10(0x3fffffff, 0x3ff).unwrap();
+        assert_eq!(u40::MAX.get(), packed.get());
+        assert_eq!((0x3fffffff, 0x3ff), packed.get_30_10());
+    }
+}
@@ -1,8 +1,16 @@
 use std::{
     io::{Read, Write},
-    net::TcpStream,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
+use anyhow::Context;
 use mail_parser::Message;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -11,6 +19,44 @@ pub enum Server {
     US,
 }
 
+impl Server {
+    /// Fetches the version for `region` of every product listed in the summary.
+    /// Products without data for that region, or whose versions couldn't be fetched, get `None`.
+    pub fn all_product_versions(
+        self,
+        region: &str,
+    ) -> Result<Vec<(String, Option<Version>)>, anyhow::Error> {
+        const MAX_CONCURRENT_REQUESTS: usize = 8;
+
+        let products = summary(self)?;
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(vec![None; products.len()]);
+
+        thread::scope(|s| {
+            for _ in 0..MAX_CONCURRENT_REQUESTS.min(products.len()) {
+                s.spawn(|| loop {
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    let product = match products.get(idx) {
+                        Some(endpoint) => &endpoint.product,
+                        None => break,
+                    };
+
+                    let version = versions(self, product)
+                        .ok()
+                        .and_then(|res| version_for_region(&res, region).ok().cloned());
+                    results.lock().unwrap()[idx] = version;
+                });
+            }
+        });
+
+        Ok(products
+            .into_iter()
+            .map(|e| e.product)
+            .zip(results.into_inner().unwrap())
+            .collect())
+    }
+}
+
 pub enum Command<'a> {
     Summary,
     ProductVersions { product: &'a str },
@@ -20,6 +66,30 @@ pub enum Command<'a> {
     Revocation { hash: &'a str },
 }
 
+/// Ribbit replies are small text files, anything bigger than this isn't a real server
+const MAX_RESPONSE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Applies to connecting, and separately to the whole reply being read
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Tries each address in turn, like `TcpStream::connect` but with a timeout per address
+fn connect_any(
+    addrs: impl Iterator<Item = SocketAddr>,
+    timeout: Duration,
+) -> Result<TcpStream, anyhow::Error> {
+    let mut last_error = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(match last_error {
+        Some(e) => e.into(),
+        None => anyhow::anyhow!("no addresses to connect to"),
+    })
+}
+
 pub fn execute_ribbit_command(server: Server, command: Command) -> Result<Vec<u8>, anyhow::Error> {
     let host = match server {
         Server::EU => "eu.version.battle.net",
@@ -35,17 +105,42 @@ pub fn execute_ribbit_command(server: Server, command: Command) -> Result<Vec<u8
         Command::Revocation { hash } => format!("v1/ocsp/{hash}"),
     };
 
-    let mut stream = TcpStream::connect((host, 1119))?;
+    let mut stream = connect_any((host, 1119).to_socket_addrs()?, TIMEOUT)
+        .with_context(|| format!("connecting to {}", host))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
     write!(stream, "{}\r\n", command)?;
 
+    let deadline = Instant::now() + TIMEOUT;
     let mut reply = vec![];
-    stream.read_to_end(&mut reply)?;
+    let mut buf = [0u8; 16 * 1024];
+    loop {
+        let remaining = deadline
+            .checked_duration_since(Instant::now())
+            .filter(|d| !d.is_zero())
+            .ok_or_else(|| anyhow::anyhow!("ribbit response from {} timed out", host))?;
+        stream.set_read_timeout(Some(remaining))?;
+
+        let n = match stream.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        if (reply.len() + n) as u64 > MAX_RESPONSE_SIZE {
+            anyhow::bail!(
+                "ribbit response from {} was larger than {} bytes",
+                host,
+                MAX_RESPONSE_SIZE
+            );
+        }
+        reply.extend_from_slice(&buf[..n]);
+    }
 
     Ok(reply)
 }
 
 fn get_body_with_content_disposition(res: &[u8], content_disposition: &str) -> Option<String> {
-    let parsed = Message::parse(res).unwrap();
+    let parsed = Message::parse(res)?;
     let summary = parsed.parts.iter().find(|part| {
         part.headers()
             .iter()
@@ -71,7 +166,7 @@ pub struct Endpoint {
 pub fn summary(server: Server) -> Result<Vec<Endpoint>, anyhow::Error> {
     let res = execute_ribbit_command(server, Command::Summary)?;
     let body = get_body_with_content_disposition(&res, "summary")
-        .expect("no mime section with content-disposition = summary");
+        .ok_or_else(|| anyhow::anyhow!("no mime section with content-disposition = summary"))?;
 
     let mut lines = body.lines();
     let _header = lines.next().expect("header not present");
@@ -105,10 +200,16 @@ pub struct Version {
 }
 
 pub fn versions(server: Server, product: &str) -> Result<Vec<Version>, anyhow::Error> {
+    parse_versions(&versions_body(server, product)?)
+}
+
+fn versions_body(server: Server, product: &str) -> Result<String, anyhow::Error> {
     let res = execute_ribbit_command(server, Command::ProductVersions { product })?;
-    let body = get_body_with_content_disposition(&res, "version")
-        .expect("no mime section with content-disposition = version");
+    get_body_with_content_disposition(&res, "version")
+        .ok_or_else(|| anyhow::anyhow!("no mime section with content-disposition = version"))
+}
 
+fn parse_versions(body: &str) -> Result<Vec<Version>, anyhow::Error> {
     let mut lines = body.lines();
     let _header = lines.next().expect("header not present");
 
@@ -143,10 +244,16 @@ pub struct CDNS {
 }
 
 pub fn cdns(server: Server, product: &str) -> Result<Vec<CDNS>, anyhow::Error> {
+    parse_cdns(&cdns_body(server, product)?)
+}
+
+fn cdns_body(server: Server, product: &str) -> Result<String, anyhow::Error> {
     let res = execute_ribbit_command(server, Command::ProductCDNs { product })?;
-    let body = get_body_with_content_disposition(&res, "cdn")
-        .expect("no mime section with content-disposition = cdn");
+    get_body_with_content_disposition(&res, "cdn")
+        .ok_or_else(|| anyhow::anyhow!("no mime section with content-disposition = cdn"))
+}
 
+fn parse_cdns(body: &str) -> Result<Vec<CDNS>, anyhow::Error> {
     let mut lines = body.lines();
     let _header = lines.next().expect("header not present");
 
@@ -179,31 +286,266 @@ pub fn cdns(server: Server, product: &str) -> Result<Vec<CDNS>, anyhow::Error> {
     Ok(res)
 }
 
+/// Region names from ribbit vary in case and sometimes have stray whitespace
+fn region_matches(name: &str, region: &str) -> bool {
+    name.trim().eq_ignore_ascii_case(region.trim())
+}
+
+fn no_region_error<'a>(
+    kind: &str,
+    region: &str,
+    available: impl Iterator<Item = &'a str>,
+) -> anyhow::Error {
+    let available: Vec<_> = available.map(str::trim).collect();
+    anyhow::anyhow!(
+        "couldn't find {} for region {:?}, available regions: {}",
+        kind,
+        region.trim(),
+        available.join(", ")
+    )
+}
+
+/// Finds the version for `region`, ignoring case and surrounding whitespace
+pub fn version_for_region<'a>(
+    versions: &'a [Version],
+    region: &str,
+) -> Result<&'a Version, anyhow::Error> {
+    versions
+        .iter()
+        .find(|v| region_matches(&v.region, region))
+        .ok_or_else(|| no_region_error("version", region, versions.iter().map(|v| &*v.region)))
+}
+
+/// Finds the cdns for `region`, ignoring case and surrounding whitespace
+pub fn cdns_for_region<'a>(cdns: &'a [CDNS], region: &str) -> Result<&'a CDNS, anyhow::Error> {
+    cdns.iter()
+        .find(|c| region_matches(&c.name, region))
+        .ok_or_else(|| no_region_error("cdns", region, cdns.iter().map(|c| &*c.name)))
+}
+
 pub fn bgdl(server: Server, product: &str) -> Result<Vec<Version>, anyhow::Error> {
     let res = execute_ribbit_command(server, Command::ProductBGDL { product })?;
     let body = get_body_with_content_disposition(&res, "version")
-        .expect("no mime section with content-disposition = version");
+        .ok_or_else(|| anyhow::anyhow!("no mime section with content-disposition = version"))?;
+    parse_versions(&body)
+}
 
-    let mut lines = body.lines();
-    let _header = lines.next().expect("header not present");
+/// Current seqn of a product's endpoint, per the summary. `flags` is `""` for versions, `"cdn"` for cdns.
+pub fn product_seqn(
+    server: Server,
+    product: &str,
+    flags: &str,
+) -> Result<Option<u32>, anyhow::Error> {
+    Ok(summary(server)?
+        .into_iter()
+        .find(|e| e.product == product && e.flags == flags)
+        .map(|e| e.seqn))
+}
 
-    let mut res = vec![];
-    for line in lines {
-        if line.starts_with('#') || line.is_empty() {
-            continue;
+/// Fetches versions, unless the summary says they haven't changed since `last_seqn`
+pub fn versions_if_changed(
+    server: Server,
+    product: &str,
+    last_seqn: u32,
+) -> Result<Option<Vec<Version>>, anyhow::Error> {
+    if product_seqn(server, product, "")? == Some(last_seqn) {
+        return Ok(None);
+    }
+    versions(server, product).map(Some)
+}
+
+/// Reads the seqn from the `## seqn = N` line of a response body
+pub fn body_seqn(body: &str) -> Option<u32> {
+    body.lines()
+        .find_map(|line| line.strip_prefix("## seqn = "))
+        .and_then(|seqn| seqn.trim().parse().ok())
+}
+
+/// Caches versions and cdns responses on disk, only refetching them when the summary seqn changes
+pub struct RibbitCache {
+    dir: PathBuf,
+}
+
+impl RibbitCache {
+    pub fn new(dir: impl Into<PathBuf>) -> RibbitCache {
+        RibbitCache { dir: dir.into() }
+    }
+
+    pub fn versions(&self, server: Server, product: &str) -> Result<Vec<Version>, anyhow::Error> {
+        let body = self.cached_body(server, product, "", "versions", versions_body)?;
+        parse_versions(&body)
+    }
+
+    pub fn cdns(&self, server: Server, product: &str) -> Result<Vec<CDNS>, anyhow::Error> {
+        let body = self.cached_body(server, product, "cdn", "cdns", cdns_body)?;
+        parse_cdns(&body)
+    }
+
+    fn cached_body(
+        &self,
+        server: Server,
+        product: &str,
+        flags: &str,
+        name: &str,
+        fetch: impl FnOnce(Server, &str) -> Result<String, anyhow::Error>,
+    ) -> Result<String, anyhow::Error> {
+        self.cached_file(
+            &format!("{:?}-{}-{}.psv", server, product, name),
+            || product_seqn(server, product, flags),
+            || fetch(server, product),
+        )
+    }
+
+    /// Returns the cached `file_name` if its seqn matches `current_seqn`, refetching it otherwise
+    fn cached_file(
+        &self,
+        file_name: &str,
+        current_seqn: impl FnOnce() -> Result<Option<u32>, anyhow::Error>,
+        fetch: impl FnOnce() -> Result<String, anyhow::Error>,
+    ) -> Result<String, anyhow::Error> {
+        let path = self.dir.join(file_name);
+
+        if let Ok(cached) = std::fs::read_to_string(&path) {
+            let seqn = current_seqn()?;
+            if seqn.is_some() && seqn == body_seqn(&cached) {
+                return Ok(cached);
+            }
         }
 
-        let mut parts = line.split('|');
-        res.push(Version {
-            region: parts.next().expect("no region present").to_string(),
-            build_config: parts.next().expect("no build_config present").to_string(),
-            cdn_config: parts.next().expect("no cdn_config present").to_string(),
-            key_ring: parts.next().expect("no key_ring present").to_string(),
-            build_id: parts.next().expect("no build_id present").parse()?,
-            versions_name: parts.next().expect("no versions_name present").to_string(),
-            product_config: parts.next().expect("no product_config present").to_string(),
-        });
+        let body = fetch()?;
+        std::fs::create_dir_all(&self.dir)?;
+        std::fs::write(&path, &body)?;
+        Ok(body)
     }
+}
 
-    Ok(res)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connect_any() {
+        // A port nothing listens on, found by binding and dropping a listener
+        let refused = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let listening = listener.local_addr().unwrap();
+
+        let timeout = Duration::from_secs(5);
+        let stream = connect_any([refused, listening].into_iter(), timeout).unwrap();
+        assert_eq!(listening, stream.peer_addr().unwrap());
+
+        assert!(connect_any([refused].into_iter(), timeout).is_err());
+        assert!(connect_any(std::iter::empty(), timeout).is_err());
+    }
+
+    #[test]
+    fn test_body_seqn() {
+        assert_eq!(
+            Some(1234),
+            body_seqn("Region!STRING:0|BuildId!DEC:4\n## seqn = 1234\nus|1\n")
+        );
+        assert_eq!(Some(7), body_seqn("## seqn = 7 \r\n"));
+        assert_eq!(None, body_seqn("Region!STRING:0\nus\n"));
+        assert_eq!(None, body_seqn("## seqn = soon\n"));
+        assert_eq!(None, body_seqn("## seqn 12\n"));
+    }
+
+    #[test]
+    fn test_ribbit_cache() {
+        let dir = std::env::temp_dir().join(format!("steed-ribbit-cache-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let cache = RibbitCache::new(&dir);
+
+        let body = |seqn: u32| format!("Region!STRING:0\n## seqn = {}\nus\n", seqn);
+        let no_seqn = || -> Result<Option<u32>, anyhow::Error> { panic!("nothing cached yet") };
+        let no_fetch = || -> Result<String, anyhow::Error> { panic!("cached copy is current") };
+
+        // Miss: nothing cached, so the summary isn't consulted
+        let fetched = cache.cached_file("versions.psv", no_seqn, || Ok(body(1)));
+        assert_eq!(body(1), fetched.unwrap());
+
+        // Hit: the cached seqn matches the summary
+        let cached = cache.cached_file("versions.psv", || Ok(Some(1)), no_fetch);
+        assert_eq!(body(1), cached.unwrap());
+
+        // Stale: the summary moved on, the new body replaces the cached one
+        let fetched = cache.cached_file("versions.psv", || Ok(Some(2)), || Ok(body(2)));
+        assert_eq!(body(2), fetched.unwrap());
+        assert_eq!(
+            body(2),
+            std::fs::read_to_string(dir.join("versions.psv")).unwrap()
+        );
+
+        // A product missing from the summary can't be checked, so it's always refetched
+        let fetched = cache.cached_file("versions.psv", || Ok(None), || Ok(body(3)));
+        assert_eq!(body(3), fetched.unwrap());
+
+        // Summary errors are returned rather than silently serving the cached copy
+        let err = cache
+            .cached_file("versions.psv", || anyhow::bail!("offline"), no_fetch)
+            .unwrap_err();
+        assert_eq!("offline", err.to_string());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn version(region: &str) -> Version {
+        Version {
+            region: region.to_string(),
+            build_config: String::new(),
+            cdn_config: String::new(),
+            key_ring: String::new(),
+            build_id: 1,
+            versions_name: String::new(),
+            product_config: String::new(),
+        }
+    }
+
+    fn cdns(name: &str) -> CDNS {
+        CDNS {
+            name: name.to_string(),
+            path: String::new(),
+            hosts: vec![],
+            servers: vec![],
+            config_path: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_region_matches() {
+        assert!(region_matches("eu", "eu"));
+        assert!(region_matches("EU", "eu"));
+        assert!(region_matches(" eu\t", "Eu "));
+        assert!(!region_matches("eu", "us"));
+        assert!(!region_matches("eu", "e"));
+    }
+
+    #[test]
+    fn test_version_for_region() {
+        let versions = vec![version("us"), version(" EU ")];
+        assert_eq!(" EU ", version_for_region(&versions, "eu").unwrap().region);
+        assert_eq!("us", version_for_region(&versions, "US").unwrap().region);
+
+        let err = version_for_region(&versions, "kr").unwrap_err();
+        assert_eq!(
+            "couldn't find version for region \"kr\", available regions: us, EU",
+            err.to_string()
+        );
+        assert!(version_for_region(&[], "eu").is_err());
+    }
+
+    #[test]
+    fn test_cdns_for_region() {
+        let cdns_list = vec![cdns("eu"), cdns("us")];
+        assert_eq!("us", cdns_for_region(&cdns_list, " us").unwrap().name);
+
+        let err = cdns_for_region(&cdns_list, "cn").unwrap_err();
+        assert_eq!(
+            "couldn't find cdns for region \"cn\", available regions: eu, us",
+            err.to_string()
+        );
+    }
 }
@@ -47,6 +47,23 @@ pub struct CatalogFragment {
     pub categories: Option<Categories>,
 }
 
+/// A catalog along with all of its fragments, as output by `catalog --json`
+#[derive(Debug, Clone, Serialize)]
+pub struct CatalogDump {
+    pub catalog: Catalog,
+    pub fragments: Vec<FragmentDump>,
+}
+
+/// A catalog fragment, with its contents if it could be fetched and wasn't encrypted
+#[derive(Debug, Clone, Serialize)]
+pub struct FragmentDump {
+    pub name: String,
+    pub hash: String,
+    pub encrypted: bool,
+    pub decryption_key_id: Option<String>,
+    pub fragment: Option<CatalogFragment>,
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Categories {
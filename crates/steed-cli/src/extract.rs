@@ -0,0 +1,239 @@
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use ngdp::{
+    progress::ProgressSink,
+    tact::ContentKey,
+    util::{format_hex_bytes_le, normalize_path, PathStyle},
+};
+
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{load_state, progress::IndicatifProgress, Config};
+
+/// How extracted files are named within the output directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::enum_variant_names)]
+pub enum OutputLayout {
+    /// By the file's path, e.g. `interface/icons/foo.blp`
+    ByPath,
+    /// By file data id, keeping the extension if the path is known, e.g. `12345.blp`
+    ById,
+    /// Like the CDN and CASC config layout, e.g. `ab/cd/abcd...`
+    ByContentKey,
+}
+
+impl FromStr for OutputLayout {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "path" => Ok(OutputLayout::ByPath),
+            "id" => Ok(OutputLayout::ById),
+            "ckey" => Ok(OutputLayout::ByContentKey),
+            _ => Err(anyhow!(
+                "unknown output layout: {} (expected path, id or ckey)",
+                s
+            )),
+        }
+    }
+}
+
+impl OutputLayout {
+    /// Path of a file relative to the output directory
+    pub fn output_path(
+        &self,
+        file_id: Option<i32>,
+        path: Option<&str>,
+        ckey: &ContentKey,
+    ) -> Result<PathBuf, anyhow::Error> {
+        match self {
+            OutputLayout::ByPath => {
+                let path = path.ok_or_else(|| {
+                    anyhow!(
+                        "path of file id {:?} is unknown, try the id layout",
+                        file_id
+                    )
+                })?;
+                Ok(PathBuf::from(normalize_path(path, PathStyle::ForFs)))
+            }
+            OutputLayout::ById => {
+                let file_id = file_id.ok_or_else(|| anyhow!("file id of {:?} is unknown", path))?;
+                let extension = path
+                    .and_then(|path| path.rsplit_once('.'))
+                    .map(|(_, ext)| ext.to_lowercase());
+                Ok(match extension {
+                    Some(ext) => PathBuf::from(format!("{}.{}", file_id, ext)),
+                    None => PathBuf::from(file_id.to_string()),
+                })
+            }
+            OutputLayout::ByContentKey => {
+                let key = format_hex_bytes_le(&ckey.to_inner());
+                Ok(PathBuf::from(&key[0..2]).join(&key[2..4]).join(key))
+            }
+        }
+    }
+}
+
+/// Where extracted files are written to
+trait ExtractSink {
+    fn write_file(&mut self, path: &Path, data: &[u8]) -> Result<(), anyhow::Error>;
+    fn finish(&mut self) -> Result<(), anyhow::Error>;
+}
+
+/// Writes loose files into a directory
+struct DirSink {
+    out_dir: PathBuf,
+}
+
+impl ExtractSink for DirSink {
+    fn write_file(&mut self, path: &Path, data: &[u8]) -> Result<(), anyhow::Error> {
+        let out_path = self.out_dir.join(path);
+        if let Some(parent) = out_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(out_path, data)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// Writes every file into a single zip archive as it's extracted
+struct ZipSink {
+    zip: ZipWriter<File>,
+}
+
+impl ZipSink {
+    fn create(path: &Path) -> Result<ZipSink, anyhow::Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(ZipSink {
+            zip: ZipWriter::new(File::create(path)?),
+        })
+    }
+}
+
+impl ExtractSink for ZipSink {
+    fn write_file(&mut self, path: &Path, data: &[u8]) -> Result<(), anyhow::Error> {
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Deflated)
+            .large_file(data.len() as u64 >= u32::MAX as u64);
+        self.zip.start_file(zip_entry_name(path)?, options)?;
+        self.zip.write_all(data)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), anyhow::Error> {
+        self.zip.finish()?;
+        Ok(())
+    }
+}
+
+/// Zip entries always use forward slashes, whatever the platform
+fn zip_entry_name(path: &Path) -> Result<String, anyhow::Error> {
+    let parts = path
+        .components()
+        .map(|c| {
+            c.as_os_str()
+                .to_str()
+                .ok_or_else(|| anyhow!("non utf-8 path: {}", path.display()))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(parts.join("/"))
+}
+
+/// Extracts files, given by path or file data id, into an output directory, or a zip archive if
+/// the output ends in `.zip`. `@file` extracts every path or id listed in `file`, one per line.
+pub fn extract(config: &Config) -> Result<(), anyhow::Error> {
+    let usage =
+        "usage: steed-cli extract <path, file id or @list> <out dir or .zip> [path|id|ckey]";
+    let target = std::env::args().nth(2).ok_or_else(|| anyhow!(usage))?;
+    let out = PathBuf::from(std::env::args().nth(3).ok_or_else(|| anyhow!(usage))?);
+    let layout = match std::env::args().nth(4) {
+        Some(layout) => layout.parse()?,
+        None => OutputLayout::ByPath,
+    };
+
+    let (targets, from_list) = match target.strip_prefix('@') {
+        Some(list) => (
+            std::fs::read_to_string(list)?
+                .lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty())
+                .map(str::to_string)
+                .collect(),
+            true,
+        ),
+        None => (vec![target], false),
+    };
+
+    let state = load_state(config)?;
+
+    let mut files = vec![];
+    for target in &targets {
+        let res = match target.parse::<i32>() {
+            Ok(file_id) => state.lookup_id(file_id).map(|record| {
+                (
+                    Some(file_id),
+                    state.listfile.get_name(file_id).map(str::to_string),
+                    record,
+                )
+            }),
+            Err(_) => state
+                .lookup_path(target)
+                .map(|record| (state.listfile.get_id(target), Some(target.clone()), record)),
+        };
+        match res {
+            Ok(file) => files.push(file),
+            Err(e) if from_list => eprintln!("Skipping {}: {}", target, e),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut sink: Box<dyn ExtractSink> = match out.extension() {
+        Some(ext) if ext.eq_ignore_ascii_case("zip") => Box::new(ZipSink::create(&out)?),
+        _ => Box::new(DirSink {
+            out_dir: out.clone(),
+        }),
+    };
+
+    let total_bytes = files
+        .iter()
+        .filter_map(|(_, _, record)| state.casc.encoding.lookup_by_ckey(&record.content_key))
+        .map(|e| u64::from(e.file_size))
+        .sum();
+    let progress = IndicatifProgress::new();
+    progress.start_task(total_bytes);
+
+    let mut written_bytes = 0;
+    for (file_id, path, record) in &files {
+        let out_path = layout.output_path(*file_id, path.as_deref(), &record.content_key)?;
+        progress.message(&out_path.display().to_string());
+
+        // Only one decoded file is held in memory at a time
+        let data = state.read_by_ckey(&record.content_key)?;
+        sink.write_file(&out_path, &data)?;
+
+        written_bytes += data.len();
+        progress.inc(data.len() as u64);
+    }
+    sink.finish()?;
+    progress.finish();
+
+    println!(
+        "Wrote {} files, {} bytes to {}",
+        files.len(),
+        written_bytes,
+        out.display()
+    );
+    Ok(())
+}
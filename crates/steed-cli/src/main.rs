@@ -1,30 +1,55 @@
-use anyhow::anyhow;
-use catalog::{Catalog, CatalogFragment};
+use anyhow::{anyhow, Context};
+use catalog::{Catalog, CatalogDump, CatalogFragment, FragmentDump};
+use indicatif::HumanBytes;
 use ngdp::{
-    casc::CASC,
-    listfile::{parse_listfile, ListFile},
+    blte::{decode_blte, describe},
+    casc::{
+        build_info::BuildInfo,
+        idx::{IndexLayout, Indexes},
+        shmem::Shmem,
+        CASC,
+    },
+    error::NgdpError,
+    listfile::{fetch_listfile_cached, parse_listfile, ListFile},
+    source::{ArchivedFiles, CDNSource, ContentSource, Layered},
     tact::{
         cdn::CDNClient,
-        config::{parse_build_config, parse_cdn_config},
+        config::{parse_build_config, parse_cdn_config, BuildConfig},
+        index::parse_index,
         keys::TactKeys,
-        root::{parse_root, ContentFlags, LocaleFlags, Root},
+        resolve::resolve_build,
+        root::{parse_root, ContentFlags, LocaleFlags, Record, Root},
         ContentKey, EncodingKey,
     },
-    util::parse_hex_bytes,
+    util::{format_hex_bytes_le, parse_hex_bytes},
 };
-use ribbit::{cdns, versions, Server};
+use ribbit::Server;
 use serde::Deserialize;
-use std::{fs::read_to_string, path::PathBuf, str::FromStr};
+use std::{
+    cell::OnceCell,
+    fs::read_to_string,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 mod catalog;
+mod extract;
 mod install;
+mod progress;
+mod update;
 
 #[derive(Deserialize, Debug, Clone)]
 pub struct Config {
     wow_path: String,
     tactkeys_path: Option<String>,
-    listfile_path: String,
+    listfile_path: Option<String>,
+    listfile_url: Option<String>,
+    /// Where the listfile downloaded from `listfile_url` is cached, defaults to `.steed-listfile.csv` in `wow_path`
+    listfile_cache_path: Option<String>,
     cdn_override: Option<String>,
+    /// Defaults to `CDNClient::DEFAULT_MAX_CONNECTIONS_PER_HOST`
+    max_connections_per_host: Option<usize>,
 }
 
 fn main() -> Result<(), anyhow::Error> {
@@ -35,6 +60,12 @@ fn main() -> Result<(), anyhow::Error> {
     match std::env::args().nth(1).as_deref() {
         Some("install") => install::install(&config),
         Some("catalog") => catalog(&config),
+        Some("listfile-subset") => listfile_subset(&config),
+        Some("info") => info(),
+        Some("blte") => blte(),
+        Some("keys") => keys(&config),
+        Some("update") => update::update(&config),
+        Some("extract") => extract::extract(&config),
         _ => do_stuff(&config),
     }
 }
@@ -43,66 +74,164 @@ struct State {
     casc: CASC,
     root: Root,
     listfile: ListFile,
+    cdn: CDNClient,
+    cdn_config_text: String,
+    archived_files: OnceCell<ArchivedFiles>,
+    wow_path: PathBuf,
 }
 
 impl State {
+    const FLAGS: (ContentFlags, LocaleFlags) = (ContentFlags::empty(), LocaleFlags::EN_GB);
+
     fn read_file(&self, path: &str) -> Result<Vec<u8>, anyhow::Error> {
-        let file_id = self
-            .listfile
-            .get_id(path)
-            .ok_or_else(|| anyhow!("couldn't find file_id for {}", path))?;
-        let record = self
-            .root
-            .lookup_by_fileid_and_flags(file_id, ContentFlags::empty(), LocaleFlags::EN_GB)
-            .ok_or_else(|| anyhow!("couldn't find record for file_id: {}", file_id))?;
-        self.casc.read_by_ckey(&record.content_key)
+        let record = self.lookup_path(path)?;
+        self.read_by_ckey(&record.content_key)
+    }
+
+    fn lookup_path(&self, path: &str) -> Result<&Record, anyhow::Error> {
+        let (content_flags, locale_flags) = Self::FLAGS;
+        match self.listfile.get_id(path) {
+            Some(file_id) => self.lookup_id(file_id),
+            // Not in the listfile, but root might still know it by name
+            None => self
+                .root
+                .lookup_by_name_and_flags(path, content_flags, locale_flags)
+                .ok_or_else(|| anyhow!("couldn't find {} in listfile or root", path)),
+        }
+    }
+
+    fn lookup_id(&self, file_id: i32) -> Result<&Record, anyhow::Error> {
+        let (_, locale_flags) = Self::FLAGS;
+        self.root
+            .record_for_id(file_id, Some(locale_flags))
+            .ok_or_else(|| anyhow!("couldn't find record for file_id: {}", file_id))
+    }
+
+    fn read_by_ckey(&self, ckey: &ContentKey) -> Result<Vec<u8>, anyhow::Error> {
+        Ok(Layered(&self.casc, RemoteSource(self)).read_by_ckey(ckey)?)
+    }
+
+    /// CDN source for fallback reads, loading the archive map on first use
+    fn remote(&self) -> Result<CDNSource<'_>, anyhow::Error> {
+        let archived_files = match self.archived_files.get() {
+            Some(archived_files) => archived_files,
+            None => {
+                let archived_files = self.load_archived_files()?;
+                self.archived_files.get_or_init(|| archived_files)
+            }
+        };
+        Ok(
+            CDNSource::new(&self.cdn, &self.casc.encoding, &self.casc.tact_keys)
+                .with_archived_files(archived_files),
+        )
+    }
+
+    /// Builds the archive map used for CDN fallback, preferring archive indexes cached in the install
+    fn load_archived_files(&self) -> Result<ArchivedFiles, anyhow::Error> {
+        let cdn_config = parse_cdn_config(&self.cdn_config_text)?;
+        let indices_dir = self.wow_path.join("Data").join("indices");
+
+        let mut archived_files = ArchivedFiles::new();
+        for (archive, &size) in cdn_config
+            .archives
+            .iter()
+            .zip(&cdn_config.archives_index_size)
+        {
+            let index_data = match std::fs::read(indices_dir.join(format!("{:?}.index", archive))) {
+                Ok(data) => data,
+                Err(_) => self.cdn.read_index(archive)?.read_vec_limited(size, size)?,
+            };
+            let index = parse_index(&index_data)?;
+            for (key, entry) in index.entries {
+                archived_files.insert(key, (archive.clone(), entry));
+            }
+        }
+        Ok(archived_files)
     }
 }
 
-fn do_stuff(config: &Config) -> Result<(), anyhow::Error> {
-    let res = versions(Server::EU, "wow")?;
-    let version = res
-        .iter()
-        .find(|v| v.region == "eu")
-        .ok_or_else(|| anyhow!("couldn't find eu version"))?;
-    dbg!(&version);
+/// Reads through `State::remote`, so local reads never touch the CDN or archive indexes
+struct RemoteSource<'a>(&'a State);
 
-    let res = cdns(Server::EU, "wow")?;
-    let cdns = res
-        .iter()
-        .find(|v| v.name == "eu")
-        .ok_or_else(|| anyhow!("couldn't find eu cdns"))?;
-    dbg!(&cdns);
+impl ContentSource for RemoteSource<'_> {
+    fn read_by_ckey(&self, ckey: &ContentKey) -> Result<Vec<u8>, NgdpError> {
+        self.0.remote()?.read_by_ckey(ckey)
+    }
 
-    let cdncache = CDNClient::new(cdns.clone(), config.cdn_override.clone());
+    fn read_by_ekey(&self, ekey: &EncodingKey) -> Result<Vec<u8>, NgdpError> {
+        self.0.remote()?.read_by_ekey(ekey)
+    }
+}
 
-    let build_config_text = cdncache
-        .read_config(&ContentKey::parse(&version.build_config)?)?
-        .read_string()?;
-    let build_config = parse_build_config(&build_config_text)?;
+/// Opens a CASC, printing any warnings from opening it
+fn open_casc(path: &str, build_config: &BuildConfig) -> Result<CASC, anyhow::Error> {
+    let casc = CASC::new(path, build_config)?;
+    for warning in &casc.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    Ok(casc)
+}
+
+fn load_state(config: &Config) -> Result<State, anyhow::Error> {
+    let resolved = resolve_build(Server::EU, "eu", "wow", config.cdn_override.clone())?;
+    dbg!(&resolved.version);
+
+    let build_config = resolved.build_config()?;
     dbg!(&build_config);
 
-    let state = {
-        let mut casc = CASC::new(&config.wow_path, &build_config)?;
+    let mut casc = open_casc(&config.wow_path, &build_config)?;
 
-        let root = {
-            let file = casc.read_by_ckey(&build_config.root)?;
-            parse_root(&file)?
-        };
+    let root = {
+        let file = casc.read_by_ckey(&build_config.root)?;
+        parse_root(&file)?
+    };
+    for rec_type in &root.record_types {
+        if rec_type.unknown_content_bits != 0 || rec_type.unknown_locale_bits != 0 {
+            eprintln!(
+                "warning: root block has unknown content bits {:#x}, locale bits {:#x}",
+                rec_type.unknown_content_bits, rec_type.unknown_locale_bits
+            );
+        }
+    }
 
-        let listfile = {
-            let content = std::fs::read_to_string(&config.listfile_path)?;
-            parse_listfile(&content)?
-        };
+    let listfile = load_listfile(config)?;
+
+    populate_tact_keys_file(config, &mut casc.tact_keys)?;
 
-        populate_tact_keys_file(config, &mut casc.tact_keys)?;
+    Ok(State {
+        casc,
+        root,
+        listfile,
+        cdn: resolved.cdn,
+        cdn_config_text: resolved.cdn_config_text,
+        archived_files: OnceCell::new(),
+        wow_path: PathBuf::from(&config.wow_path),
+    })
+}
 
-        State {
-            casc,
-            root,
-            listfile,
+/// Loads the listfile from `listfile_path` if set, otherwise from the cached download of `listfile_url`
+fn load_listfile(config: &Config) -> Result<ListFile, anyhow::Error> {
+    match (&config.listfile_path, &config.listfile_url) {
+        (Some(path), _) => parse_listfile(&std::fs::read_to_string(path)?),
+        (None, Some(url)) => {
+            let cache_path = match &config.listfile_cache_path {
+                Some(path) => PathBuf::from(path),
+                None => Path::new(&config.wow_path).join(".steed-listfile.csv"),
+            };
+            let fetched = fetch_listfile_cached(url, &cache_path)?;
+            if let Some(e) = fetched.fetch_error {
+                eprintln!("Error fetching listfile, using cached copy: {:#}", e);
+            }
+            Ok(fetched.listfile)
         }
-    };
+        (None, None) => Err(anyhow!(
+            "config has neither listfile_path nor listfile_url set"
+        )),
+    }
+}
+
+fn do_stuff(config: &Config) -> Result<(), anyhow::Error> {
+    let state = load_state(config)?;
 
     // Quick test case for decryption
     // let blp = state.read_file("interface/icons/inv_tigermount.blp")?;
@@ -117,8 +246,8 @@ fn do_stuff(config: &Config) -> Result<(), anyhow::Error> {
     let mut files: Vec<&str> = state
         .listfile
         .iter()
-        .map(|(name, _id)| name.as_str())
-        .filter(|name| name.ends_with(".blp"))
+        .map(|(name, _id)| name)
+        .filter(|name| name.to_lowercase().ends_with(".blp"))
         .collect();
     files.sort();
 
@@ -161,6 +290,209 @@ fn do_stuff(config: &Config) -> Result<(), anyhow::Error> {
     Ok(())
 }
 
+/// Writes the subset of the listfile present in the current build to stdout.
+/// Takes an optional locale name (e.g. `enUS`) to only include files for that locale.
+fn listfile_subset(config: &Config) -> Result<(), anyhow::Error> {
+    let locale_flags = match std::env::args().nth(2) {
+        Some(locale) => {
+            LocaleFlags::from_name(&locale).ok_or_else(|| anyhow!("unknown locale: {}", locale))?
+        }
+        None => LocaleFlags::empty(),
+    };
+
+    let state = load_state(config)?;
+
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    for (id, path) in
+        state
+            .listfile
+            .subset_for_root(&state.root, ContentFlags::empty(), locale_flags)
+    {
+        writeln!(out, "{};{}", id, path)?;
+    }
+    out.flush()?;
+
+    Ok(())
+}
+
+/// Prints a summary of the local CASC in the given install directory
+fn info() -> Result<(), anyhow::Error> {
+    let dir = std::env::args()
+        .nth(2)
+        .ok_or_else(|| anyhow!("usage: steed-cli info <dir>"))?;
+    let dir = PathBuf::from(dir);
+    let data_path = dir.join("Data").join("data");
+
+    let shmem_data = std::fs::read(data_path.join("shmem"))?;
+    let shmem = Shmem::parse(&shmem_data)?;
+    let indexes = Indexes::read(&data_path, &shmem)?;
+
+    println!("Data path: {}", data_path.display());
+    println!("  shmem data path: {}", shmem.data_path);
+
+    println!("Indexes:");
+    let stats = indexes.stats();
+    for (bucket, stat) in stats.iter().enumerate() {
+        println!(
+            "  {:02x}: {:>8} entries, {:>10}",
+            bucket,
+            stat.entries,
+            HumanBytes(stat.total_size)
+        );
+        if stat.layout != IndexLayout::default() {
+            println!("      non-standard layout: {:?}", stat.layout);
+        }
+    }
+    let total_size: u64 = stats.iter().map(|s| s.total_size).sum();
+    println!(
+        "  total: {} entries, {}",
+        indexes.total_entries(),
+        HumanBytes(total_size)
+    );
+
+    println!("Shmem:");
+    println!(
+        "  free space in existing data files: {}",
+        HumanBytes(shmem.total_unused_bytes())
+    );
+
+    let build_info = match BuildInfo::read(&dir) {
+        Ok(build_info) => build_info,
+        Err(e) => {
+            println!("No .build.info: {}", e);
+            return Ok(());
+        }
+    };
+    let installed = match build_info.active_entry() {
+        Ok(installed) => installed,
+        Err(e) => {
+            println!("{}", e);
+            return Ok(());
+        }
+    };
+
+    println!("Build:");
+    println!("  product: {}", installed.product().unwrap_or("<unknown>"));
+    println!("  version: {}", installed.version().unwrap_or("<unknown>"));
+    println!("  branch: {}", installed.branch().unwrap_or("<unknown>"));
+
+    let Some(build_key) = installed.build_key() else {
+        println!("  no build key");
+        return Ok(());
+    };
+    println!("  build key: {:?}", build_key);
+
+    let build_config_text =
+        read_to_string(local_config_path(&dir, &build_key)).context("reading build config")?;
+    let build_config = parse_build_config(&build_config_text)?;
+    let casc = open_casc(&dir.to_string_lossy(), &build_config)?;
+    let encoding = &casc.encoding;
+
+    println!("Encoding:");
+    println!("  especs: {}", encoding.especs.len());
+    println!(
+        "  content key pages: {}, entries: {}",
+        encoding.cekey_pages.len(),
+        encoding
+            .cekey_pages
+            .iter()
+            .flat_map(|p| &p.entries.0)
+            .filter(|e| e.key_count > 0)
+            .count()
+    );
+    println!("  encoding key pages: {}", encoding.ekey_spec_pages.len());
+
+    let required_keys = encoding.required_keys(&casc.tact_keys);
+    println!(
+        "  encryption keys: {} present, {} missing",
+        required_keys.present.len(),
+        required_keys.missing.len()
+    );
+    for key_name in &required_keys.missing {
+        println!("    missing: {}", format_hex_bytes_le(key_name));
+    }
+
+    Ok(())
+}
+
+fn local_config_path(dir: &Path, key: &ContentKey) -> PathBuf {
+    let key = format_hex_bytes_le(&key.to_inner());
+    dir.join("Data")
+        .join("config")
+        .join(&key[0..2])
+        .join(&key[2..4])
+        .join(key)
+}
+
+fn blte() -> Result<(), anyhow::Error> {
+    let path = match std::env::args().nth(2).as_deref() {
+        Some("info") => std::env::args().nth(3),
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("usage: steed-cli blte info <file>"))?;
+
+    let content = std::fs::read(path)?;
+    let info = describe(&content)?;
+
+    println!("Header size: {}", info.header_size);
+    if let Some(flags) = info.flags {
+        println!("Flags: {:#04x}", flags);
+    }
+    println!("Chunks: {}", info.chunks.len());
+    for (index, chunk) in info.chunks.iter().enumerate() {
+        println!(
+            "  {:>4}: mode {} compressed {:>10} decompressed {:>10} checksum {}",
+            index,
+            chunk.mode as char,
+            chunk.compressed_size,
+            chunk.decompressed_size,
+            format_hex_bytes_le(&chunk.checksum)
+        );
+        if let Some(key_name) = chunk.key_name {
+            println!(
+                "        encrypted with key {}",
+                format_hex_bytes_le(&key_name)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the keys a local file would be known by, and for BLTE files also those of its decoded content
+fn keys(config: &Config) -> Result<(), anyhow::Error> {
+    let path = std::env::args()
+        .nth(2)
+        .ok_or_else(|| anyhow!("usage: steed-cli keys <file>"))?;
+    let content = std::fs::read(path)?;
+
+    println!("Content key: {:?}", ContentKey::from_data(&content));
+
+    if content.starts_with(b"BLTE") {
+        println!("Encoding key: {:?}", EncodingKey::from_blte(&content)?);
+
+        let mut tact_keys = TactKeys::default();
+        populate_tact_keys_file(config, &mut tact_keys)?;
+        let missing_key = describe(&content)?
+            .chunks
+            .into_iter()
+            .filter_map(|chunk| chunk.key_name)
+            .find(|key_name| tact_keys.get_key(key_name).is_none());
+        match missing_key {
+            Some(key_name) => println!(
+                "Decoded content key: unknown, needs encryption key {}",
+                format_hex_bytes_le(&key_name)
+            ),
+            None => println!(
+                "Decoded content key: {:?}",
+                ContentKey::from_data(&decode_blte(&tact_keys, &content)?)
+            ),
+        }
+    }
+
+    Ok(())
+}
+
 pub fn populate_tact_keys_file(
     config: &Config,
     tact_keys: &mut TactKeys,
@@ -168,59 +500,35 @@ pub fn populate_tact_keys_file(
     if let Some(tactkeys_path) = &config.tactkeys_path {
         let path = PathBuf::from_str(tactkeys_path).unwrap();
         let keys = read_to_string(path.join("WoW.txt"))?;
-        for line in keys.lines() {
-            let (name, key) = match line.split_once(' ') {
-                Some(v) => v,
-                None => continue,
-            };
-
-            let name = parse_hex_bytes::<8>(name);
-            let key = parse_hex_bytes::<16>(key);
-
-            match (name, key) {
-                (Some(mut name), Some(key)) => {
-                    name.reverse();
-                    tact_keys.add_key(name, key)
-                }
-                (_, _) => continue,
-            }
-        }
+        let keys = keys.lines().filter_map(|line| {
+            let (name, key) = line.split_once(' ')?;
+            let mut name = parse_hex_bytes::<8>(name)?;
+            let key = parse_hex_bytes::<16>(key)?;
+            name.reverse();
+            Some((name, key))
+        });
+        tact_keys
+            .add_keys_checked(keys)
+            .context("loading WoW.txt tact keys")?;
     }
 
     Ok(())
 }
 
 fn catalog(config: &Config) -> Result<(), anyhow::Error> {
-    let res = versions(Server::EU, "catalogs")?;
-    dbg!(&res);
-
-    let version = res
-        .iter()
-        .max_by_key(|v| v.versions_name.parse().unwrap_or(0))
-        .ok_or_else(|| anyhow!("couldn't find a single version"))?;
-    dbg!(&version);
-
-    let res = cdns(Server::EU, "catalogs")?;
-    let cdns = res
-        .iter()
-        .find(|v| v.name == "eu")
-        .ok_or_else(|| anyhow!("couldn't find eu cdns"))?;
-    dbg!(&cdns);
+    let json = std::env::args().nth(2).as_deref() == Some("--json");
 
-    let cdncache = CDNClient::new(cdns.clone(), config.cdn_override.clone());
+    let resolved = resolve_build(Server::EU, "eu", "catalogs", config.cdn_override.clone())?;
+    dbg!(&resolved.version);
 
-    let build_config_text = cdncache
-        .read_config(&ContentKey::parse(&version.build_config)?)?
-        .read_string()?;
-    let build_config = parse_build_config(&build_config_text)?;
+    let build_config = resolved.build_config()?;
     dbg!(&build_config);
 
-    let cdn_config_text = cdncache
-        .read_config(&ContentKey::parse(&version.cdn_config)?)?
-        .read_string()?;
-    let cdn_config = parse_cdn_config(&cdn_config_text);
+    let cdn_config = resolved.cdn_config()?;
     dbg!(&cdn_config);
 
+    let cdncache = &resolved.cdn;
+
     let mut tact_keys = TactKeys::default();
     populate_tact_keys_file(config, &mut tact_keys)?;
 
@@ -234,23 +542,43 @@ fn catalog(config: &Config) -> Result<(), anyhow::Error> {
     let catalog: Catalog = serde_json::from_str(&catalog_text)?;
     // dbg!(&catalog);
 
+    let mut fragments = vec![];
     for fragment in &catalog.fragments {
         dbg!(fragment);
-        if fragment.encrypted_hash.is_some() {
-            println!(
+        let mut dump = FragmentDump {
+            name: fragment.name.clone(),
+            hash: fragment.hash.clone(),
+            encrypted: fragment.encrypted_hash.is_some(),
+            decryption_key_id: fragment.decryption_key_id.clone(),
+            fragment: None,
+        };
+        if dump.encrypted {
+            eprintln!(
                 "Catalog fragment '{}' is encrypted, skipping...",
                 fragment.name
             );
+            fragments.push(dump);
             continue;
         }
 
         let fragment_text = cdncache
             .read_data(&EncodingKey::parse(&fragment.hash)?)?
             .read_string()?;
-        println!("{}", fragment_text);
-        println!();
+        if !json {
+            println!("{}", fragment_text);
+            println!();
+        }
         let fragment: CatalogFragment = serde_json::from_str(&fragment_text)?;
         dbg!(&fragment);
+        dump.fragment = Some(fragment);
+        fragments.push(dump);
+    }
+
+    if json {
+        let dump = CatalogDump { catalog, fragments };
+        let stdout = std::io::stdout();
+        serde_json::to_writer_pretty(stdout.lock(), &dump)?;
+        println!();
     }
 
     Ok(())
@@ -0,0 +1,89 @@
+use std::cell::RefCell;
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use ngdp::progress::{NoProgress, ProgressSink};
+
+const MAIN_BAR_STYLE: &str = "{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})";
+const SUB_BAR_STYLE: &str = "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})";
+
+/// Sub tasks smaller than this finish too quickly for a bar to be useful
+const MIN_SUB_TASK_SIZE: u64 = 1_000_000;
+
+/// Shows progress as `indicatif` bars, with sub tasks as temporary bars below the main one
+pub struct IndicatifProgress {
+    mb: MultiProgress,
+    bar: RefCell<ProgressBar>,
+}
+
+impl IndicatifProgress {
+    pub fn new() -> Self {
+        IndicatifProgress {
+            mb: MultiProgress::new(),
+            bar: RefCell::new(ProgressBar::hidden()),
+        }
+    }
+
+    fn add_bar(&self, total: u64, template: &str) -> ProgressBar {
+        let bar = self.mb.add(ProgressBar::new(total));
+        bar.set_style(
+            ProgressStyle::with_template(template)
+                .unwrap()
+                .progress_chars("#>-"),
+        );
+        bar
+    }
+}
+
+impl Default for IndicatifProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ProgressSink for IndicatifProgress {
+    fn start_task(&self, total: u64) {
+        let bar = self.add_bar(total, MAIN_BAR_STYLE);
+        self.bar.replace(bar).finish();
+    }
+
+    fn inc(&self, n: u64) {
+        self.bar.borrow().inc(n);
+    }
+
+    fn message(&self, msg: &str) {
+        self.bar.borrow().set_message(msg.to_string());
+    }
+
+    fn finish(&self) {
+        self.bar.borrow().finish();
+    }
+
+    fn sub_task(&self, total: u64) -> Box<dyn ProgressSink + '_> {
+        if total > MIN_SUB_TASK_SIZE {
+            Box::new(SubTask(self.add_bar(total, SUB_BAR_STYLE)))
+        } else {
+            Box::new(NoProgress)
+        }
+    }
+}
+
+struct SubTask(ProgressBar);
+
+impl ProgressSink for SubTask {
+    fn start_task(&self, total: u64) {
+        self.0.set_length(total);
+        self.0.set_position(0);
+    }
+
+    fn inc(&self, n: u64) {
+        self.0.inc(n);
+    }
+
+    fn message(&self, msg: &str) {
+        self.0.set_message(msg.to_string());
+    }
+
+    fn finish(&self) {
+        self.0.finish_and_clear();
+    }
+}
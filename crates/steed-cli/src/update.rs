@@ -0,0 +1,170 @@
+use anyhow::{anyhow, Context};
+use ngdp::{
+    casc::build_info::BuildInfo,
+    source::{read_cdn_pair, CDNSource, ContentSource},
+    tact::{
+        config::parse_build_config, encoding::parse_encoding, keys::TactKeys,
+        patch::parse_patch_manifest, resolve::resolve_build, root::parse_root, ContentKey,
+    },
+};
+use ribbit::Server;
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+};
+
+use crate::{local_config_path, Config};
+
+/// Determines which locally installed files changed between the installed build and the latest one,
+/// and whether each can be patched or has to be downloaded again. Applying the changes is not implemented yet.
+pub fn update(config: &Config) -> Result<(), anyhow::Error> {
+    let dir = std::env::args()
+        .nth(2)
+        .ok_or_else(|| anyhow!("usage: steed-cli update <dir> [branch]"))?;
+    let dir = PathBuf::from(dir);
+    // Defaults to the active branch
+    let branch = std::env::args().nth(3);
+
+    let build_info = BuildInfo::read(&dir).context("reading .build.info")?;
+    let installed = build_info.select(branch.as_deref())?;
+    let installed_build_key = installed
+        .build_key()
+        .ok_or_else(|| anyhow!(".build.info entry has no valid build key"))?;
+    let region = installed.branch().unwrap_or("eu");
+    let product = installed.product().unwrap_or("wow");
+
+    let resolved = resolve_build(Server::EU, region, product, config.cdn_override.clone())?;
+    let version = &resolved.version;
+
+    let latest_build_key = ContentKey::parse(&version.build_config)?;
+    if latest_build_key == installed_build_key {
+        println!(
+            "Installed build {} is up to date",
+            installed.version().unwrap_or("<unknown>")
+        );
+        return Ok(());
+    }
+    println!(
+        "Installed build: {}, latest build: {}",
+        installed.version().unwrap_or("<unknown>"),
+        version.versions_name
+    );
+
+    let cdn = &resolved.cdn;
+    let build_config = resolved.build_config()?;
+    let cdn_config = resolved.cdn_config()?;
+    println!(
+        "Latest build has {} archives, {} patch archives",
+        cdn_config.archives.len(),
+        cdn_config.patch_archives.len()
+    );
+
+    let tact_keys = TactKeys::default();
+
+    let patch_hs = build_config
+        .patch
+        .as_ref()
+        .ok_or_else(|| anyhow!("build config had no patch manifest"))?;
+    let patch_data = cdn
+        .read_data(&patch_hs.hash)?
+        .read_vec_limited(patch_hs.size, patch_hs.size)?;
+    let patch_manifest =
+        parse_patch_manifest(&tact_keys, &patch_data).context("parsing patch manifest")?;
+
+    let encoding = {
+        let encoding_pair = build_config
+            .encoding
+            .as_ref()
+            .ok_or_else(|| anyhow!("build config had no encoding field"))?;
+        let encoding_data = read_cdn_pair(cdn, &tact_keys, encoding_pair, None)?;
+        parse_encoding(&encoding_data, true).context("parsing encoding")?
+    };
+
+    let new_root = {
+        let source = CDNSource::new(cdn, &encoding, &tact_keys);
+        parse_root(&source.read_by_ckey(&build_config.root)?).context("parsing latest root")?
+    };
+
+    // The installed build's encoding and root tell us which content is installed locally
+    let installed_config_text =
+        std::fs::read_to_string(local_config_path(&dir, &installed_build_key))
+            .context("reading installed build config")?;
+    let installed_config = parse_build_config(&installed_config_text)?;
+    let casc = crate::open_casc(&dir.to_string_lossy(), &installed_config)?;
+    let old_root = parse_root(&casc.read_by_ckey(&installed_config.root)?)
+        .context("parsing installed root")?;
+
+    let old_ckeys: HashMap<_, _> = old_root
+        .iter_records()
+        .map(|(file_id, rt, record)| {
+            (
+                (file_id, rt.content_flags, rt.locale_flags),
+                &record.content_key,
+            )
+        })
+        .collect();
+    let patches: HashMap<_, _> = patch_manifest
+        .entries
+        .iter()
+        .map(|entry| (&entry.target_ckey, entry))
+        .collect();
+
+    let mut changed = HashSet::new();
+    let mut total_patch_size = 0u64;
+    let mut num_unpatched = 0;
+    let mut total_unpatched_size = 0u64;
+    for (file_id, rt, record) in new_root.iter_records() {
+        let new_ckey = &record.content_key;
+        let Some(&old_ckey) = old_ckeys.get(&(file_id, rt.content_flags, rt.locale_flags)) else {
+            continue;
+        };
+        if old_ckey == new_ckey || !casc.contains_ckey(old_ckey) || !changed.insert(new_ckey) {
+            continue;
+        }
+
+        let new_ekey = encoding
+            .lookup_by_ckey(new_ckey)
+            .and_then(|e| e.ekeys.first());
+        let patch = patches.get(new_ckey).and_then(|entry| {
+            entry
+                .patches
+                .iter()
+                .find(|p| casc.contains_ekey(&p.source_ekey))
+        });
+        match (patch, new_ekey) {
+            (Some(patch), _) => {
+                println!(
+                    "{:?} -> {:?} (file id {}, patch {:?}, {} bytes)",
+                    patch.source_ekey, new_ekey, file_id, patch.patch_ekey, patch.patch_size
+                );
+                total_patch_size += patch.patch_size as u64;
+            }
+            (None, Some(new_ekey)) => {
+                let size = encoding.lookup_encoded_size(new_ekey).unwrap_or(0);
+                println!(
+                    "{:?} -> {:?} (file id {}, no patch, {} bytes to download)",
+                    old_ckey, new_ekey, file_id, size
+                );
+                num_unpatched += 1;
+                total_unpatched_size += size;
+            }
+            (None, None) => {
+                println!(
+                    "{:?} -> <not in encoding> (file id {}, ckey {:?})",
+                    old_ckey, file_id, new_ckey
+                );
+                num_unpatched += 1;
+            }
+        }
+    }
+
+    println!(
+        "{} locally installed files changed, {} bytes of patches, {} files without a patch ({} bytes)",
+        changed.len(),
+        total_patch_size,
+        num_unpatched,
+        total_unpatched_size
+    );
+
+    Ok(())
+}